@@ -5,6 +5,7 @@ lib:
   mkMetadataDerivation,
   mkBuildPlan,
   target,
+  rustSrc,
 }:
 {
   src,
@@ -13,8 +14,39 @@ lib:
   lockFilePath ? "/Cargo.lock",
   features ? [ ],
   noDefaultFeatures ? false,
+  crossTargets ? [ ],
+  # targets to build `core`, `compiler_builtins` and `alloc` for, as there is no prebuilt sysroot for them.
+  # Target specs are given as strings, like "${./my-target.json}", and need a nightly toolchain.
+  buildStd ? [ ],
+  # profile whose panic strategy and debuginfo settings are used
+  profile ? "release",
+  # debuginfo level like "line-tables-only" instead of the profile's, "none" leaves it out
+  debuginfo ? null,
+  # "packed" or "unpacked" split debuginfo of the target crates instead of the profile's. With "packed", bins and
+  # cdylibs get a `.dwp` with the debuginfo of every crate they link in their debug output
+  splitDebuginfo ? null,
+  nativeResolver ? false,
+  pipelined ? true,
+  lintDeny ? "warning",
+  clippyFlags ? [ ],
+  reproducible ? false,
+  # shell command run against builds of the bins instrumented with `-C profile-generate`, like "app bench.txt",
+  # whose profile the build is optimized with
+  pgo ? null,
+  # linker and code generation settings per target on top of the ones from `.cargo/config.toml`, like
+  # { x86_64-unknown-linux-gnu = { linker = "${clang}/bin/clang"; targetCpu = "x86-64-v3"; targetFeatures = [ "+aes" ]; }; }
+  targetConfig ? { },
+  # output of `source-files`, as a json file or attr set, to build workspace members from only the files they read
+  sourceFiles ? null,
 }:
 let
+  sourceFiles' =
+    if isNull sourceFiles then
+      { }
+    else if builtins.isAttrs sourceFiles then
+      sourceFiles
+    else
+      builtins.fromJSON (builtins.readFile sourceFiles);
   collectedCrates = collectDependencies {
     inherit
       src
@@ -24,20 +56,46 @@ let
       ;
   };
   vendorDir = mkVendoredDerivation { inherit collectedCrates; };
+  hostTarget = target;
+  targets = lib.unique ([ target ] ++ crossTargets ++ buildStd);
   metadata_out = mkMetadataDerivation {
     inherit
-      target
+      targets
       vendorDir
       pname
       version
       src
       features
       noDefaultFeatures
+      nativeResolver
+      buildStd
+      rustSrc
       ;
   };
+  targetBuildPlans = lib.genAttrs targets (
+    target:
+    mkBuildPlan {
+      inherit
+        metadata_out
+        target
+        hostTarget
+        profile
+        debuginfo
+        splitDebuginfo
+        rustSrc
+        targetBuildPlans
+        pipelined
+        lintDeny
+        clippyFlags
+        reproducible
+        ;
+      # the training runs on the build machine
+      pgo = if target == hostTarget then pgo else null;
+      targetConfig = targetConfig.${target} or { };
+      sources = collectedCrates;
+      sourceFiles = sourceFiles';
+      workspaceSrc = src;
+    }
+  );
 in
-mkBuildPlan {
-  inherit metadata_out;
-  sources = collectedCrates;
-  workspaceSrc = src;
-}
+targetBuildPlans.${target}
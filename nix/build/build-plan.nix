@@ -1,37 +1,278 @@
 lib:
 {
   mkBuildCrateDerivation,
+  mkLintCrateDerivation,
+  mkDocDerivation,
+  mkDoctestDerivation,
+  mkDiagnosticsDerivation,
+  mkSourceFilesDerivation,
   mkRunBuildScriptDerivation,
+  mkSysrootDerivation,
+  mkCoverageDerivation,
+  mkTestDerivation,
+  mkPgoDerivation,
   crateOverrides,
+  linkFarm,
 }:
 {
   workspaceSrc,
   sources,
+  sourceFiles ? { },
   metadata_out,
+  target,
+  # target of the build machine, build scripts and proc macros are built for it when `target` builds its own sysroot
+  hostTarget ? target,
+  # profile whose panic strategy and debuginfo settings are used
+  profile ? "release",
+  # debuginfo level like "line-tables-only" instead of the profile's
+  debuginfo ? null,
+  # "packed" or "unpacked" split debuginfo instead of the profile's
+  splitDebuginfo ? null,
+  # `library` dir of the standard library sources, for targets built with `buildStd`
+  rustSrc ? null,
+  targetBuildPlans ? { },
+  pipelined ? true,
+  lintDeny ? "warning",
+  clippyFlags ? [ ],
+  reproducible ? false,
+  # shell command run against the bins instrumented with `-C profile-generate`, whose profile the build is optimized with
+  pgo ? null,
+  # linker and code generation settings on top of the ones from `.cargo/config.toml`
+  targetConfig ? { },
 }:
 let
-  metadata_val = builtins.fromJSON (builtins.readFile metadata_out);
-  packages = metadata_val.packages;
+  metadata_val = lib.rustBuild.checkSchemaVersion "cargo metadata" (
+    builtins.fromJSON (builtins.readFile metadata_out)
+  );
+  packages = lib.rustBuild.mergeTargetPackages {
+    inherit target;
+    inherit (metadata_val) packages;
+    resolved = metadata_val.targets.${target}.packages;
+  };
   workspace = metadata_val.workspace;
   mainPackage = metadata_val.mainPackage or null;
+  profile' = metadata_val.profiles.${profile} or { };
+  panic = profile'.panic or null;
+  debuginfo' = if isNull debuginfo then profile'.debug or null else debuginfo;
+  splitDebuginfo' = if isNull splitDebuginfo then profile'.splitDebuginfo or null else splitDebuginfo;
+  sysrootPackages = metadata_val.targets.${target}.sysroot or [ ];
+  configCodegen = metadata_val.targets.${target}.codegen or { };
+  codegen =
+    configCodegen
+    // targetConfig
+    // {
+      targetFeatures = (configCodegen.targetFeatures or [ ]) ++ (targetConfig.targetFeatures or [ ]);
+    };
+  # the build machine may not have the CPU, so build scripts and proc macros are built without it
+  tunesCpu = !isNull (codegen.targetCpu or null) || codegen.targetFeatures != [ ];
+  # like cargo, the linker of the host target is also used for build scripts and proc macros
+  hostCodegen = lib.optionalAttrs (hostTarget == target) {
+    linker = codegen.linker or null;
+    linkerFlavor = codegen.linkerFlavor or null;
+  };
 
-  buildPlan = (
-    let
-      mkPackage' = lib.rustBuild.mkPackage {
+  mkPackage =
+    args:
+    lib.rustBuild.mkPackage (
+      {
         inherit
           mkBuildCrateDerivation
+          mkLintCrateDerivation
+          mkDocDerivation
+          mkDoctestDerivation
           mkRunBuildScriptDerivation
-          buildPlan
+          mkCoverageDerivation
+          mkTestDerivation
           workspaceSrc
           sources
+          sourceFiles
+          rustSrc
           crateOverrides
+          pipelined
+          lintDeny
+          clippyFlags
+          reproducible
           ;
+        debuginfo = debuginfo';
+      }
+      // args
+    );
+  sysroot =
+    if sysrootPackages == [ ] then
+      null
+    else
+      mkSysrootDerivation {
+        # the path of a target spec can't be part of the name
+        name = "${lib.removeSuffix ".json" (baseNameOf (builtins.unsafeDiscardStringContext target))}-sysroot";
+        inherit target;
+        crateOutputs = map (id: plainBuildPlan.${id}.rustLib) sysrootPackages;
       };
+  isProcMacro =
+    package:
+    package ? rustLib && !isNull package.rustLib && package.rustLib.crateType == "proc-macro";
+  # without a prebuilt standard library for the target or with a CPU the build machine may lack, build scripts and
+  # proc macros are built for the host
+  hostBuildPlan =
+    if isNull sysroot && !tunesCpu then
+      plainBuildPlan
+    else
+      builtins.mapAttrs
+        (mkPackage {
+          buildPlan = hostBuildPlan;
+          codegen = hostCodegen;
+          inherit hostCodegen;
+        })
+        (
+        builtins.mapAttrs (
+          _: package:
+          package
+          // {
+            common = package.common // {
+              target = hostTarget;
+            };
+          }
+        ) packages
+      );
+  mkTargetPlan =
+    {
+      # whether proc macros are taken from `hostBuildPlan`
+      separateHost ? !isNull sysroot || tunesCpu,
+      ...
+    }@args:
+    let
+      mkPackage' = mkPackage (
+        {
+          inherit
+            hostBuildPlan
+            panic
+            sysroot
+            codegen
+            hostCodegen
+            ;
+          splitDebuginfo = splitDebuginfo';
+          hostTarget = if isNull sysroot then null else hostTarget;
+        }
+        // removeAttrs args [ "separateHost" ]
+      );
     in
-    builtins.mapAttrs mkPackage' packages
-  );
+    builtins.mapAttrs (
+      id: package:
+      if separateHost && isProcMacro package then hostBuildPlan.${id} else mkPackage' id package
+    ) packages;
+  # without instrumentation, the build scripts and proc macros of the other plans come from it
+  plainBuildPlan = mkTargetPlan { buildPlan = plainBuildPlan; };
+  # target crates instrumented for the training run of `pgo`
+  pgoGeneratePlan = mkTargetPlan {
+    buildPlan = pgoGeneratePlan;
+    separateHost = true;
+    profileGenerate = true;
+  };
+  pgoProfile =
+    if isNull pgo then
+      null
+    else
+      mkPgoDerivation {
+        name = "workspace-pgo";
+        src = workspaceSrc;
+        bins = lib.concatMap (package: builtins.attrValues (pgoGeneratePlan.${package}.bins or { })) (
+          builtins.attrValues workspace
+        );
+        trainingCommand = pgo;
+      };
+  buildPlan =
+    if isNull pgo then
+      plainBuildPlan
+    else
+      mkTargetPlan {
+        inherit buildPlan;
+        separateHost = true;
+        profileUse = "${pgoProfile}/merged.profdata";
+      };
+  # workspace crates instrumented for coverage, the other packages evaluate to the derivations of plainBuildPlan
+  coverageBuildPlan = mkTargetPlan {
+    buildPlan = coverageBuildPlan;
+    coverage = true;
+  };
+  # the whole graph built with `-Z sanitizer`, build scripts and proc macros come from the uninstrumented plans
+  mkSanitizerPlan =
+    sanitizer:
+    let
+      plan = mkTargetPlan {
+        buildPlan = plan;
+        separateHost = true;
+        inherit sanitizer;
+      };
+    in
+    plan;
+  sanitizerTests =
+    sanitizer:
+    let
+      plan = mkSanitizerPlan sanitizer;
+    in
+    builtins.mapAttrs (_: package: plan.${package}.testRun) (
+      lib.filterAttrs (_: package: plan.${package} ? testRun) workspace
+    );
   workspaceMembers = builtins.mapAttrs (_: package: buildPlan.${package}) workspace;
-  other = { inherit workspaceMembers buildPlan; };
+  checks = builtins.mapAttrs (
+    name: package: linkFarm "${name}-check" buildPlan.${package}.checks
+  ) workspace;
+  lints = builtins.mapAttrs (
+    name: package: linkFarm "${name}-clippy" buildPlan.${package}.lints
+  ) workspace;
+  docs = builtins.mapAttrs (_: package: buildPlan.${package}.doc) (
+    lib.filterAttrs (_: package: buildPlan.${package} ? doc) workspace
+  );
+  doctests = builtins.mapAttrs (_: package: buildPlan.${package}.doctest) (
+    lib.filterAttrs (_: package: buildPlan.${package} ? doctest) workspace
+  );
+  coverage = builtins.mapAttrs (_: package: coverageBuildPlan.${package}.coverage) (
+    lib.filterAttrs (_: package: coverageBuildPlan.${package} ? coverage) workspace
+  );
+  diagnostics = mkDiagnosticsDerivation {
+    name = "workspace-diagnostics";
+    inherit workspaceSrc;
+    crateOutputs = lib.concatMap (
+      package:
+      lib.optional (package ? rustLib) package.rustLib
+      ++ lib.optional (package ? cLib) package.cLib
+      ++ builtins.attrValues (package.bins or { })
+    ) (builtins.attrValues buildPlan);
+  };
+  sourceFilesOut = mkSourceFilesDerivation {
+    name = "workspace-source-files";
+    crateOutputs = lib.concatMap (
+      package:
+      lib.optional (package ? rustLib) package.rustLib
+      ++ lib.optional (package ? cLib) package.cLib
+      ++ lib.optional (package ? buildScriptRun) package.buildScriptRun
+      ++ builtins.attrValues (package.bins or { })
+    ) (builtins.attrValues workspaceMembers);
+  };
+  doc = mkDocDerivation {
+    name = "workspace-doc";
+    crates = lib.mapAttrsToList (_: package: package.rustLib) (
+      lib.filterAttrs (_: package: package ? rustLib) buildPlan
+    );
+  };
+  other = {
+    inherit
+      sysroot
+      workspaceMembers
+      buildPlan
+      targetBuildPlans
+      checks
+      lints
+      docs
+      doc
+      doctests
+      coverage
+      pgoProfile
+      diagnostics
+      ;
+    tests-asan = sanitizerTests "address";
+    tests-tsan = sanitizerTests "thread";
+    sourceFiles = sourceFilesOut;
+  };
   package = if isNull mainPackage then other else buildPlan.${mainPackage} // other;
 in
 if package ? bins then
@@ -0,0 +1,27 @@
+lib:
+{
+  mkDerivation,
+  coverageHook,
+}:
+lib.extendMkDerivation {
+  constructDrv = mkDerivation;
+  excludeDrvArgNames = [ ];
+  extendDrvArgs =
+    _final:
+    {
+      src,
+      # dir of the package in `src`, the tests run in it and only its sources are reported
+      packageDir,
+      testOutputs,
+      nativeBuildInputs ? [ ],
+      ...
+    }:
+    {
+      inherit src packageDir testOutputs;
+      dontUnpack = true;
+      dontPatch = true;
+      dontConfigure = true;
+      dontInstall = true;
+      nativeBuildInputs = nativeBuildInputs ++ [ coverageHook ];
+    };
+}
@@ -27,11 +27,27 @@ lib.extendMkDerivation {
     "deps"
     "optimize"
     "debuginfo"
+    "debuginfoLevel"
+    "splitDebuginfo"
     "crateType"
     "entrypoint"
     "targetName"
     "buildScriptRun"
     "links"
+    "reproducible"
+    "panic"
+    "sysroot"
+    "sysrootCrate"
+    "coverage"
+    "sanitizer"
+    "profileGenerate"
+    "profileUse"
+    "codegen"
+    "metadataOnly"
+    "linkDeps"
+    "check"
+    "doctest"
+    "versionScript"
   ];
   extendDrvArgs =
     _final:
@@ -59,11 +75,27 @@ lib.extendMkDerivation {
       deps ? [ ],
       optimize ? true,
       debuginfo ? true,
+      debuginfoLevel ? null,
+      splitDebuginfo ? null,
       crateType,
       entrypoint,
       targetName,
       buildScriptRun ? null,
       links ? null,
+      reproducible ? false,
+      panic ? null,
+      sysroot ? null,
+      sysrootCrate ? false,
+      coverage ? false,
+      sanitizer ? null,
+      profileGenerate ? false,
+      profileUse ? null,
+      codegen ? { },
+      metadataOnly ? false,
+      linkDeps ? [ ],
+      check ? false,
+      doctest ? false,
+      versionScript ? null,
       nativeBuildInputs ? [ ],
       passAsFile ? [ ],
       doCheck ? false,
@@ -71,10 +103,19 @@ lib.extendMkDerivation {
       ...
     }:
     let
-      separateDebugInfo = debuginfo && (crateType == "bin" || crateType == "cdylib");
+      separateDebugInfo = debuginfo && !check && (crateType == "bin" || crateType == "cdylib");
       dontStrip = crateType != "bin" && crateType != "cdylib";
     in
     {
+      ${if metadataOnly || check || crateType == "test" then "name" else null} =
+        "${pname}-${version}-${
+          if check then
+            "check"
+          else if crateType == "test" then
+            "test-${targetName}"
+          else
+            "metadata"
+        }";
       inherit
         separateDebugInfo
         dontStrip
@@ -88,34 +129,55 @@ lib.extendMkDerivation {
       dontInstall = true;
 
       rustBuildCrateJob = builtins.toJSON {
+        inherit (lib.rustBuild) schemaVersion;
+        common = {
+          inherit
+            rustcFlags
+            cfgs
+            linkArgs
+            manifestPath
+            version
+            authors
+            pname
+            description
+            homepage
+            repository
+            license
+            licenseFile
+            rustVersion
+            readme
+            target
+            features
+            allFeatures
+            crateName
+            edition
+            deps
+            optimize
+            debuginfo
+            debuginfoLevel
+            splitDebuginfo
+            links
+            reproducible
+            panic
+            sysroot
+            sysrootCrate
+            coverage
+            sanitizer
+            profileGenerate
+            profileUse
+            codegen
+            ;
+        };
         inherit
-          rustcFlags
-          cfgs
-          linkArgs
-          manifestPath
-          version
-          authors
-          pname
-          description
-          homepage
-          repository
-          license
-          licenseFile
-          rustVersion
-          readme
-          target
-          features
-          allFeatures
-          crateName
-          edition
-          deps
-          optimize
-          debuginfo
           crateType
           entrypoint
           targetName
           buildScriptRun
-          links
+          metadataOnly
+          linkDeps
+          check
+          doctest
+          versionScript
           ;
       };
       passAsFile = passAsFile ++ [ "rustBuildCrateJob" ];
@@ -0,0 +1,25 @@
+lib:
+{
+  mkDerivation,
+  diagnosticsHook,
+}:
+lib.extendMkDerivation {
+  constructDrv = mkDerivation;
+  excludeDrvArgNames = [ ];
+  extendDrvArgs =
+    _final:
+    {
+      crateOutputs,
+      workspaceSrc,
+      nativeBuildInputs ? [ ],
+      ...
+    }:
+    {
+      inherit crateOutputs workspaceSrc;
+      dontUnpack = true;
+      dontPatch = true;
+      dontConfigure = true;
+      dontInstall = true;
+      nativeBuildInputs = nativeBuildInputs ++ [ diagnosticsHook ];
+    };
+}
@@ -0,0 +1,26 @@
+lib:
+{
+  mkDerivation,
+  docCratesHook,
+}:
+lib.extendMkDerivation {
+  constructDrv = mkDerivation;
+  excludeDrvArgNames = [ "crates" ];
+  extendDrvArgs =
+    _final:
+    {
+      crates,
+      nativeBuildInputs ? [ ],
+      passAsFile ? [ ],
+      ...
+    }:
+    {
+      dontUnpack = true;
+      dontPatch = true;
+      dontConfigure = true;
+      dontInstall = true;
+      rustDocJobs = lib.rustBuild.docJobs crates;
+      passAsFile = passAsFile ++ [ "rustDocJobs" ];
+      nativeBuildInputs = nativeBuildInputs ++ [ docCratesHook ];
+    };
+}
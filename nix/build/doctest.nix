@@ -0,0 +1,23 @@
+lib:
+{
+  mkBuildCrateDerivation,
+  doctestHook,
+}:
+lib.extendMkDerivation {
+  constructDrv = mkBuildCrateDerivation;
+  excludeDrvArgNames = [ ];
+  extendDrvArgs =
+    _final:
+    {
+      pname,
+      version,
+      nativeBuildInputs ? [ ],
+      ...
+    }:
+    {
+      name = "${pname}-${version}-doctest";
+      doctest = true;
+      dontRustBuildCrate = true;
+      nativeBuildInputs = nativeBuildInputs ++ [ doctestHook ];
+    };
+}
@@ -0,0 +1,25 @@
+lib:
+{
+  mkBuildCrateDerivation,
+  lintCrateHook,
+}:
+lib.extendMkDerivation {
+  constructDrv = mkBuildCrateDerivation;
+  excludeDrvArgNames = [ ];
+  extendDrvArgs =
+    _final:
+    {
+      pname,
+      version,
+      lintDeny ? "warning",
+      clippyFlags ? [ ],
+      nativeBuildInputs ? [ ],
+      ...
+    }:
+    {
+      inherit lintDeny clippyFlags;
+      name = "${pname}-${version}-clippy";
+      dontRustBuildCrate = true;
+      nativeBuildInputs = nativeBuildInputs ++ [ lintCrateHook ];
+    };
+}
@@ -2,6 +2,7 @@ lib:
 {
   mkDerivation,
   cargoMetadataHook,
+  cargo,
 }:
 lib.extendMkDerivation {
   constructDrv = mkDerivation;
@@ -12,22 +13,30 @@ lib.extendMkDerivation {
       pname,
       version,
       vendorDir,
-      target,
+      targets,
       src,
       features ? [ ],
       noDefaultFeatures ? false,
+      nativeResolver ? false,
+      buildStd ? [ ],
+      # `library` dir of the standard library sources, the `rust-src` of rustc if null
+      rustSrc ? null,
       nativeBuildInputs ? [ ],
       ...
     }:
     {
       inherit
         vendorDir
-        target
+        targets
         src
         features
         noDefaultFeatures
+        nativeResolver
+        buildStd
         ;
+      ${if isNull rustSrc then null else "RUST_SRC_PATH"} = rustSrc;
       name = "${pname}-${version}-cargo-metadata.json";
-      nativeBuildInputs = nativeBuildInputs ++ [ cargoMetadataHook ];
+      nativeBuildInputs =
+        nativeBuildInputs ++ [ cargoMetadataHook ] ++ lib.optional (!nativeResolver) cargo;
     };
 }
@@ -0,0 +1,27 @@
+lib:
+{
+  mkDerivation,
+  pgoHook,
+}:
+lib.extendMkDerivation {
+  constructDrv = mkDerivation;
+  excludeDrvArgNames = [ ];
+  extendDrvArgs =
+    _final:
+    {
+      # outputs of the bins built with `profileGenerate`, put on the `PATH` of the training
+      bins,
+      # shell command run in the build dir, `$src` points to the workspace
+      trainingCommand,
+      nativeBuildInputs ? [ ],
+      ...
+    }:
+    {
+      inherit bins trainingCommand;
+      dontUnpack = true;
+      dontPatch = true;
+      dontConfigure = true;
+      dontInstall = true;
+      nativeBuildInputs = nativeBuildInputs ++ [ pgoHook ];
+    };
+}
@@ -27,12 +27,22 @@ lib.extendMkDerivation {
     "deps"
     "optimize"
     "debuginfo"
+    "debuginfoLevel"
+    "splitDebuginfo"
     "crateType"
     "entrypoint"
     "targetName"
     "buildScriptRun"
     "links"
-
+    "reproducible"
+    "panic"
+    "sysroot"
+    "sysrootCrate"
+    "coverage"
+    "sanitizer"
+    "profileGenerate"
+    "profileUse"
+    "codegen"
   ];
   extendDrvArgs =
     final:
@@ -59,8 +69,19 @@ lib.extendMkDerivation {
       deps ? [ ],
       optimize ? true,
       debuginfo ? true,
+      debuginfoLevel ? null,
+      splitDebuginfo ? null,
       buildScript,
       links ? null,
+      reproducible ? false,
+      panic ? null,
+      sysroot ? null,
+      sysrootCrate ? false,
+      coverage ? false,
+      sanitizer ? null,
+      profileGenerate ? false,
+      profileUse ? null,
+      codegen ? { },
       nativeBuildInputs ? [ ],
       passAsFile ? [ ],
       ...
@@ -73,31 +94,45 @@ lib.extendMkDerivation {
       dontConfigure = true;
       dontInstall = true;
       rustRunBuildScriptJob = builtins.toJSON {
-        inherit
-          rustcFlags
-          cfgs
-          linkArgs
-          manifestPath
-          version
-          authors
-          pname
-          description
-          homepage
-          repository
-          license
-          licenseFile
-          rustVersion
-          readme
-          target
-          features
-          allFeatures
-          crateName
-          edition
-          deps
-          optimize
-          debuginfo
-          links
-          ;
+        inherit (lib.rustBuild) schemaVersion;
+        common = {
+          inherit
+            rustcFlags
+            cfgs
+            linkArgs
+            manifestPath
+            version
+            authors
+            pname
+            description
+            homepage
+            repository
+            license
+            licenseFile
+            rustVersion
+            readme
+            target
+            features
+            allFeatures
+            crateName
+            edition
+            deps
+            optimize
+            debuginfo
+            debuginfoLevel
+            splitDebuginfo
+            links
+            reproducible
+            panic
+            sysroot
+            sysrootCrate
+            coverage
+            sanitizer
+            profileGenerate
+            profileUse
+            codegen
+            ;
+        };
       };
       passAsFile = passAsFile ++ [ "rustRunBuildScriptJob" ];
       nativeBuildInputs = nativeBuildInputs ++ [ runBuildScriptHook ];
@@ -0,0 +1,24 @@
+lib:
+{
+  mkDerivation,
+  sourceFilesHook,
+}:
+lib.extendMkDerivation {
+  constructDrv = mkDerivation;
+  excludeDrvArgNames = [ ];
+  extendDrvArgs =
+    _final:
+    {
+      crateOutputs,
+      nativeBuildInputs ? [ ],
+      ...
+    }:
+    {
+      inherit crateOutputs;
+      dontUnpack = true;
+      dontPatch = true;
+      dontConfigure = true;
+      dontInstall = true;
+      nativeBuildInputs = nativeBuildInputs ++ [ sourceFilesHook ];
+    };
+}
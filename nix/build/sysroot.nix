@@ -0,0 +1,25 @@
+lib:
+{
+  mkDerivation,
+  sysrootHook,
+}:
+lib.extendMkDerivation {
+  constructDrv = mkDerivation;
+  excludeDrvArgNames = [ ];
+  extendDrvArgs =
+    _final:
+    {
+      target,
+      crateOutputs,
+      nativeBuildInputs ? [ ],
+      ...
+    }:
+    {
+      inherit target crateOutputs;
+      dontUnpack = true;
+      dontPatch = true;
+      dontConfigure = true;
+      dontInstall = true;
+      nativeBuildInputs = nativeBuildInputs ++ [ sysrootHook ];
+    };
+}
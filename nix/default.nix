@@ -14,12 +14,14 @@ let
         rustc
         cargo
         rustdoc
-        nushell
+        clippy
+        llvmTools
         rustPlatform
         mkDerivation
         fetchurl
         makeSetupHook
         runCommand
+        linkFarm
         mkStandardCrateRegistry
         defaultCrateRegistries
         extraCrateRegistries
@@ -28,15 +30,34 @@ let
         unpackSrcHook
         prepareLockfileHook
         buildCrateHook
+        lintCrateHook
+        docCratesHook
+        doctestHook
+        diagnosticsHook
+        sourceFilesHook
         cargoMetadataHook
         runBuildScriptHook
+        sysrootHook
+        coverageHook
+        testHook
+        pgoHook
+        rustSrc
         mkLockfileDerivation
         mkSourceDerivation
         collectDependencies
         mkVendoredDerivation
         mkMetadataDerivation
         mkBuildCrateDerivation
+        mkLintCrateDerivation
+        mkDocDerivation
+        mkDoctestDerivation
+        mkDiagnosticsDerivation
+        mkSourceFilesDerivation
         mkRunBuildScriptDerivation
+        mkSysrootDerivation
+        mkCoverageDerivation
+        mkTestDerivation
+        mkPgoDerivation
         mkBuildPlan
         ;
       crateRegistries = defaultCrateRegistries // extraCrateRegistries;
@@ -48,7 +69,8 @@ let
           cargo
           rustc
           rustdoc
-          nushell
+          clippy
+          llvmTools
           ;
       };
     in
@@ -59,13 +81,17 @@ let
       inherit (pkgs)
         cargo
         rustc
-        nushell
+        clippy
         rustPlatform
         fetchurl
         makeSetupHook
         runCommand
+        linkFarm
         ;
       rustdoc = pkgs.rustc;
+      # llvm-profdata and llvm-cov, which have to read the profiles of rustc's llvm version
+      llvmTools = rustc.llvmPackages.llvm or pkgs.llvmPackages.llvm;
+      rustSrc = rustPlatform.rustLibSrc;
       inherit crateRegistries;
       mkDerivation = pkgs.stdenv.mkDerivation;
       mkStandardCrateRegistry = registry_import.mkStandardCrateRegistry;
@@ -81,8 +107,17 @@ let
         unpackSrcHook
         prepareLockfileHook
         buildCrateHook
+        lintCrateHook
+        docCratesHook
+        doctestHook
+        diagnosticsHook
+        sourceFilesHook
         cargoMetadataHook
         runBuildScriptHook
+        sysrootHook
+        coverageHook
+        testHook
+        pgoHook
         ;
       mkLockfileDerivation = lib.makeOverridable (import ./vendor/parse-lockfile.nix lib) {
         inherit
@@ -105,16 +140,57 @@ let
         inherit mkDerivation vendorBuildHook;
       };
       mkMetadataDerivation = lib.makeOverridable (import ./build/metadata.nix lib) {
-        inherit mkDerivation cargoMetadataHook;
+        inherit mkDerivation cargoMetadataHook cargo;
       };
       mkBuildCrateDerivation = lib.makeOverridable (import ./build/crate.nix lib) {
         inherit mkDerivation buildCrateHook;
       };
+      mkLintCrateDerivation = lib.makeOverridable (import ./build/lint.nix lib) {
+        inherit mkBuildCrateDerivation lintCrateHook;
+      };
+      mkDocDerivation = lib.makeOverridable (import ./build/doc.nix lib) {
+        inherit mkDerivation docCratesHook;
+      };
+      mkDoctestDerivation = lib.makeOverridable (import ./build/doctest.nix lib) {
+        inherit mkBuildCrateDerivation doctestHook;
+      };
+      mkDiagnosticsDerivation = lib.makeOverridable (import ./build/diagnostics.nix lib) {
+        inherit mkDerivation diagnosticsHook;
+      };
+      mkSourceFilesDerivation = lib.makeOverridable (import ./build/source-files.nix lib) {
+        inherit mkDerivation sourceFilesHook;
+      };
       mkRunBuildScriptDerivation = lib.makeOverridable (import ./build/run-script.nix lib) {
         inherit mkDerivation runBuildScriptHook;
       };
+      mkSysrootDerivation = lib.makeOverridable (import ./build/sysroot.nix lib) {
+        inherit mkDerivation sysrootHook;
+      };
+      mkCoverageDerivation = lib.makeOverridable (import ./build/coverage.nix lib) {
+        inherit mkDerivation coverageHook;
+      };
+      mkTestDerivation = lib.makeOverridable (import ./build/test.nix lib) {
+        inherit mkDerivation testHook;
+      };
+      mkPgoDerivation = lib.makeOverridable (import ./build/pgo.nix lib) {
+        inherit mkDerivation pgoHook;
+      };
       mkBuildPlan = lib.makeOverridable (import ./build/build-plan.nix lib) {
-        inherit mkBuildCrateDerivation mkRunBuildScriptDerivation crateOverrides;
+        inherit
+          mkBuildCrateDerivation
+          mkLintCrateDerivation
+          mkDocDerivation
+          mkDoctestDerivation
+          mkDiagnosticsDerivation
+          mkSourceFilesDerivation
+          mkRunBuildScriptDerivation
+          mkSysrootDerivation
+          mkCoverageDerivation
+          mkTestDerivation
+          mkPgoDerivation
+          crateOverrides
+          linkFarm
+          ;
       };
       build = lib.makeOverridable (import ./build.nix lib) {
         inherit
@@ -123,6 +199,7 @@ let
           mkMetadataDerivation
           mkBuildPlan
           target
+          rustSrc
           ;
       };
     };
@@ -142,15 +219,34 @@ let
           unpackSrcHook
           prepareLockfileHook
           buildCrateHook
+          lintCrateHook
+          docCratesHook
+          doctestHook
+          diagnosticsHook
+          sourceFilesHook
           cargoMetadataHook
           runBuildScriptHook
+          sysrootHook
+          coverageHook
+          testHook
+          pgoHook
+          rustSrc
           mkLockfileDerivation
           mkSourceDerivation
           collectDependencies
           mkVendoredDerivation
           mkMetadataDerivation
           mkBuildCrateDerivation
+          mkLintCrateDerivation
+          mkDocDerivation
+          mkDoctestDerivation
+          mkDiagnosticsDerivation
+          mkSourceFilesDerivation
           mkRunBuildScriptDerivation
+          mkSysrootDerivation
+          mkCoverageDerivation
+          mkTestDerivation
+          mkPgoDerivation
           mkBuildPlan
           build
           ;
@@ -175,6 +271,7 @@ let
                 rustc = toolchain;
                 cargo = toolchain;
                 rustdoc = toolchain;
+                clippy = toolchain;
               }
             )
           );
@@ -1,5 +1,26 @@
 lib: rec {
 
+  /**
+    Version of the json documents exchanged with nix-rust-build.
+    Has to match `SCHEMA_VERSION` in `src/schema.rs`, which documents when it is bumped.
+  */
+  schemaVersion = 2;
+
+  /**
+    # Type
+    ```
+    checkSchemaVersion :: String -> AttrSet -> AttrSet | error
+    ```
+  */
+  checkSchemaVersion =
+    name: doc:
+    if (doc.schemaVersion or null) == schemaVersion then
+      doc
+    else
+      throw "${name} has schema version ${
+        toString (doc.schemaVersion or "none")
+      }, expected ${toString schemaVersion}";
+
   /**
     # Type
     ```
@@ -84,17 +105,108 @@ lib: rec {
     in
     builtins.zipAttrsWith mapper;
 
+  /**
+    Combine the target independent package metadata with the packages resolved for a single target.
+    The target and the features enabled for it are added to the common attributes of every package.
+
+    #Type
+    ```
+    mergeTargetPackages :: { target :: String, packages :: AttrSet, resolved :: AttrSet } -> AttrSet
+    ```
+  */
+  mergeTargetPackages =
+    {
+      target,
+      packages,
+      resolved,
+    }:
+    builtins.mapAttrs (
+      id:
+      package@{ features, ... }:
+      (removeAttrs package [ "features" ])
+      // {
+        common = packages.${id} // {
+          inherit target features;
+        };
+      }
+    ) resolved;
+
+  /**
+    Whether the entry at `path`, relative to the filtered source, is kept for a package reading `files`.
+    Listed directories are kept with everything below them, parents of listed entries are kept so they can be reached.
+
+    # Type
+    ```
+    sourceFilter :: [ String ] -> String -> String -> Bool
+    ```
+  */
+  sourceFilter =
+    files: path: type:
+    builtins.any (
+      file:
+      file == ""
+      || file == path
+      || lib.hasPrefix "${file}/" path
+      || (type == "directory" && lib.hasPrefix "${path}/" file)
+    ) files;
+
+  /**
+    Only keep the `files` of `src` listed by `nix-rust-build source-files`, so changes to other files don't cause rebuilds.
+
+    # Type
+    ```
+    filterSource :: { src :: Path, name :: String, files :: [ String ] } -> Path
+    ```
+  */
+  filterSource =
+    {
+      src,
+      name,
+      files,
+    }:
+    builtins.path {
+      path = src;
+      inherit name;
+      filter = path: sourceFilter files (lib.removePrefix "${toString src}/" (toString path));
+    };
+
+  /**
+    Set the `src` of a package: the workspace, its vendored source or, for crates of a sysroot, the `library` dir of the standard library sources.
+  */
   patchSrc =
-    { workspaceSrc, sources }:
+    {
+      workspaceSrc,
+      sources,
+      sourceFiles ? { },
+      rustSrc ? null,
+    }:
     common@{
       mainWorkspace,
       pname,
       version,
       ...
     }:
+    let
+      id = "${pname}-${version}";
+    in
     (removeAttrs common [ "mainWorkspace" ])
     // {
-      src = if mainWorkspace then workspaceSrc else sources.${"${pname}-${version}"}.path;
+      src =
+        if common.sysrootCrate or false then
+          if isNull rustSrc then
+            throw "building ${id} of the standard library requires rustSrc"
+          else
+            rustSrc
+        else if !mainWorkspace then
+          sources.${id}.path
+        else if sourceFiles ? ${id} then
+          filterSource {
+            src = workspaceSrc;
+            name = "${id}-source";
+            files = sourceFiles.${id};
+          }
+        else
+          workspaceSrc;
     };
 
   patchOverrides =
@@ -112,38 +224,116 @@ lib: rec {
     {
       workspaceSrc,
       sources,
+      sourceFiles ? { },
+      rustSrc ? null,
       crateOverrides,
     }:
     let
-      patchSrc' = patchSrc { inherit workspaceSrc sources; };
+      patchSrc' = patchSrc {
+        inherit
+          workspaceSrc
+          sources
+          sourceFiles
+          rustSrc
+          ;
+      };
       patchOverrides' = patchOverrides crateOverrides;
     in
     common: patchOverrides' (patchSrc' common);
 
+  /**
+    Map resolved dependencies to the `output` of their rust libraries,
+    falling back to the full `rustLib` for packages without such an output.
+  */
   patchDeps =
-    buildPlan:
+    buildPlan: output:
     let
       mapper =
         { name, pkg }:
         {
           inherit name;
-          path = buildPlan.${pkg}.rustLib;
+          path = buildPlan.${pkg}.${output} or buildPlan.${pkg}.rustLib;
         };
     in
     builtins.map mapper;
+
+  /**
+    Collect the rust libraries of `deps` and everything they link against, keyed by package id.
+    Proc macros are not linked, so their own dependencies are left out.
+
+    # Type
+    ```
+    linkClosure :: AttrSet -> [ { name :: String, pkg :: String } ] -> AttrSet
+    ```
+  */
+  linkClosure =
+    buildPlan:
+    builtins.foldl' (
+      acc:
+      { pkg, ... }:
+      acc // buildPlan.${pkg}.rustLibClosure // { ${pkg} = buildPlan.${pkg}.rustLib; }
+    ) { };
+
+  isLinkedCrateType =
+    crateType:
+    builtins.elem crateType [
+      "bin"
+      "cdylib"
+      "proc-macro"
+      "test"
+    ];
+
   patchJob =
-    patchDeps': common:
-    job@{ deps, ... }:
+    {
+      patchDeps',
+      linkClosure',
+      pipelined,
+    }:
+    common:
+    job@{
+      deps,
+      crateType,
+      check ? false,
+      doctest ? false,
+      ...
+    }:
     let
+      output =
+        if check then
+          "rustLibCheck"
+        else if pipelined && crateType == "lib" && !doctest then
+          "rustLibMetadata"
+        else
+          "rustLib";
       a =
         common
         // job
         // {
-          deps = patchDeps' deps;
+          deps = patchDeps' output deps;
+        }
+        // lib.optionalAttrs (pipelined && !check && (doctest || isLinkedCrateType crateType)) {
+          linkDeps = builtins.attrValues (linkClosure' deps);
         };
       a' = if a ? src then a else break a;
     in
     a';
+  /**
+    The json list of `nix-rust-build doc` jobs for the given crate derivations.
+    Built from strings, so the derivations their jobs depend on stay in the context.
+
+    # Type
+    ```
+    docJobs :: [ Derivation ] -> String
+    ```
+  */
+  docJobs =
+    crates:
+    "["
+    + builtins.concatStringsSep "," (
+      map (crate: ''{"src":${builtins.toJSON "${crate.src}"},"job":${crate.rustBuildCrateJob}}'') crates
+    )
+    + "]";
+
   mkBuildScriptPkg =
     { mkBuildCrateDerivation, patchJob' }:
     { common, buildScript }:
@@ -154,7 +344,11 @@ lib: rec {
     in
     mkBuildCrateDerivation (patchJob' common buildScript');
   mkBuildScriptRun =
-    { mkRunBuildScriptDerivation, patchDeps' }:
+    {
+      mkRunBuildScriptDerivation,
+      patchDeps',
+      pipelined,
+    }:
     {
       common,
       buildScript,
@@ -163,8 +357,9 @@ lib: rec {
     mkRunBuildScriptDerivation (
       common
       // {
-
-        deps = patchDeps' buildScript.mainDeps;
+        deps = patchDeps' (
+          if pipelined then "rustLibMetadata" else "rustLib"
+        ) buildScript.mainDeps;
         edition = buildScript.edition;
         buildScript = buildScriptBin;
       }
@@ -173,16 +368,31 @@ lib: rec {
     {
       mkBuildCrateDerivation,
       patchJob',
+      # build scripts link against the dependencies built for the host
+      patchHostJob' ? patchJob',
       mkRunBuildScriptDerivation,
       patchDeps',
+      pipelined,
     }:
     let
-      mkBuildScriptPkg' = mkBuildScriptPkg { inherit mkBuildCrateDerivation patchJob'; };
-      mkBuildScriptRun' = mkBuildScriptRun { inherit mkRunBuildScriptDerivation patchDeps'; };
+      mkBuildScriptPkg' = mkBuildScriptPkg {
+        inherit mkBuildCrateDerivation;
+        patchJob' = patchHostJob';
+      };
+      mkBuildScriptRun' = mkBuildScriptRun {
+        inherit mkRunBuildScriptDerivation patchDeps' pipelined;
+      };
     in
-    args@{ common, buildScript }:
+    {
+      common,
+      buildScript,
+      hostCommon ? common,
+    }:
     let
-      buildScriptBin = mkBuildScriptPkg' args;
+      buildScriptBin = mkBuildScriptPkg' {
+        common = hostCommon;
+        inherit buildScript;
+      };
       buildScriptRun = mkBuildScriptRun' { inherit common buildScript buildScriptBin; };
     in
     {
@@ -195,40 +405,129 @@ lib: rec {
   mkPackage =
     {
       mkBuildCrateDerivation,
+      mkLintCrateDerivation,
+      mkDocDerivation,
+      mkDoctestDerivation,
       mkRunBuildScriptDerivation,
+      mkCoverageDerivation,
+      mkTestDerivation,
       buildPlan,
+      # plan the build scripts and proc macros link against, differs if the target builds its own sysroot
+      hostBuildPlan ? buildPlan,
+      # target build scripts are compiled for, the one of the package if null
+      hostTarget ? null,
       workspaceSrc,
       sources,
+      sourceFiles ? { },
+      rustSrc ? null,
       crateOverrides,
+      pipelined ? true,
+      lintDeny ? "warning",
+      clippyFlags ? [ ],
+      reproducible ? false,
+      # panic strategy of the profile
+      panic ? null,
+      # debuginfo level of the profile, like "line-tables-only" or "limited", "none" leaves it out
+      debuginfo ? null,
+      # `-C split-debuginfo` of the target crates, "packed" puts a `.dwp` of bins and cdylibs into their debug output
+      splitDebuginfo ? null,
+      # sysroot derivation, for targets without a prebuilt standard library
+      sysroot ? null,
+      # instrument workspace crates and report the coverage of their tests
+      coverage ? false,
+      # `-Z sanitizer` the crates are built with, build scripts and proc macros run uninstrumented
+      sanitizer ? null,
+      # instrument the target crates with `-C profile-generate` for a PGO training run
+      profileGenerate ? false,
+      # merged profile of the training run the target crates are optimized with
+      profileUse ? null,
+      # linker and code generation settings of the target, `{ linker, linkerFlavor, targetCpu, targetFeatures }`
+      codegen ? { },
+      # the part of `codegen` build scripts and proc macros use
+      hostCodegen ? { },
     }:
     let
       patchCommon' = patchCommon {
         inherit
           workspaceSrc
           sources
+          sourceFiles
+          rustSrc
           crateOverrides
           ;
       };
       patchDeps' = patchDeps buildPlan;
-      patchJob' = patchJob patchDeps';
+      linkClosure' = linkClosure buildPlan;
+      patchJob' = patchJob { inherit patchDeps' linkClosure' pipelined; };
+      patchHostJob' = patchJob {
+        patchDeps' = patchDeps hostBuildPlan;
+        linkClosure' = linkClosure hostBuildPlan;
+        inherit pipelined;
+      };
       mkBuildScriptCombined' = mkBuildScriptCombined {
         inherit
-
           mkBuildCrateDerivation
           patchJob'
+          patchHostJob'
           mkRunBuildScriptDerivation
           patchDeps'
+          pipelined
           ;
       };
     in
     id:
     package@{ common, ... }:
     let
-      common' = patchCommon' common;
+      instrumented = coverage && common.mainWorkspace;
+      # applied before the overrides, so single crates can opt out
+      common' = patchCommon' (
+        common
+        // {
+          # llvm-cov finds the sources by the paths recorded in the test binaries
+          reproducible = reproducible && !instrumented;
+        }
+        // lib.optionalAttrs instrumented { coverage = true; }
+        // lib.optionalAttrs (!isNull panic) { inherit panic; }
+        // lib.optionalAttrs (!isNull debuginfo) (
+          if debuginfo == "none" then { debuginfo = false; } else { debuginfoLevel = debuginfo; }
+        )
+        // lib.optionalAttrs (!isNull splitDebuginfo && splitDebuginfo != "off") { inherit splitDebuginfo; }
+        // lib.optionalAttrs (!isNull sysroot && !(common.sysrootCrate or false)) { inherit sysroot; }
+        // lib.optionalAttrs (!isNull sanitizer && !(common.sysrootCrate or false)) { inherit sanitizer; }
+        // lib.optionalAttrs (profileGenerate && !(common.sysrootCrate or false)) { inherit profileGenerate; }
+        // lib.optionalAttrs (!isNull profileUse && !(common.sysrootCrate or false)) { inherit profileUse; }
+        // lib.optionalAttrs (codegen != { }) { inherit codegen; }
+      );
+      hostCommon =
+        (
+          if isNull hostTarget then
+            removeAttrs common' [
+              "splitDebuginfo"
+              "sanitizer"
+              "profileGenerate"
+              "profileUse"
+              "codegen"
+            ]
+          else
+            removeAttrs common' [
+              "panic"
+              "sysroot"
+              "splitDebuginfo"
+              "sanitizer"
+              "profileGenerate"
+              "profileUse"
+              "codegen"
+            ]
+            // {
+              target = hostTarget;
+            }
+        )
+        // lib.optionalAttrs (hostCodegen != { }) { codegen = hostCodegen; };
       buildScriptOut =
         if package ? buildScript && !isNull package.buildScript then
           mkBuildScriptCombined' {
             common = common';
+            inherit hostCommon;
             buildScript = package.buildScript;
           }
         else
@@ -251,24 +550,99 @@ lib: rec {
           name = deriv.pname;
           value = deriv;
         };
+      mkCheck = job: patchJob'' (job // { check = true; });
+      mkLint =
+        job:
+        mkLintCrateDerivation (patchJob' common'' job // { inherit lintDeny clippyFlags; });
+      hasRustLib = package ? rustLib && !isNull package.rustLib;
+      hasCLib = package ? cLib && !isNull package.cLib;
+      hasBins = package ? bins && !isNull package.bins;
+      hasDoctest = package ? doctest && !isNull package.doctest;
+      hasTests = (package.tests or [ ]) != [ ];
+      tests = map patchJob'' package.tests;
       out' =
-        if package ? rustLib && !isNull package.rustLib then
-          out // { rustLib = patchJob'' package.rustLib; }
+        if hasRustLib then
+          out
+          // {
+            rustLib = patchJob'' package.rustLib;
+            rustLibClosure =
+              if package.rustLib.crateType == "proc-macro" then
+                { }
+              else
+                linkClosure' package.rustLib.deps;
+          }
+          // lib.optionalAttrs (pipelined && package.rustLib.crateType == "lib") {
+            rustLibMetadata = patchJob'' (package.rustLib // { metadataOnly = true; });
+          }
+          // lib.optionalAttrs (package.rustLib.crateType == "lib") {
+            rustLibCheck = mkCheck package.rustLib;
+          }
+          // {
+            doc = mkDocDerivation {
+              name = "${common''.pname}-${common''.version}-doc";
+              crates = [ out'.rustLib ] ++ builtins.attrValues out'.rustLibClosure;
+            };
+          }
         else
-          out;
+          out // { rustLibClosure = { }; };
       out'' =
-        if package ? cLib && !isNull package.cLib then
-          out' // { cLib = patchJob'' package.cLib; }
-        else
-          out';
+        (if hasCLib then out' // { cLib = patchJob'' package.cLib; } else out')
+        // lib.optionalAttrs hasDoctest {
+          doctest = mkDoctestDerivation (
+            patchJob' common'' (package.doctest // { doctest = true; })
+          );
+        };
       out''' =
-        if package ? bins && !isNull package.bins then
+        if hasBins then
           let
             bins = builtins.listToAttrs (map mkBin package.bins);
           in
           out'' // bins // { inherit bins; }
         else
           out'';
+      out'''' =
+        out'''
+        // lib.optionalAttrs hasTests {
+          inherit tests;
+          testRun = mkTestDerivation {
+            name = "${common''.pname}-${common''.version}-tests${
+              lib.optionalString (!isNull sanitizer) "-${sanitizer}"
+            }";
+            inherit (common'') src;
+            packageDir = dirOf common''.manifestPath;
+            testOutputs = tests;
+          };
+        }
+        // lib.optionalAttrs (hasTests && instrumented) {
+          coverage = mkCoverageDerivation {
+            name = "${common''.pname}-${common''.version}-coverage";
+            inherit (common'') src;
+            packageDir = dirOf common''.manifestPath;
+            testOutputs = tests;
+          };
+        };
+      checks =
+        lib.optionalAttrs hasRustLib { lib = out'.rustLibCheck or out'.rustLib; }
+        // lib.optionalAttrs hasCLib { cLib = mkCheck package.cLib; }
+        // lib.optionalAttrs hasBins (
+          builtins.listToAttrs (
+            map (bin: {
+              name = bin.targetName;
+              value = mkCheck bin;
+            }) package.bins
+          )
+        );
+      lints =
+        lib.optionalAttrs hasRustLib { lib = mkLint package.rustLib; }
+        // lib.optionalAttrs hasCLib { cLib = mkLint package.cLib; }
+        // lib.optionalAttrs hasBins (
+          builtins.listToAttrs (
+            map (bin: {
+              name = bin.targetName;
+              value = mkLint bin;
+            }) package.bins
+          )
+        );
     in
-    out''';
+    out'''' // { inherit checks lints; };
 }
@@ -6,11 +6,28 @@ rustBuildCrateHook() {
     cat "$rustBuildCrateJobPath"
     echo "src: $src"
     echo "out: $out"
-    nu @run_build@ "$rustBuildCrateJobPath" "$src" "$out"
+    @nix_rust_build@ compile "$src" "$(command -v cargo)" "$(command -v rustc)" "$rustBuildCrateJobPath" "$out"
     runHook postBuild
     echo "Finished rustBuildCrateHook"
 }
 
+# gdb looks for the `.dwp` of packed split debuginfo next to the separated debug file, named after the artifact
+rustBuildCrateMoveDwp() {
+    [ -n "${debug:-}" ] || return 0
+    local dwp artifact id
+    while IFS= read -r -d $'\0' dwp; do
+        artifact=${dwp%.dwp}
+        id=$($READELF -n "$artifact" | sed 's/.*Build ID: \([0-9a-f]*\).*/\1/; t; d')
+        if [ "${#id}" != 40 ]; then
+            echo "could not find build ID of $artifact, keeping $dwp" >&2
+            continue
+        fi
+        mkdir -p "$debug/lib/debug/.build-id/${id:0:2}"
+        mv "$dwp" "$debug/lib/debug/.build-id/${id:0:2}/$(basename "$artifact").dwp"
+    done < <(find "$out" -type f -name '*.dwp' -print0)
+}
+postFixupHooks+=(rustBuildCrateMoveDwp)
+
 if [ -z "${dontRustBuildCrate:-}" ] && [ -z "${buildPhase:-}" ]; then
     buildPhase=rustBuildCrateHook
 fi
@@ -2,7 +2,16 @@
 rustCargoMetadataBuildHook() {
     echo "Executing rustCargoMetadataBuildHook"
     runHook preBuild
-    nu @metadata@
+    local command=metadata
+    if [ -n "${nativeResolver:-}" ]; then
+        command=resolve
+    fi
+    local buildStdFlags=()
+    for buildStdTarget in ${buildStd:-}; do
+        buildStdFlags+=(--build-std "$buildStdTarget")
+    done
+    # shellcheck disable=SC2086
+    @nix_rust_build@ "$command" "$src" "$vendorDir" "$out" $targets "${buildStdFlags[@]}"
     runHook postBuild
     echo "Finished rustCargoMetadataBuildHook"
 }
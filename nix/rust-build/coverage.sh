@@ -0,0 +1,13 @@
+# shellcheck shell=bash disable=SC2154
+rustCoverageHook() {
+    echo "Executing rustCoverageHook"
+    runHook preBuild
+    # shellcheck disable=SC2086
+    @nix_rust_build@ coverage @llvm_profdata@ @llvm_cov@ "$src/$packageDir" "$out" $testOutputs
+    runHook postBuild
+    echo "Finished rustCoverageHook"
+}
+
+if [ -z "${dontRustCoverage:-}" ] && [ -z "${buildPhase:-}" ]; then
+    buildPhase=rustCoverageHook
+fi
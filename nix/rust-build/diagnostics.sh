@@ -0,0 +1,14 @@
+# shellcheck shell=bash disable=SC2154
+rustDiagnosticsHook() {
+    echo "Executing rustDiagnosticsHook"
+    runHook preBuild
+    mkdir -p "$out"
+    # shellcheck disable=SC2086
+    @nix_rust_build@ diagnostics "$out/diagnostics.json" --sarif "$out/diagnostics.sarif" --strip-prefix "$workspaceSrc" $crateOutputs
+    runHook postBuild
+    echo "Finished rustDiagnosticsHook"
+}
+
+if [ -z "${dontRustDiagnostics:-}" ] && [ -z "${buildPhase:-}" ]; then
+    buildPhase=rustDiagnosticsHook
+fi
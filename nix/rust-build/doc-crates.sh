@@ -0,0 +1,15 @@
+# shellcheck shell=bash disable=SC2154
+rustDocCratesHook() {
+    echo "Executing rustDocCratesHook"
+    runHook preBuild
+    echo "jobs:"
+    cat "$rustDocJobsPath"
+    echo "out: $out"
+    @nix_rust_build@ doc "$(command -v cargo)" "$(command -v rustdoc)" "$rustDocJobsPath" "$out/share/doc"
+    runHook postBuild
+    echo "Finished rustDocCratesHook"
+}
+
+if [ -z "${dontRustDocCrates:-}" ] && [ -z "${buildPhase:-}" ]; then
+    buildPhase=rustDocCratesHook
+fi
@@ -0,0 +1,16 @@
+# shellcheck shell=bash disable=SC2154
+rustDoctestHook() {
+    echo "Executing rustDoctestHook"
+    runHook preBuild
+    echo "job:"
+    cat "$rustBuildCrateJobPath"
+    echo "src: $src"
+    echo "out: $out"
+    @nix_rust_build@ doctest "$src" "$(command -v cargo)" "$(command -v rustdoc)" "$rustBuildCrateJobPath" "$out"
+    runHook postBuild
+    echo "Finished rustDoctestHook"
+}
+
+if [ -z "${dontRustDoctest:-}" ] && [ -z "${buildPhase:-}" ]; then
+    buildPhase=rustDoctestHook
+fi
@@ -5,7 +5,8 @@
   cargo,
   rustc,
   rustdoc,
-  nushell,
+  clippy,
+  llvmTools,
 }:
 let
   file =
@@ -14,107 +15,240 @@ let
       inherit path;
       recursive = false;
     };
-  nu_files =
-    paths:
-    lib.fileset.toSource {
-      root = ../../nu;
-      fileset = lib.fileset.unions paths;
-    };
+  nix_rust_build = rust-build: "${rust-build}/bin/nix-rust-build";
 in
 {
   prepareLockfileHook = lib.makeOverridable (
-    { makeSetupHook, nushell }:
+    { makeSetupHook, rust-build }:
     makeSetupHook {
       name = "prepareLockfileHook";
-      propagatedBuildInputs = [ nushell ];
       substitutions = {
-        prepare_lockfile = "${
-          nu_files [
-            ../../nu/version.nu
-            ../../nu/prepare_lockfile.nu
-          ]
-        }/prepare_lockfile.nu";
+        nix_rust_build = nix_rust_build rust-build;
       };
     } (file ./prepare-lockfile.sh)
-  ) { inherit makeSetupHook nushell; };
+  ) { inherit makeSetupHook rust-build; };
   unpackSrcHook = lib.makeOverridable (
-    { makeSetupHook, nushell }:
+    { makeSetupHook, rust-build }:
     makeSetupHook {
       name = "unpackSrcHook";
-      propagatedBuildInputs = [ nushell ];
       substitutions = {
-        install_src_hash = file ../../nu/install_src_hash.nu;
+        nix_rust_build = nix_rust_build rust-build;
       };
     } (file ./unpack-src.sh)
-  ) { inherit makeSetupHook nushell; };
+  ) { inherit makeSetupHook rust-build; };
   vendorBuildHook = lib.makeOverridable (
-    { makeSetupHook, nushell }:
+    { makeSetupHook, rust-build }:
     makeSetupHook {
       name = "vendorBuildHook";
-      propagatedBuildInputs = [ nushell ];
       substitutions = {
-        write_vendor = file ../../nu/write_vendor.nu;
+        nix_rust_build = nix_rust_build rust-build;
       };
     } (file ./vendor-build.sh)
-  ) { inherit makeSetupHook nushell; };
+  ) { inherit makeSetupHook rust-build; };
   cargoMetadataHook = lib.makeOverridable (
     {
       makeSetupHook,
-      nushell,
-      cargo,
+      rust-build,
+      rustc,
     }:
     makeSetupHook {
       name = "cargoMetadataHook";
-      propagatedBuildInputs = [
-        nushell
-        cargo
-      ];
+      # cargo is only needed without the native resolver, see `build/metadata.nix`
+      propagatedBuildInputs = [ rustc ];
       substitutions = {
-        metadata = file ../../nu/metadata.nu;
+        nix_rust_build = nix_rust_build rust-build;
       };
     } (file ./cargo-metadata.sh)
-  ) { inherit makeSetupHook nushell cargo; };
+  ) {
+    inherit
+      makeSetupHook
+      rust-build
+      rustc
+      ;
+  };
   buildCrateHook =
     lib.makeOverridable
       (
         {
           makeSetupHook,
-          nushell,
+          rust-build,
           rustc,
           cargo,
         }:
         makeSetupHook {
           name = "buildCrateHook";
           propagatedBuildInputs = [
-            nushell
             rustc
             cargo
           ];
           substitutions = {
-            run_build = "${
-              nu_files [
-                ../../nu/run_build.nu
-                ../../nu/run_common.nu
-                ../../nu/version.nu
-              ]
-            }/run_build.nu";
+            nix_rust_build = nix_rust_build rust-build;
           };
         } ./build.sh
       )
       {
         inherit
           makeSetupHook
-          nushell
+          rust-build
           rustc
           cargo
           ;
       };
+  lintCrateHook =
+    lib.makeOverridable
+      (
+        {
+          makeSetupHook,
+          rust-build,
+          cargo,
+          clippy,
+        }:
+        makeSetupHook {
+          name = "lintCrateHook";
+          propagatedBuildInputs = [
+            cargo
+            clippy
+          ];
+          substitutions = {
+            nix_rust_build = nix_rust_build rust-build;
+          };
+        } ./lint-crate.sh
+      )
+      {
+        inherit
+          makeSetupHook
+          rust-build
+          cargo
+          clippy
+          ;
+      };
+  docCratesHook =
+    lib.makeOverridable
+      (
+        {
+          makeSetupHook,
+          rust-build,
+          cargo,
+          rustdoc,
+        }:
+        makeSetupHook {
+          name = "docCratesHook";
+          propagatedBuildInputs = [
+            cargo
+            rustdoc
+          ];
+          substitutions = {
+            nix_rust_build = nix_rust_build rust-build;
+          };
+        } ./doc-crates.sh
+      )
+      {
+        inherit
+          makeSetupHook
+          rust-build
+          cargo
+          rustdoc
+          ;
+      };
+  doctestHook =
+    lib.makeOverridable
+      (
+        {
+          makeSetupHook,
+          rust-build,
+          cargo,
+          rustdoc,
+        }:
+        makeSetupHook {
+          name = "doctestHook";
+          propagatedBuildInputs = [
+            cargo
+            rustdoc
+          ];
+          substitutions = {
+            nix_rust_build = nix_rust_build rust-build;
+          };
+        } ./doctest.sh
+      )
+      {
+        inherit
+          makeSetupHook
+          rust-build
+          cargo
+          rustdoc
+          ;
+      };
+  diagnosticsHook = lib.makeOverridable (
+    { makeSetupHook, rust-build }:
+    makeSetupHook {
+      name = "diagnosticsHook";
+      substitutions = {
+        nix_rust_build = nix_rust_build rust-build;
+      };
+    } ./diagnostics.sh
+  ) { inherit makeSetupHook rust-build; };
+  sourceFilesHook = lib.makeOverridable (
+    { makeSetupHook, rust-build }:
+    makeSetupHook {
+      name = "sourceFilesHook";
+      substitutions = {
+        nix_rust_build = nix_rust_build rust-build;
+      };
+    } ./source-files.sh
+  ) { inherit makeSetupHook rust-build; };
+  coverageHook = lib.makeOverridable (
+    {
+      makeSetupHook,
+      rust-build,
+      llvmTools,
+    }:
+    makeSetupHook {
+      name = "coverageHook";
+      substitutions = {
+        nix_rust_build = nix_rust_build rust-build;
+        llvm_profdata = "${llvmTools}/bin/llvm-profdata";
+        llvm_cov = "${llvmTools}/bin/llvm-cov";
+      };
+    } ./coverage.sh
+  ) { inherit makeSetupHook rust-build llvmTools; };
+  testHook = lib.makeOverridable (
+    { makeSetupHook, rust-build }:
+    makeSetupHook {
+      name = "testHook";
+      substitutions = {
+        nix_rust_build = nix_rust_build rust-build;
+      };
+    } ./test.sh
+  ) { inherit makeSetupHook rust-build; };
+  pgoHook = lib.makeOverridable (
+    {
+      makeSetupHook,
+      rust-build,
+      llvmTools,
+    }:
+    makeSetupHook {
+      name = "pgoHook";
+      substitutions = {
+        nix_rust_build = nix_rust_build rust-build;
+        llvm_profdata = "${llvmTools}/bin/llvm-profdata";
+      };
+    } ./pgo.sh
+  ) { inherit makeSetupHook rust-build llvmTools; };
+  sysrootHook = lib.makeOverridable (
+    { makeSetupHook, rust-build }:
+    makeSetupHook {
+      name = "sysrootHook";
+      substitutions = {
+        nix_rust_build = nix_rust_build rust-build;
+      };
+    } ./sysroot.sh
+  ) { inherit makeSetupHook rust-build; };
   runBuildScriptHook =
     lib.makeOverridable
       (
         {
           makeSetupHook,
-          nushell,
+          rust-build,
           rustc,
           cargo,
           rustdoc,
@@ -122,26 +256,19 @@ in
         makeSetupHook {
           name = "runBuildScriptHook";
           propagatedBuildInputs = [
-            nushell
             cargo
             rustc
             rustdoc
           ];
           substitutions = {
-            run_build_script = "${
-              nu_files [
-                ../../nu/run_build_script.nu
-                ../../nu/run_common.nu
-                ../../nu/version.nu
-              ]
-            }/run_build_script.nu";
+            nix_rust_build = nix_rust_build rust-build;
           };
         } ./run-build-script.sh
       )
       {
         inherit
           makeSetupHook
-          nushell
+          rust-build
           cargo
           rustc
           rustdoc
@@ -0,0 +1,15 @@
+# shellcheck shell=bash disable=SC2154
+rustLintCrateHook() {
+    echo "Executing rustLintCrateHook"
+    runHook preBuild
+    echo "job:"
+    cat "$rustBuildCrateJobPath"
+    # shellcheck disable=SC2086
+    @nix_rust_build@ lint "$src" "$(command -v cargo)" "$(command -v clippy-driver)" "$rustBuildCrateJobPath" "$out" --deny "$lintDeny" -- $clippyFlags
+    runHook postBuild
+    echo "Finished rustLintCrateHook"
+}
+
+if [ -z "${dontRustLintCrate:-}" ] && [ -z "${buildPhase:-}" ]; then
+    buildPhase=rustLintCrateHook
+fi
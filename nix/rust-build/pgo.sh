@@ -0,0 +1,13 @@
+# shellcheck shell=bash disable=SC2154
+rustPgoHook() {
+    echo "Executing rustPgoHook"
+    runHook preBuild
+    # shellcheck disable=SC2086
+    @nix_rust_build@ pgo @llvm_profdata@ "$PWD" "$out" $bins -- "$SHELL" -c "$trainingCommand"
+    runHook postBuild
+    echo "Finished rustPgoHook"
+}
+
+if [ -z "${dontRustPgo:-}" ] && [ -z "${buildPhase:-}" ]; then
+    buildPhase=rustPgoHook
+fi
@@ -2,7 +2,7 @@
 rustPrepareLockfileBuildHook() {
     echo "Executing rustPrepareLockfileBuildHook"
     runHook preBuild
-    nu @prepare_lockfile@ "${src}/${lockFilePath}" "$out"
+    @nix_rust_build@ lockfile "${src}/${lockFilePath}" "$out"
     runHook postBuild
     echo "Finished rustPrepareLockfileBuildHook"
 }
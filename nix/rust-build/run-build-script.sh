@@ -8,7 +8,7 @@ rustRunBuildScriptHook() {
     echo "src: $src"
     echo "out: $out"
     echo "path: $PATH"
-    nu @run_build_script@ "${buildScript}/bin/build_script" "$rustRunBuildScriptJobPath" "$src" "$out"
+    @nix_rust_build@ run-build-script "${buildScript}/bin/build_script" "$(command -v cargo)" "$(command -v rustc)" "$(command -v rustdoc)" "$src" "$rustRunBuildScriptJobPath" "$out"
     runHook postBuild
     echo "Finished rustRunBuildScriptHook"
 }
@@ -10,6 +10,7 @@ let
       ../../src
       ../../Cargo.toml
       ../../Cargo.lock
+      ../../tests/fixtures
     ];
   };
 in
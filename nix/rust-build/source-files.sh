@@ -0,0 +1,13 @@
+# shellcheck shell=bash disable=SC2154
+rustSourceFilesHook() {
+    echo "Executing rustSourceFilesHook"
+    runHook preBuild
+    # shellcheck disable=SC2086
+    @nix_rust_build@ source-files "$out" $crateOutputs
+    runHook postBuild
+    echo "Finished rustSourceFilesHook"
+}
+
+if [ -z "${dontRustSourceFiles:-}" ] && [ -z "${buildPhase:-}" ]; then
+    buildPhase=rustSourceFilesHook
+fi
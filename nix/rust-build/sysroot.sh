@@ -0,0 +1,13 @@
+# shellcheck shell=bash disable=SC2154
+rustSysrootHook() {
+    echo "Executing rustSysrootHook"
+    runHook preBuild
+    # shellcheck disable=SC2086
+    @nix_rust_build@ sysroot "$target" "$out" $crateOutputs
+    runHook postBuild
+    echo "Finished rustSysrootHook"
+}
+
+if [ -z "${dontRustSysroot:-}" ] && [ -z "${buildPhase:-}" ]; then
+    buildPhase=rustSysrootHook
+fi
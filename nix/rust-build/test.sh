@@ -0,0 +1,13 @@
+# shellcheck shell=bash disable=SC2154
+rustTestHook() {
+    echo "Executing rustTestHook"
+    runHook preBuild
+    # shellcheck disable=SC2086
+    @nix_rust_build@ test "$src/$packageDir" "$out" $testOutputs
+    runHook postBuild
+    echo "Finished rustTestHook"
+}
+
+if [ -z "${dontRustTest:-}" ] && [ -z "${buildPhase:-}" ]; then
+    buildPhase=rustTestHook
+fi
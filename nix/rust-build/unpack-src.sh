@@ -2,7 +2,7 @@
 rustInstallSrcHashHook() {
     echo "Executing rustInstallSrcHashHook"
     runHook preInstall
-    nu @install_src_hash@ "$src" "$out"
+    @nix_rust_build@ install-src-hash "$src" . "$out"
     runHook postInstall
     echo "Finished rustInstallSrcHashHook"
 }
@@ -2,7 +2,7 @@
 rustVendorBuildHook() {
     echo "Executing rustVendorBuildHook"
     runHook preBuild
-    nu @write_vendor@ "$jobPath" "$out"
+    @nix_rust_build@ write-vendor "$jobPath" "$out"
     runHook postBuild
     echo "Finished rustVendorBuildHook"
 }
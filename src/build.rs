@@ -0,0 +1,1124 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fs::{self, File},
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::mpsc,
+    thread,
+};
+
+use cargo_metadata::Edition;
+use clap::Args;
+use color_eyre::eyre::{Context, OptionExt, Result, bail, eyre};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cache::CACHE_ENV,
+    diagnostics::FAILURE_LOG_PREFIX,
+    pgo,
+    run_build_script::rustc_host_tripple,
+    schema::{
+        BuildScriptJob, CompileTarget, CrateJob, CrateJobCommon, Dep, Metadata, PackageMetadata,
+        ResolvedDep, ResolvedPackage, SchemaVersion, TargetCodegen,
+    },
+    sysroot,
+};
+
+/// Written next to the output of a job once it succeeded.
+const DONE_SUFFIX: &str = "done";
+
+/// A single `compile`, `run-build-script`, `sysroot`, `test`, `coverage` or `pgo` invocation of
+/// the plan.
+enum Step {
+    Compile(CrateJob),
+    RunBuildScript {
+        script: PathBuf,
+        job: BuildScriptJob,
+    },
+    Sysroot {
+        target: String,
+        libs: Vec<PathBuf>,
+    },
+    Test {
+        package_dir: PathBuf,
+        tests: Vec<PathBuf>,
+    },
+    Coverage {
+        package_dir: PathBuf,
+        tests: Vec<PathBuf>,
+    },
+    Pgo {
+        dir: PathBuf,
+        bins: Vec<PathBuf>,
+        command: Vec<String>,
+    },
+}
+
+struct Node {
+    /// `pname-version` and the kind of the job, for the log.
+    label: String,
+    src: PathBuf,
+    step: Step,
+    /// Nodes whose outputs have to exist before this one runs.
+    deps: Vec<usize>,
+    /// Output directory, named after the hash of all inputs of the job.
+    out: PathBuf,
+}
+
+impl Node {
+    fn with_suffix(&self, suffix: &str) -> PathBuf {
+        let mut path = self.out.clone().into_os_string();
+        path.push(".");
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+
+    fn done(&self) -> bool {
+        self.out.is_dir() && self.with_suffix(DONE_SUFFIX).is_file()
+    }
+}
+
+/// Outputs of one package, as far as they are needed by its dependents.
+#[derive(Clone, Copy, Default)]
+struct PackageNodes {
+    rust_lib: Option<usize>,
+    build_script_run: Option<usize>,
+}
+
+/// Translates the resolved packages of one target into [`Node`]s, the way `mkPackage` in
+/// `nix/lib.nix` does without pipelining.
+///
+/// Targets with a sysroot built from `rust-src` have no standard library for build scripts and
+/// proc macros, sanitized and PGO builds shouldn't instrument them and builds for a CPU the
+/// build machine may not have shouldn't tune them, so those and their dependencies are planned
+/// a second time for `host`.
+///
+/// With PGO the target packages are planned twice, first instrumented for the training run and
+/// then optimized with its profile, while the host packages are shared by both.
+struct Planner<'a> {
+    metadata: &'a Metadata,
+    resolved: &'a BTreeMap<String, ResolvedPackage>,
+    target: &'a str,
+    host: &'a str,
+    project_dir: &'a Path,
+    vendor_dir: &'a Path,
+    /// Standard library sources, for targets with a built sysroot.
+    library_dir: Option<PathBuf>,
+    out: &'a Path,
+    optimize: bool,
+    panic: Option<String>,
+    /// Debuginfo level, `none` leaves it out.
+    debuginfo: Option<String>,
+    /// `-C split-debuginfo` mode of the target crates.
+    split_debuginfo: Option<String>,
+    /// Linker and code generation settings of the target.
+    codegen: TargetCodegen,
+    /// Instrument the workspace crates and plan the coverage of the members' tests.
+    coverage: bool,
+    /// Sanitizer of every crate built for the target, whose members' tests are run.
+    sanitizer: Option<String>,
+    /// Instrument the target crates for the training run of a profile-guided optimization.
+    profile_generate: bool,
+    /// Node running the training, whose profile the target crates are optimized with.
+    training: Option<usize>,
+    /// Node linking the sysroot, once its crates are planned.
+    sysroot: Option<usize>,
+    /// Paths and version of the tools, so a different compiler rebuilds everything.
+    tools: String,
+    nodes: Vec<Node>,
+    /// Planned packages, by id and whether they are built for the host.
+    packages: BTreeMap<(&'a str, bool), PackageNodes>,
+    /// Bins of the workspace members, linked into `out/bin`.
+    bins: Vec<(String, usize)>,
+    /// Coverage reports of the workspace members, linked into `out/coverage`.
+    coverages: Vec<(String, usize)>,
+    /// Sanitized test runs of the workspace members, linked into `out/tests-<sanitizer>`.
+    test_runs: Vec<(String, usize)>,
+}
+
+impl<'a> Planner<'a> {
+    fn src(&self, package: &PackageMetadata) -> PathBuf {
+        if package.sysroot_crate
+            && let Some(library_dir) = &self.library_dir
+        {
+            return library_dir.clone();
+        }
+        if package.main_workspace {
+            return self.project_dir.to_path_buf();
+        }
+        let versioned = self
+            .vendor_dir
+            .join(format!("{}-{}", package.pname, package.version));
+        if versioned.is_dir() {
+            versioned
+        } else {
+            self.vendor_dir.join(&package.pname)
+        }
+    }
+
+    fn common(
+        &self,
+        package: &PackageMetadata,
+        features: &[String],
+        crate_name: &str,
+        edition: Edition,
+        deps: Vec<ResolvedDep>,
+        host: bool,
+    ) -> CrateJobCommon {
+        CrateJobCommon {
+            rustc_flags: Vec::new(),
+            cfgs: Vec::new(),
+            link_args: Vec::new(),
+            manifest_path: package.manifest_path.clone(),
+            version: package.version.clone(),
+            authors: package.authors.clone(),
+            pname: package.pname.clone(),
+            description: package.description.clone(),
+            homepage: package.homepage.clone(),
+            repository: package.repository.clone(),
+            license: package.license.clone(),
+            license_file: package.license_file.clone(),
+            rust_version: package.rust_version.clone(),
+            readme: package.readme.clone(),
+            target: if host { self.host } else { self.target }.to_string(),
+            features: features.to_vec(),
+            all_features: package.all_features.clone(),
+            crate_name: crate_name.to_string(),
+            edition,
+            deps,
+            links: package.links.clone(),
+            optimize: self.optimize,
+            debuginfo: self.debuginfo.as_deref() != Some("none"),
+            debuginfo_level: self.debuginfo.clone().filter(|level| level != "none"),
+            reproducible: false,
+            panic: if host { None } else { self.panic.clone() },
+            split_debuginfo: self
+                .split_debuginfo
+                .clone()
+                .filter(|split| !host && split != "off"),
+            sysroot: self
+                .sysroot
+                .filter(|_| !host && !package.sysroot_crate)
+                .map(|node| self.nodes[node].out.clone()),
+            sysroot_crate: package.sysroot_crate,
+            coverage: self.coverage && package.main_workspace && !host,
+            sanitizer: if host { None } else { self.sanitizer.clone() },
+            profile_generate: self.profile_generate && !host && !package.sysroot_crate,
+            profile_use: self
+                .training
+                .filter(|_| !host && !package.sysroot_crate)
+                .map(|node| self.nodes[node].out.join(pgo::PROFDATA)),
+            codegen: if !host {
+                self.codegen.clone()
+            } else if self.host == self.target {
+                self.codegen.linker_only()
+            } else {
+                TargetCodegen::default()
+            },
+        }
+    }
+
+    /// Whether build scripts and proc macros are built for the host instead of the target.
+    fn separate_host(&self) -> bool {
+        self.library_dir.is_some()
+            || self.sanitizer.is_some()
+            || self.profile_generate
+            || self.training.is_some()
+            || self.codegen.tunes_cpu()
+    }
+
+    /// Whether `resolved` is a proc macro that has to be built for the host.
+    fn host_only(&self, resolved: &ResolvedPackage) -> bool {
+        self.separate_host()
+            && resolved
+                .rust_lib
+                .as_ref()
+                .is_some_and(|lib| lib.crate_type == "proc-macro")
+    }
+
+    /// Plans `deps`, returning their rust library outputs and nodes.
+    fn deps(&mut self, deps: &'a [Dep], host: bool) -> Result<(Vec<ResolvedDep>, Vec<usize>)> {
+        let mut resolved = Vec::new();
+        let mut nodes = Vec::new();
+        for dep in deps {
+            let (_, resolved_dep) = self.lookup(&dep.pkg)?;
+            let node = self
+                .package(&dep.pkg, host || self.host_only(resolved_dep))?
+                .rust_lib
+                .ok_or_else(|| eyre!("dependency {} has no rust library", dep.pkg))?;
+            resolved.push(ResolvedDep {
+                name: dep.name.clone(),
+                path: self.nodes[node].out.clone(),
+            });
+            nodes.push(node);
+        }
+        Ok((resolved, nodes))
+    }
+
+    /// Adds a node, deriving its output directory from everything the job reads.
+    fn push(
+        &mut self,
+        package: &PackageMetadata,
+        kind: &str,
+        src: PathBuf,
+        step: Step,
+        mut deps: Vec<usize>,
+    ) -> Result<usize> {
+        let mut hash = Sha256::new();
+        let mut field = |value: &[u8]| {
+            hash.update(value);
+            hash.update([0]);
+        };
+        field(self.tools.as_bytes());
+        field(kind.as_bytes());
+        field(src.as_os_str().as_encoded_bytes());
+        match &step {
+            Step::Compile(job) => {
+                field(&serde_json::to_vec(job).context("serializing crate job")?);
+            }
+            Step::RunBuildScript { script, job } => {
+                field(script.as_os_str().as_encoded_bytes());
+                field(&serde_json::to_vec(job).context("serializing build script job")?);
+            }
+            Step::Sysroot { .. } => {
+                return Err(eyre!("sysroot nodes are added by Planner::plan_sysroot"));
+            }
+            Step::Test { .. } | Step::Coverage { .. } => {
+                return Err(eyre!("test nodes are added by Planner::plan_tests"));
+            }
+            Step::Pgo { .. } => {
+                return Err(eyre!("pgo nodes are added by Planner::plan_training"));
+            }
+        }
+        if let Step::Compile(job) = &step {
+            if job.common.sysroot.is_some() {
+                deps.extend(self.sysroot);
+            }
+            if job.common.profile_use.is_some() {
+                deps.extend(self.training);
+            }
+        }
+        let manifest_dir = src.join(&package.manifest_path);
+        let manifest_dir = manifest_dir
+            .parent()
+            .ok_or_eyre("manifest has no parent dir")?;
+        field(source_hash(manifest_dir, self.out)?.as_bytes());
+        let hash = hex::encode(&hash.finalize().as_slice()[0..8]);
+        let label = format!("{}-{}-{kind}", package.pname, package.version);
+        let out = self.out.join(format!("{label}-{hash}"));
+        // the second PGO pass replans the build scripts of target packages
+        if let Some(node) = self.nodes.iter().position(|node| node.out == out) {
+            return Ok(node);
+        }
+        self.nodes.push(Node {
+            out,
+            label,
+            src,
+            step,
+            deps,
+        });
+        Ok(self.nodes.len() - 1)
+    }
+
+    fn lookup(&self, id: &str) -> Result<(&'a PackageMetadata, &'a ResolvedPackage)> {
+        let metadata = self.metadata;
+        let package = metadata
+            .packages
+            .get(id)
+            .ok_or_else(|| eyre!("unknown package {id}"))?;
+        let resolved = self
+            .resolved
+            .get(id)
+            .ok_or_else(|| eyre!("package {id} is not resolved for {}", self.target))?;
+        Ok((package, resolved))
+    }
+
+    /// Plans the linking of the libraries of the sysroot packages `ids` into a sysroot.
+    fn plan_sysroot(&mut self, ids: &'a [String]) -> Result<usize> {
+        let mut libs = Vec::new();
+        let mut deps = Vec::new();
+        for id in ids {
+            let node = self
+                .package(id, false)?
+                .rust_lib
+                .ok_or_else(|| eyre!("sysroot package {id} has no rust library"))?;
+            libs.push(self.nodes[node].out.clone());
+            deps.push(node);
+        }
+        let mut hash = Sha256::new();
+        hash.update(self.tools.as_bytes());
+        hash.update(self.target.as_bytes());
+        for lib in &libs {
+            hash.update([0]);
+            hash.update(lib.as_os_str().as_encoded_bytes());
+        }
+        let hash = hex::encode(&hash.finalize().as_slice()[0..8]);
+        let label = format!("{}-sysroot", sysroot::target_name(self.target));
+        self.nodes.push(Node {
+            out: self.out.join(format!("{label}-{hash}")),
+            label,
+            src: self.library_dir.clone().unwrap_or_default(),
+            step: Step::Sysroot {
+                target: self.target.to_string(),
+                libs,
+            },
+            deps,
+        });
+        Ok(self.nodes.len() - 1)
+    }
+
+    /// Plans running the test binaries of `package` built by the nodes `tests`, reporting their
+    /// coverage if `coverage` is set.
+    fn plan_tests(
+        &mut self,
+        package: &PackageMetadata,
+        kind: &str,
+        tests: Vec<usize>,
+        coverage: bool,
+    ) -> Result<usize> {
+        let src = self.src(package);
+        let package_dir = src
+            .join(&package.manifest_path)
+            .parent()
+            .ok_or_eyre("manifest has no parent dir")?
+            .to_path_buf();
+        let tests_out = Vec::from_iter(tests.iter().map(|test| self.nodes[*test].out.clone()));
+        let mut hash = Sha256::new();
+        hash.update(self.tools.as_bytes());
+        hash.update(kind.as_bytes());
+        hash.update([0]);
+        hash.update(package_dir.as_os_str().as_encoded_bytes());
+        for test in &tests_out {
+            hash.update([0]);
+            hash.update(test.as_os_str().as_encoded_bytes());
+        }
+        let hash = hex::encode(&hash.finalize().as_slice()[0..8]);
+        let label = format!("{}-{}-{kind}", package.pname, package.version);
+        let step = if coverage {
+            Step::Coverage {
+                package_dir,
+                tests: tests_out,
+            }
+        } else {
+            Step::Test {
+                package_dir,
+                tests: tests_out,
+            }
+        };
+        self.nodes.push(Node {
+            out: self.out.join(format!("{label}-{hash}")),
+            label,
+            src,
+            step,
+            deps: tests,
+        });
+        Ok(self.nodes.len() - 1)
+    }
+
+    /// Plans the PGO training run of the instrumented `bins` with the shell `command`.
+    fn plan_training(&mut self, bins: Vec<(String, usize)>, command: &str) -> Result<usize> {
+        if bins.is_empty() {
+            bail!("the workspace members have no bins to train");
+        }
+        let bins_out = Vec::from_iter(bins.iter().map(|(_, bin)| self.nodes[*bin].out.clone()));
+        let command = vec!["sh".to_string(), "-c".to_string(), command.to_string()];
+        let mut hash = Sha256::new();
+        hash.update(self.tools.as_bytes());
+        for arg in &command {
+            hash.update([0]);
+            hash.update(arg);
+        }
+        for bin in &bins_out {
+            hash.update([0]);
+            hash.update(bin.as_os_str().as_encoded_bytes());
+        }
+        let hash = hex::encode(&hash.finalize().as_slice()[0..8]);
+        let label = "pgo".to_string();
+        self.nodes.push(Node {
+            out: self.out.join(format!("{label}-{hash}")),
+            label,
+            src: self.project_dir.to_path_buf(),
+            step: Step::Pgo {
+                dir: self.project_dir.to_path_buf(),
+                bins: bins_out,
+                command,
+            },
+            deps: Vec::from_iter(bins.into_iter().map(|(_, bin)| bin)),
+        });
+        Ok(self.nodes.len() - 1)
+    }
+
+    /// Plans the compilation of one target of package `id`.
+    fn compile(
+        &mut self,
+        id: &str,
+        target: &'a CompileTarget,
+        kind: &str,
+        build_script_run: Option<usize>,
+        host: bool,
+    ) -> Result<usize> {
+        let (package, resolved) = self.lookup(id)?;
+        let (deps, mut dep_nodes) = self.deps(&target.deps, host)?;
+        dep_nodes.extend(build_script_run);
+        let job = CrateJob {
+            schema_version: SchemaVersion::default(),
+            common: self.common(
+                package,
+                &resolved.features,
+                &target.crate_name,
+                target.edition,
+                deps,
+                host,
+            ),
+            crate_type: target.crate_type.clone(),
+            entrypoint: target.entrypoint.clone(),
+            target_name: target.target_name.clone(),
+            build_script_run: build_script_run.map(|run| self.nodes[run].out.clone()),
+            metadata_only: false,
+            link_deps: Vec::new(),
+            check: false,
+            doctest: false,
+            version_script: None,
+        };
+        let kind = if host {
+            format!("{kind}-host")
+        } else {
+            kind.to_string()
+        };
+        self.push(
+            package,
+            &kind,
+            self.src(package),
+            Step::Compile(job),
+            dep_nodes,
+        )
+    }
+
+    /// Plans the build script and rust library of package `id`, everything its dependents need.
+    fn package(&mut self, id: &'a str, host: bool) -> Result<PackageNodes> {
+        if let Some(nodes) = self.packages.get(&(id, host)) {
+            return Ok(*nodes);
+        }
+        let (package, resolved) = self.lookup(id)?;
+        let mut build_script_run = None;
+        if let Some(build_script) = &resolved.build_script {
+            let script_host = host || self.separate_host();
+            let (deps, dep_nodes) = self.deps(&build_script.deps, script_host)?;
+            let job = CrateJob {
+                schema_version: SchemaVersion::default(),
+                common: self.common(
+                    package,
+                    &resolved.features,
+                    &build_script.crate_name,
+                    build_script.edition,
+                    deps,
+                    script_host,
+                ),
+                crate_type: build_script.crate_type.clone(),
+                entrypoint: build_script.entrypoint.clone(),
+                target_name: build_script.target_name.clone(),
+                build_script_run: None,
+                metadata_only: false,
+                link_deps: Vec::new(),
+                check: false,
+                doctest: false,
+                version_script: None,
+            };
+            let bin = self.push(
+                package,
+                if script_host {
+                    "build-script-host"
+                } else {
+                    "build-script"
+                },
+                self.src(package),
+                Step::Compile(job),
+                dep_nodes,
+            )?;
+            let (deps, mut dep_nodes) = self.deps(&build_script.main_deps, host)?;
+            dep_nodes.push(bin);
+            let job = BuildScriptJob {
+                schema_version: SchemaVersion::default(),
+                common: self.common(
+                    package,
+                    &resolved.features,
+                    &build_script.main_crate_name,
+                    build_script.edition,
+                    deps,
+                    host,
+                ),
+            };
+            let script = self.nodes[bin]
+                .out
+                .join("bin")
+                .join(&build_script.target_name);
+            build_script_run = Some(self.push(
+                package,
+                if host {
+                    "build-script-run-host"
+                } else {
+                    "build-script-run"
+                },
+                self.src(package),
+                Step::RunBuildScript { script, job },
+                dep_nodes,
+            )?);
+        }
+        let rust_lib = match &resolved.rust_lib {
+            Some(lib) => Some(self.compile(id, lib, "lib", build_script_run, host)?),
+            None => None,
+        };
+        let nodes = PackageNodes {
+            rust_lib,
+            build_script_run,
+        };
+        self.packages.insert((id, host), nodes);
+        Ok(nodes)
+    }
+
+    /// Plans workspace member `id` including its C library and bins, which dependents can't use.
+    fn member(&mut self, id: &'a str) -> Result<()> {
+        let (_, resolved) = self.lookup(id)?;
+        let nodes = self.package(id, self.host_only(resolved))?;
+        if let Some(lib) = &resolved.c_lib {
+            self.compile(id, lib, "clib", nodes.build_script_run, false)?;
+        }
+        for bin in resolved.bins.iter().flatten() {
+            let kind = format!("bin-{}", bin.target_name);
+            let node = self.compile(id, bin, &kind, nodes.build_script_run, false)?;
+            self.bins.push((bin.target_name.clone(), node));
+        }
+        if (self.coverage || self.sanitizer.is_some()) && !resolved.tests.is_empty() {
+            let mut tests = Vec::new();
+            for test in &resolved.tests {
+                let kind = format!("test-{}", test.target_name);
+                tests.push(self.compile(id, test, &kind, nodes.build_script_run, false)?);
+            }
+            let (package, _) = self.lookup(id)?;
+            if self.coverage {
+                let node = self.plan_tests(package, "coverage", tests.clone(), true)?;
+                self.coverages.push((package.pname.clone(), node));
+            }
+            if let Some(sanitizer) = self.sanitizer.clone() {
+                let node = self.plan_tests(package, &format!("tests-{sanitizer}"), tests, false)?;
+                self.test_runs.push((package.pname.clone(), node));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hash of the sources in `dir`.
+///
+/// Vendored crates are identified by their `.cargo-checksum.json`, workspace crates by all of
+/// their files, leaving out nested packages, hidden and `target` directories and `out`.
+fn source_hash(dir: &Path, out: &Path) -> Result<String> {
+    let checksum = dir.join(".cargo-checksum.json");
+    let mut hash = Sha256::new();
+    if checksum.is_file() {
+        hash.update(fs::read(&checksum).context("reading vendor checksum")?);
+    } else {
+        hash_dir(dir, dir, out, &mut hash)?;
+    }
+    Ok(hex::encode(hash.finalize()))
+}
+
+fn hash_dir(root: &Path, dir: &Path, out: &Path, hash: &mut Sha256) -> Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .with_context(|| format!("reading {}", dir.display()))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .context("reading directory entry")?;
+    entries.sort();
+    for entry in entries {
+        let name = entry.file_name().unwrap_or_default().to_string_lossy();
+        if entry.is_dir() {
+            if name.starts_with('.')
+                || name == "target"
+                || entry == out
+                || entry.join("Cargo.toml").is_file()
+            {
+                continue;
+            }
+            hash_dir(root, &entry, out, hash)?;
+        } else if entry.is_file() {
+            let relative = entry.strip_prefix(root).context("stripping source dir")?;
+            hash.update(relative.as_os_str().as_encoded_bytes());
+            hash.update([0]);
+            hash.update(fs::read(&entry).with_context(|| format!("reading {}", entry.display()))?);
+            hash.update([0]);
+        }
+    }
+    Ok(())
+}
+
+struct Tools {
+    exe: PathBuf,
+    cargo: PathBuf,
+    rustc: PathBuf,
+    rustdoc: PathBuf,
+    llvm_profdata: PathBuf,
+    llvm_cov: PathBuf,
+    cache: Option<PathBuf>,
+}
+
+/// The tool from `var`, or the one in the `bin` dir of `sysroot`.
+///
+/// Going through the rustup proxies would let the `rust-toolchain.toml` of vendored crates
+/// switch the compiler halfway through the build.
+fn tool(var: &str, name: &str, sysroot: &Path) -> PathBuf {
+    if let Some(path) = env::var_os(var) {
+        return PathBuf::from(path);
+    }
+    let path = sysroot.join("bin").join(name);
+    if path.is_file() {
+        path
+    } else {
+        PathBuf::from(name)
+    }
+}
+
+/// The sysroot of the rustc from `RUSTC`.
+fn sysroot() -> Result<PathBuf> {
+    let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let output = Command::new(rustc)
+        .arg("--print=sysroot")
+        .output()
+        .context("getting sysroot from rustc")?;
+    if !output.status.success() {
+        bail!("rustc failed to print its sysroot");
+    }
+    Ok(PathBuf::from(
+        String::from_utf8(output.stdout)
+            .context("outputs includes non utf-8")?
+            .trim(),
+    ))
+}
+
+/// Runs `node` through its subcommand, logging to `<out>.log`.
+fn execute(node: &Node, tools: &Tools) -> Result<()> {
+    if node.out.exists() {
+        fs::remove_dir_all(&node.out).context("removing stale output")?;
+    }
+    let job_path = node.with_suffix("json");
+    let mut command = Command::new(&tools.exe);
+    match &node.step {
+        Step::Compile(job) => {
+            fs::write(
+                &job_path,
+                serde_json::to_vec_pretty(job).context("serializing crate job")?,
+            )
+            .context("writing crate job")?;
+            command
+                .arg("compile")
+                .arg(&node.src)
+                .arg(&tools.cargo)
+                .arg(&tools.rustc);
+        }
+        Step::RunBuildScript { script, job } => {
+            fs::write(
+                &job_path,
+                serde_json::to_vec_pretty(job).context("serializing build script job")?,
+            )
+            .context("writing build script job")?;
+            command
+                .arg("run-build-script")
+                .arg(script)
+                .arg(&tools.cargo)
+                .arg(&tools.rustc)
+                .arg(&tools.rustdoc)
+                .arg(&node.src);
+        }
+        Step::Sysroot { target, libs } => {
+            command.arg("sysroot").arg(target).arg(&node.out).args(libs);
+        }
+        Step::Test { package_dir, tests } => {
+            command
+                .arg("test")
+                .arg(package_dir)
+                .arg(&node.out)
+                .args(tests);
+        }
+        Step::Coverage { package_dir, tests } => {
+            command
+                .arg("coverage")
+                .arg(&tools.llvm_profdata)
+                .arg(&tools.llvm_cov)
+                .arg(package_dir)
+                .arg(&node.out)
+                .args(tests);
+        }
+        Step::Pgo {
+            dir,
+            bins,
+            command: training,
+        } => {
+            command
+                .arg("pgo")
+                .arg(&tools.llvm_profdata)
+                .arg(dir)
+                .arg(&node.out)
+                .args(bins)
+                .arg("--")
+                .args(training);
+        }
+    }
+    if matches!(node.step, Step::Compile(_) | Step::RunBuildScript { .. }) {
+        command.arg(&job_path).arg(&node.out);
+    }
+    if let Some(cache) = &tools.cache {
+        command.env(CACHE_ENV, cache);
+    }
+    let log_path = node.with_suffix("log");
+    let log = File::create(&log_path).context("creating job log")?;
+    let status = command
+        .stdout(log.try_clone().context("duplicating job log")?)
+        .stderr(log)
+        .stdin(Stdio::null())
+        .status()
+        .with_context(|| format!("executing {}", node.label))?;
+    if !status.success() {
+        // the diagnostics are rendered in the log already
+        let log = fs::read_to_string(&log_path).unwrap_or_default();
+        let log = Vec::from_iter(
+            log.lines()
+                .filter(|line| !line.starts_with(FAILURE_LOG_PREFIX)),
+        );
+        bail!(
+            "{} failed with {status}, rerun with\n{command:?}\nlog:\n{}",
+            node.label,
+            log.join("\n")
+        );
+    }
+    File::create(node.with_suffix(DONE_SUFFIX)).context("marking job as done")?;
+    Ok(())
+}
+
+/// Runs every node whose output is missing, at most `jobs` at a time and each one only after
+/// all of its dependencies.
+fn schedule(nodes: &[Node], tools: &Tools, jobs: usize) -> Result<()> {
+    let total = nodes.len();
+    let mut done: Vec<bool> = nodes.iter().map(Node::done).collect();
+    let skipped = done.iter().filter(|d| **d).count();
+    println!("{skipped} of {total} jobs are up to date");
+    let mut started: Vec<bool> = done.clone();
+    let mut failed = Vec::new();
+    let mut running = 0;
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| -> Result<()> {
+        loop {
+            if failed.is_empty() {
+                for (index, node) in nodes.iter().enumerate() {
+                    if running >= jobs {
+                        break;
+                    }
+                    if started[index] || !node.deps.iter().all(|d| done[*d]) {
+                        continue;
+                    }
+                    started[index] = true;
+                    running += 1;
+                    println!("building {}", node.label);
+                    let sender = sender.clone();
+                    scope.spawn(move || {
+                        // the receiver only goes away after all jobs finished
+                        let _ = sender.send((index, execute(node, tools)));
+                    });
+                }
+            }
+            if running == 0 {
+                break;
+            }
+            let (index, result) = receiver.recv().context("receiving job result")?;
+            running -= 1;
+            match result {
+                Ok(()) => done[index] = true,
+                Err(e) => failed.push(e),
+            }
+        }
+        Ok(())
+    })?;
+    if let Some(e) = failed.into_iter().next() {
+        return Err(e);
+    }
+    if let Some(node) = done.iter().position(|d| !d) {
+        bail!("{} was never ready to build", nodes[node].label);
+    }
+    Ok(())
+}
+
+/// Links the outputs of the per member `nodes` into `dir`, named after the member.
+fn link_members(
+    nodes: &[Node],
+    dir: &Path,
+    members: Vec<(String, usize)>,
+    what: &str,
+) -> Result<()> {
+    if members.is_empty() {
+        return Ok(());
+    }
+    fs::create_dir_all(dir).with_context(|| format!("creating {what} dir"))?;
+    for (pname, node) in members {
+        let link = dir.join(&pname);
+        if link.symlink_metadata().is_ok() {
+            fs::remove_file(&link).with_context(|| format!("removing old {what} link"))?;
+        }
+        symlink(&nodes[node].out, &link).with_context(|| format!("linking {what}"))?;
+        println!("{what} of {pname} in {}", link.display());
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+pub struct BuildOptions {
+    /// Target to build for, defaults to the host of rustc.
+    #[arg(long)]
+    pub target: Option<String>,
+    /// Number of jobs run in parallel, defaults to the available parallelism.
+    #[arg(long, short)]
+    pub jobs: Option<usize>,
+    /// Build with optimizations.
+    #[arg(long)]
+    pub release: bool,
+    /// Profile whose settings, like the panic strategy, are applied. Defaults to `release`
+    /// with `--release` and `dev` otherwise.
+    #[arg(long)]
+    pub profile: Option<String>,
+    /// Debuginfo level instead of the profile's, `full` if neither sets one.
+    #[arg(long, value_parser = [
+        "none",
+        "line-directives-only",
+        "line-tables-only",
+        "limited",
+        "full",
+    ])]
+    pub debuginfo: Option<String>,
+    /// Split the debuginfo off the target crates instead of the profile's setting. `packed`
+    /// writes a `.dwp` next to every linked bin, cdylib and test.
+    #[arg(long, value_parser = ["off", "packed", "unpacked"])]
+    pub split_debuginfo: Option<String>,
+    /// Reuse compiled crates from this cache directory, see the `cache` subcommand.
+    #[arg(long)]
+    pub cache: Option<PathBuf>,
+    /// Instrument the workspace crates, run the tests of every member and write its lcov and
+    /// summary report to `out/coverage/<member>`. `llvm-profdata` and `llvm-cov` are taken from
+    /// `LLVM_PROFDATA` and `LLVM_COV` or the `llvm-tools` component.
+    #[arg(long)]
+    pub coverage: bool,
+    /// Build everything for the target with `-Z sanitizer`, like `address` or `thread`, and
+    /// run the tests of every member, linked into `out/tests-<sanitizer>/<member>`.
+    #[arg(long)]
+    pub sanitizer: Option<String>,
+    /// Optimize with profile-guided optimization. The shell command runs in the project dir
+    /// against builds of the members' bins instrumented with `-C profile-generate`, whose
+    /// profile every crate of the target is rebuilt with. `llvm-profdata` is taken from
+    /// `LLVM_PROFDATA` or the `llvm-tools` component.
+    #[arg(long, conflicts_with_all = ["coverage", "sanitizer"])]
+    pub pgo: Option<String>,
+    /// Linker of the target, instead of the one from `.cargo/config.toml`.
+    #[arg(long)]
+    pub linker: Option<String>,
+    /// Linker flavor of the target, like `gnu-lld-cc`.
+    #[arg(long)]
+    pub linker_flavor: Option<String>,
+    /// CPU the target crates are built for, like `x86-64-v3` or `native`, instead of the one
+    /// from `.cargo/config.toml`. Build scripts and proc macros are built for the default CPU.
+    #[arg(long)]
+    pub target_cpu: Option<String>,
+    /// Feature like `+avx2` the target crates are built with, on top of the ones from
+    /// `.cargo/config.toml`.
+    #[arg(long)]
+    pub target_feature: Vec<String>,
+}
+
+/// Builds the workspace described by `metadata` for `target` without nix.
+///
+/// Every compile and build script job of the plan gets its own directory in `out`, named after
+/// the hash of its job, its sources and the tools, and is skipped if that directory was
+/// already built. Jobs run as `nix-rust-build compile` and `run-build-script` subprocesses,
+/// whose job json and log are kept next to their output for debugging. With `cache` they
+/// share the compile cache in that directory. The bins of the workspace members are linked
+/// into `out/bin`, their coverage reports into `out/coverage` and sanitized test runs into
+/// `out/tests-<sanitizer>`. With PGO only the optimized bins are linked.
+pub fn run(
+    metadata: PathBuf,
+    project_dir: PathBuf,
+    vendor_dir: PathBuf,
+    out: PathBuf,
+    options: BuildOptions,
+) -> Result<()> {
+    let BuildOptions {
+        target,
+        jobs,
+        release,
+        profile,
+        debuginfo,
+        split_debuginfo,
+        cache,
+        coverage,
+        sanitizer,
+        pgo,
+        linker,
+        linker_flavor,
+        target_cpu,
+        target_feature,
+    } = options;
+    let metadata: Metadata =
+        serde_json::from_slice(&fs::read(metadata).context("reading metadata")?)
+            .context("deserializing metadata")?;
+    let sysroot = sysroot()?;
+    let rustc = tool("RUSTC", "rustc", &sysroot);
+    let host = rustc_host_tripple(&rustc)?.trim().to_string();
+    // the llvm-tools component installs next to the target libraries of the host
+    let llvm_tools = sysroot.join("lib/rustlib").join(&host);
+    let tools = Tools {
+        exe: env::current_exe().context("getting nix-rust-build executable")?,
+        cargo: tool("CARGO", "cargo", &sysroot),
+        rustc,
+        rustdoc: tool("RUSTDOC", "rustdoc", &sysroot),
+        llvm_profdata: tool("LLVM_PROFDATA", "llvm-profdata", &llvm_tools),
+        llvm_cov: tool("LLVM_COV", "llvm-cov", &llvm_tools),
+        cache: match cache {
+            Some(cache) => {
+                fs::create_dir_all(&cache).context("creating cache dir")?;
+                Some(cache.canonicalize().context("resolving cache dir")?)
+            }
+            None => None,
+        },
+    };
+    let target = match target {
+        Some(target) => sysroot::resolve_target(target)?,
+        None => host.clone(),
+    };
+    let target_metadata = metadata.targets.get(&target).ok_or_else(|| {
+        eyre!(
+            "metadata has no target {target}, available: {}",
+            Vec::from_iter(metadata.targets.keys().map(String::as_str)).join(", ")
+        )
+    })?;
+    let profile = profile.unwrap_or_else(|| if release { "release" } else { "dev" }.to_string());
+    let profile = match metadata.profiles.get(&profile) {
+        Some(profile) => Some(profile),
+        // documents written before profiles were recorded
+        None if metadata.profiles.is_empty() => None,
+        None => return Err(eyre!("metadata has no profile {profile}")),
+    };
+    let panic = profile.and_then(|profile| profile.panic.clone());
+    let debuginfo = debuginfo.or_else(|| profile.and_then(|profile| profile.debug.clone()));
+    let split_debuginfo =
+        split_debuginfo.or_else(|| profile.and_then(|profile| profile.split_debuginfo.clone()));
+    let mut codegen = target_metadata.codegen.clone();
+    codegen.linker = linker.or(codegen.linker);
+    codegen.linker_flavor = linker_flavor.or(codegen.linker_flavor);
+    codegen.target_cpu = target_cpu.or(codegen.target_cpu);
+    codegen.target_features.extend(target_feature);
+    let library_dir = if target_metadata.sysroot.is_empty() {
+        None
+    } else {
+        Some(sysroot::library_dir(&tools.rustc)?)
+    };
+    fs::create_dir_all(&out).context("creating output dir")?;
+    let out = out.canonicalize().context("resolving output dir")?;
+    let project_dir = project_dir
+        .canonicalize()
+        .context("resolving project dir")?;
+    let vendor_dir = vendor_dir.canonicalize().context("resolving vendor dir")?;
+    let version = Command::new(&tools.rustc)
+        .arg("-vV")
+        .output()
+        .context("getting rustc version")?
+        .stdout;
+    let mut planner = Planner {
+        metadata: &metadata,
+        resolved: &target_metadata.packages,
+        target: &target,
+        host: &host,
+        project_dir: &project_dir,
+        vendor_dir: &vendor_dir,
+        library_dir,
+        out: &out,
+        optimize: release,
+        panic,
+        debuginfo,
+        split_debuginfo,
+        codegen,
+        coverage,
+        sanitizer: sanitizer.clone(),
+        profile_generate: false,
+        training: None,
+        sysroot: None,
+        tools: format!(
+            "{}\n{}\n{}\n{}",
+            tools.exe.display(),
+            tools.cargo.display(),
+            tools.rustdoc.display(),
+            String::from_utf8_lossy(&version)
+        ),
+        nodes: Vec::new(),
+        packages: BTreeMap::new(),
+        bins: Vec::new(),
+        coverages: Vec::new(),
+        test_runs: Vec::new(),
+    };
+    if !target_metadata.sysroot.is_empty() {
+        planner.sysroot = Some(planner.plan_sysroot(&target_metadata.sysroot)?);
+    }
+    let members: BTreeSet<&str> = metadata.workspace.values().map(String::as_str).collect();
+    if let Some(command) = &pgo {
+        planner.profile_generate = true;
+        for id in &members {
+            planner.member(id)?;
+        }
+        let bins = std::mem::take(&mut planner.bins);
+        planner.training = Some(planner.plan_training(bins, command)?);
+        planner.profile_generate = false;
+        // the host packages and the sysroot are built the same way in both passes
+        planner
+            .packages
+            .retain(|(id, host), _| *host || metadata.packages[*id].sysroot_crate);
+    }
+    for id in members {
+        planner.member(id)?;
+    }
+    let Planner {
+        nodes,
+        bins,
+        coverages,
+        test_runs,
+        ..
+    } = planner;
+    let jobs = match jobs {
+        Some(jobs) => jobs.max(1),
+        None => thread::available_parallelism()
+            .context("getting available parallelism")?
+            .get(),
+    };
+    schedule(&nodes, &tools, jobs)?;
+
+    let bin_dir = out.join("bin");
+    fs::create_dir_all(&bin_dir).context("creating bin dir")?;
+    for (_, node) in bins {
+        // named after the target's conventions, like `name.exe` or `name.wasm`
+        let dir = nodes[node].out.join("bin");
+        for entry in fs::read_dir(&dir).context("reading bin dir")? {
+            let file_name = entry.context("reading bin dir entry")?.file_name();
+            // the bin refers to its unpacked dwarf objects by their path in the node
+            if Path::new(&file_name).extension() == Some("dwo".as_ref()) {
+                continue;
+            }
+            let link = bin_dir.join(&file_name);
+            if link.symlink_metadata().is_ok() {
+                fs::remove_file(&link).context("removing old bin link")?;
+            }
+            symlink(dir.join(&file_name), &link).context("linking bin")?;
+            println!("built {}", link.display());
+        }
+    }
+    link_members(&nodes, &out.join("coverage"), coverages, "coverage")?;
+    if let Some(sanitizer) = sanitizer {
+        let dir = out.join(format!("tests-{sanitizer}"));
+        link_members(&nodes, &dir, test_runs, "tests")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,483 @@
+use std::{
+    collections::BTreeSet,
+    env,
+    ffi::OsString,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    process::{self, Command},
+    time::{Duration, SystemTime},
+};
+
+use clap::Subcommand;
+use color_eyre::eyre::{Context, OptionExt, Result, bail, eyre};
+use sha2::{Digest, Sha256};
+
+use crate::dep_info;
+
+/// Environment variable naming the cache directory, the cache is disabled without it.
+pub const CACHE_ENV: &str = "NIX_RUST_BUILD_CACHE";
+
+/// Changes whenever the key or the layout of the entries changes.
+const CACHE_VERSION: &str = "nix-rust-build cache 1";
+
+/// Temporary entries older than this are left over from aborted builds.
+const STALE_TMP: Duration = Duration::from_secs(60 * 60);
+
+/// On-disk cache of the files rustc writes to an output directory.
+///
+/// Entries live in `entries/<key>`, with the files in `files` and a `used` marker whose
+/// modification time is the last use. Every lookup and store is appended to `events`.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// The cache from [`CACHE_ENV`], if it is set.
+    pub fn from_env() -> Option<Self> {
+        env::var_os(CACHE_ENV)
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| Self::new(dir.into()))
+    }
+
+    fn entries(&self) -> PathBuf {
+        self.dir.join("entries")
+    }
+
+    fn tmp(&self) -> PathBuf {
+        self.dir.join("tmp")
+    }
+
+    fn record(&self, event: &str, key: &str) -> Result<()> {
+        fs::create_dir_all(&self.dir).context("creating cache dir")?;
+        // appends of a single short line don't interleave between concurrent builds
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join("events"))
+            .and_then(|mut events| events.write_all(format!("{event} {key}\n").as_bytes()))
+            .context("recording cache event")
+    }
+
+    /// Computes the key of `command`, which writes to `out` and reads the outputs in `dep_dirs`
+    /// and of its `build_script` run.
+    ///
+    /// The key covers the toolchain, the invocation with these directories replaced by
+    /// placeholders, the contents of the build script output, of every `--extern` and of every
+    /// file rustc reports in its dep-info, together with the environment read through `env!`.
+    /// Returns `None` if the dep-info can't be generated, in which case the crate is not cached.
+    pub fn key(
+        &self,
+        command: &Command,
+        out: &Path,
+        dep_dirs: &BTreeSet<PathBuf>,
+        build_script: Option<&Path>,
+    ) -> Result<Option<String>> {
+        let mut replacements: Vec<(String, &str)> = dep_dirs
+            .iter()
+            .map(|dir| (dir.display().to_string(), "<dep>"))
+            .collect();
+        replacements.push((out.display().to_string(), "<out>"));
+        if let Some(dir) = build_script {
+            replacements.push((dir.display().to_string(), "<build-script>"));
+        }
+        let sysroot = command
+            .get_args()
+            .skip_while(|arg| *arg != "--sysroot")
+            .nth(1)
+            .map(PathBuf::from);
+        if let Some(dir) = &sysroot {
+            replacements.push((dir.display().to_string(), "<sysroot>"));
+        }
+        // longer paths first, so a directory doesn't replace part of a sibling sharing its prefix
+        replacements.sort_by_key(|(from, _)| std::cmp::Reverse(from.len()));
+        let normalize = |value: &str| {
+            replacements
+                .iter()
+                .fold(value.to_string(), |value, (from, to)| {
+                    value.replace(from, to)
+                })
+        };
+
+        let mut hash = Sha256::new();
+        let mut field = |value: &[u8]| {
+            hash.update(value);
+            hash.update([0]);
+        };
+        field(CACHE_VERSION.as_bytes());
+        let version = Command::new(command.get_program())
+            .arg("-vV")
+            .output()
+            .context("getting compiler version")?;
+        field(&version.stdout);
+        if let Some(dir) = build_script {
+            // generated sources are part of the dep-info, but native libraries are only read by
+            // the linker, other leftovers of the build script don't matter
+            for file in files_below(dir)? {
+                let content = || {
+                    fs::read(dir.join(&file)).with_context(|| format!("reading {}", file.display()))
+                };
+                if file == Path::new("result.toml") {
+                    field(file.as_os_str().as_encoded_bytes());
+                    field(normalize(&String::from_utf8_lossy(&content()?)).as_bytes());
+                } else if is_native_lib(&file) {
+                    field(file.as_os_str().as_encoded_bytes());
+                    field(&Sha256::digest(content()?));
+                }
+            }
+        }
+        if let Some(dir) = &sysroot {
+            field(&sysroot_hash(dir)?);
+        }
+        if let Some(dir) = command.get_current_dir() {
+            field(normalize(&dir.to_string_lossy()).as_bytes());
+        }
+        let mut envs: Vec<_> = command.get_envs().collect();
+        envs.sort();
+        for (name, value) in envs {
+            field(name.as_encoded_bytes());
+            field(normalize(&value.unwrap_or_default().to_string_lossy()).as_bytes());
+        }
+        let args: Vec<_> = command.get_args().collect();
+        for (index, arg) in args.iter().enumerate() {
+            let arg = arg.to_string_lossy();
+            field(normalize(&arg).as_bytes());
+            if let Some(script) = arg.strip_prefix("link-arg=-Wl,--version-script=") {
+                field(&file_hash(Path::new(script))?);
+            }
+            if let Some(profile) = arg.strip_prefix("profile-use=") {
+                field(&file_hash(Path::new(profile))?);
+            }
+            if index > 0
+                && args[index - 1] == "--extern"
+                && let Some((_, path)) = arg.split_once('=')
+            {
+                field(&file_hash(Path::new(path))?);
+            }
+        }
+
+        fs::create_dir_all(self.tmp()).context("creating cache tmp dir")?;
+        let dep_info = self.tmp().join(format!("{}.d", process::id()));
+        let mut dep_info_command = Command::new(command.get_program());
+        let mut emit = false;
+        for arg in command.get_args() {
+            if emit {
+                let mut value = OsString::from("dep-info=");
+                value.push(&dep_info);
+                dep_info_command.arg(value);
+            } else {
+                dep_info_command.arg(arg);
+            }
+            emit = arg == "--emit";
+        }
+        for (name, value) in command.get_envs() {
+            match value {
+                Some(value) => dep_info_command.env(name, value),
+                None => dep_info_command.env_remove(name),
+            };
+        }
+        if let Some(dir) = command.get_current_dir() {
+            dep_info_command.current_dir(dir);
+        }
+        println!("generating dep-info for the cache key");
+        let status = dep_info_command
+            .output()
+            .context("generating dep-info")?
+            .status;
+        if !status.success() {
+            println!("dep-info failed with {status}, not caching");
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&dep_info).context("reading dep-info")?;
+        fs::remove_file(&dep_info).context("removing dep-info")?;
+        let (inputs, env_deps) = dep_info::parse(&content);
+        let cwd = command.get_current_dir().unwrap_or(Path::new(""));
+        for input in inputs {
+            field(normalize(&input.to_string_lossy()).as_bytes());
+            field(&file_hash(&cwd.join(&input))?);
+        }
+        for env_dep in env_deps {
+            field(normalize(env_dep).as_bytes());
+        }
+        Ok(Some(hex::encode(hash.finalize())))
+    }
+
+    /// Copies the files of entry `key` into `out`, returning whether there was one.
+    pub fn restore(&self, key: &str, out: &Path) -> Result<bool> {
+        let entry = self.entries().join(key);
+        if !entry.join("used").is_file() {
+            self.record("miss", key)?;
+            return Ok(false);
+        }
+        let files = entry.join("files");
+        let stored = if files.is_dir() {
+            files_below(&files)?
+        } else {
+            BTreeSet::new()
+        };
+        for file in stored {
+            let target = out.join(&file);
+            if let Some(dir) = target.parent() {
+                fs::create_dir_all(dir).context("creating output dir")?;
+            }
+            fs::copy(files.join(&file), &target)
+                .with_context(|| format!("restoring {}", file.display()))?;
+        }
+        fs::write(entry.join("used"), "").context("marking cache entry as used")?;
+        self.record("hit", key)?;
+        println!("restored {} from cache entry {key}", out.display());
+        Ok(true)
+    }
+
+    /// Stores every file in `out` that is not in `before` as entry `key`.
+    pub fn store(&self, key: &str, out: &Path, before: &BTreeSet<PathBuf>) -> Result<()> {
+        let entry = self.entries().join(key);
+        if entry.is_dir() {
+            return Ok(());
+        }
+        let tmp = self.tmp().join(format!("{key}.{}", process::id()));
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp).context("removing old cache tmp dir")?;
+        }
+        for file in files_below(out)?.difference(before) {
+            let target = tmp.join("files").join(file);
+            if let Some(dir) = target.parent() {
+                fs::create_dir_all(dir).context("creating cache entry dir")?;
+            }
+            fs::copy(out.join(file), &target)
+                .with_context(|| format!("caching {}", file.display()))?;
+        }
+        fs::create_dir_all(&tmp).context("creating cache entry dir")?;
+        fs::write(tmp.join("used"), "").context("marking cache entry as used")?;
+        fs::create_dir_all(self.entries()).context("creating cache entries dir")?;
+        // another build may have stored the same entry in the meantime
+        if fs::rename(&tmp, &entry).is_err() && entry.is_dir() {
+            fs::remove_dir_all(&tmp).context("removing duplicate cache entry")?;
+        } else if !entry.is_dir() {
+            bail!("failed to store cache entry {key}");
+        }
+        self.record("store", key)
+    }
+
+    /// Every entry with its last use and size in bytes, least recently used first.
+    fn list(&self) -> Result<Vec<(PathBuf, SystemTime, u64)>> {
+        let entries = self.entries();
+        if !entries.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut list = Vec::new();
+        for entry in fs::read_dir(&entries).context("reading cache entries")? {
+            let path = entry.context("reading cache entry")?.path();
+            let used = fs::metadata(path.join("used"))
+                .and_then(|m| m.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            let size = files_below(&path)?
+                .iter()
+                .map(|file| fs::metadata(path.join(file)).map(|m| m.len()))
+                .sum::<Result<u64, _>>()
+                .context("reading cache entry size")?;
+            list.push((path, used, size));
+        }
+        list.sort_by_key(|(path, used, _)| (*used, path.clone()));
+        Ok(list)
+    }
+}
+
+fn is_native_lib(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.contains(".so")
+        || ["a", "o", "lib", "obj", "dylib", "dll"]
+            .iter()
+            .any(|extension| path.extension().is_some_and(|e| e == *extension))
+}
+
+fn file_hash(path: &Path) -> Result<Vec<u8>> {
+    match fs::read(path) {
+        Ok(content) => Ok(Sha256::digest(content).to_vec()),
+        // inputs removed since, like files generated by a proc macro
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(b"missing".to_vec()),
+        Err(e) => Err(eyre!(e).wrap_err(format!("hashing {}", path.display()))),
+    }
+}
+
+/// Hashes the libraries of a sysroot written by [`crate::sysroot::run`], which are symlinks to
+/// the outputs of their compile jobs.
+fn sysroot_hash(sysroot: &Path) -> Result<Vec<u8>> {
+    let mut libs = Vec::new();
+    let rustlib = sysroot.join("lib/rustlib");
+    for target in
+        fs::read_dir(&rustlib).with_context(|| format!("reading {}", rustlib.display()))?
+    {
+        let lib_dir = target
+            .context("reading directory entry")?
+            .path()
+            .join("lib");
+        for lib in
+            fs::read_dir(&lib_dir).with_context(|| format!("reading {}", lib_dir.display()))?
+        {
+            libs.push(lib.context("reading directory entry")?.path());
+        }
+    }
+    libs.sort();
+    let mut hash = Sha256::new();
+    for lib in libs {
+        hash.update(
+            lib.strip_prefix(sysroot)
+                .context("stripping sysroot")?
+                .as_os_str()
+                .as_encoded_bytes(),
+        );
+        hash.update(file_hash(&lib)?);
+    }
+    Ok(hash.finalize().to_vec())
+}
+
+/// The regular files below `dir`, relative to it. Symlinks are left out.
+pub fn files_below(dir: &Path) -> Result<BTreeSet<PathBuf>> {
+    let mut files = BTreeSet::new();
+    let mut queue = vec![dir.to_path_buf()];
+    while let Some(current) = queue.pop() {
+        for entry in
+            fs::read_dir(&current).with_context(|| format!("reading {}", current.display()))?
+        {
+            let entry = entry.context("reading directory entry")?;
+            let file_type = entry.file_type().context("reading file type")?;
+            if file_type.is_dir() {
+                queue.push(entry.path());
+            } else if file_type.is_file() {
+                files.insert(
+                    entry
+                        .path()
+                        .strip_prefix(dir)
+                        .context("stripping directory")?
+                        .to_path_buf(),
+                );
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Parses sizes like `512M` or `10G` into bytes.
+fn parse_size(size: &str) -> Result<u64> {
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => size.split_at(index),
+        None => (size, ""),
+    };
+    let number: u64 = number.parse().context("parsing size")?;
+    let factor = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        unit => return Err(eyre!("unknown size unit {unit}")),
+    };
+    Ok(number * factor)
+}
+
+#[derive(Subcommand)]
+pub enum CacheCommand {
+    /// Prints the number and size of the entries and the hits and misses so far.
+    Stats {
+        /// Cache directory, defaults to `NIX_RUST_BUILD_CACHE`.
+        dir: Option<PathBuf>,
+    },
+    /// Removes the least recently used entries.
+    Gc {
+        /// Cache directory, defaults to `NIX_RUST_BUILD_CACHE`.
+        dir: Option<PathBuf>,
+        /// Remove entries until the cache is at most this large, like `10G`.
+        #[arg(long, value_parser = |s: &str| parse_size(s).map_err(|e| e.to_string()))]
+        max_size: Option<u64>,
+        /// Remove entries unused for more than this many days.
+        #[arg(long)]
+        max_age: Option<u64>,
+    },
+}
+
+fn cache_dir(dir: Option<PathBuf>) -> Result<Cache> {
+    dir.map(Cache::new)
+        .or_else(Cache::from_env)
+        .ok_or_eyre(format!("no cache dir given and {CACHE_ENV} is not set"))
+}
+
+pub fn run(command: CacheCommand) -> Result<()> {
+    match command {
+        CacheCommand::Stats { dir } => {
+            let cache = cache_dir(dir)?;
+            let entries = cache.list()?;
+            let size: u64 = entries.iter().map(|(_, _, size)| size).sum();
+            let events = fs::read_to_string(cache.dir.join("events")).unwrap_or_default();
+            let count = |event: &str| {
+                events
+                    .lines()
+                    .filter(|line| line.split(' ').next() == Some(event))
+                    .count()
+            };
+            let (hits, misses) = (count("hit"), count("miss"));
+            println!("cache: {}", cache.dir.display());
+            println!("entries: {}", entries.len());
+            println!("size: {:.1} MiB", size as f64 / (1 << 20) as f64);
+            println!("hits: {hits}");
+            println!("misses: {misses}");
+            if hits + misses > 0 {
+                println!(
+                    "hit rate: {:.1}%",
+                    hits as f64 * 100.0 / (hits + misses) as f64
+                );
+            }
+            Ok(())
+        }
+        CacheCommand::Gc {
+            dir,
+            max_size,
+            max_age,
+        } => {
+            let cache = cache_dir(dir)?;
+            if max_size.is_none() && max_age.is_none() {
+                bail!("nothing to collect, pass --max-size or --max-age");
+            }
+            let now = SystemTime::now();
+            if let Ok(tmp) = fs::read_dir(cache.tmp()) {
+                for entry in tmp {
+                    let path = entry.context("reading cache tmp dir")?.path();
+                    let modified = fs::metadata(&path).and_then(|m| m.modified());
+                    if modified.is_ok_and(|m| now.duration_since(m).unwrap_or_default() > STALE_TMP)
+                    {
+                        if path.is_dir() {
+                            fs::remove_dir_all(&path)
+                        } else {
+                            fs::remove_file(&path)
+                        }
+                        .context("removing stale cache tmp entry")?;
+                    }
+                }
+            }
+            let entries = cache.list()?;
+            let mut size: u64 = entries.iter().map(|(_, _, size)| size).sum();
+            let max_age = max_age.map(|days| Duration::from_secs(days * 24 * 60 * 60));
+            let mut removed = 0;
+            for (path, used, entry_size) in entries {
+                let too_old = max_age
+                    .is_some_and(|max_age| now.duration_since(used).unwrap_or_default() > max_age);
+                let too_large = max_size.is_some_and(|max_size| size > max_size);
+                if !too_old && !too_large {
+                    continue;
+                }
+                fs::remove_dir_all(&path).context("removing cache entry")?;
+                size -= entry_size;
+                removed += 1;
+            }
+            println!(
+                "removed {removed} entries, {:.1} MiB left",
+                size as f64 / (1 << 20) as f64
+            );
+            Ok(())
+        }
+    }
+}
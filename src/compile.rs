@@ -1,59 +1,24 @@
-use cargo_metadata::Edition;
-use color_eyre::eyre::{bail, eyre, Context, OptionExt, Result};
-use serde::{Deserialize, Serialize};
+use color_eyre::eyre::{Context, OptionExt, Result, bail, eyre};
 use sha2::{Digest, Sha256};
 use std::{
-    collections::{HashMap, HashSet},
-    env, fs,
-    os::unix::{fs::symlink, process::CommandExt},
+    collections::{BTreeMap, BTreeSet, HashMap},
+    env,
+    ffi::{OsStr, OsString},
+    fs,
+    os::unix::fs::symlink,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Stdio},
 };
 
-use crate::run_build_script::BuildScriptResult;
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct RustLibMetadata {
-    pub lib: PathBuf,
-    pub deps: HashSet<PathBuf>,
-    pub metadata: HashMap<String, String>,
-    pub links: Option<String>,
-    pub lib_path: HashSet<String>,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct ResolvedDep {
-    pub name: String,
-    pub path: PathBuf,
-}
-
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct CrateJobCommon {
-    pub rustc_flags: Vec<String>,
-    pub cfgs: Vec<String>,
-    pub link_args: Vec<String>,
-    pub manifest_path: PathBuf,
-    pub version: String,
-    pub authors: Option<Vec<String>>,
-    pub pname: String,
-    pub description: Option<String>,
-    pub homepage: Option<String>,
-    pub repository: Option<String>,
-    pub license: Option<String>,
-    pub license_file: Option<PathBuf>,
-    pub rust_version: Option<String>,
-    pub readme: Option<PathBuf>,
-    pub target: String,
-    pub features: Vec<String>,
-    pub all_features: Vec<String>,
-    pub crate_name: String,
-    pub edition: Edition,
-    pub deps: Vec<ResolvedDep>,
-    pub links: Option<String>,
-    pub optimize: bool,
-    pub debuginfo: bool,
-}
+use crate::{
+    cache::{self, Cache},
+    dep_info, diagnostics, rpath,
+    schema::{
+        self, BuildScriptResult, CrateJobCommon, DepInfo, RustLibMetadata, SchemaVersion,
+        TargetCodegen,
+    },
+    sysroot,
+};
 
 fn s(s: &Option<String>) -> &str {
     s.as_deref().unwrap_or("")
@@ -63,11 +28,12 @@ fn p(p: &Option<PathBuf>, base: &Path) -> PathBuf {
     p.as_deref().map(|p| base.join(p)).unwrap_or_default()
 }
 
-fn o(o: &Option<Vec<String>>) -> String{
-    o.as_deref().map(|v|v.join(" ")).unwrap_or_default()
+fn o(o: &Option<Vec<String>>) -> String {
+    o.as_deref().map(|v| v.join(" ")).unwrap_or_default()
 }
 
 impl CrateJobCommon {
+    /// Sets the `CARGO_*` environment cargo provides to rustc and build scripts.
     pub fn add_metadata_env(&self, cargo: &Path, src: &Path, command: &mut Command) -> Result<()> {
         let version = cargo_metadata::semver::Version::parse(&self.version)
             .context("parsing crate version")?;
@@ -99,32 +65,100 @@ impl CrateJobCommon {
     }
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct CrateJob {
-    #[serde(flatten)]
-    common: CrateJobCommon,
-    crate_type: String,
-    entrypoint: PathBuf,
-    target_name: String,
-    build_script_run: Option<PathBuf>,
-    #[serde(default)]
-    metadata: HashMap<String, String>,
-    #[serde(default)]
-    lib_path: HashSet<String>,
-    #[serde(default)]
+impl TargetCodegen {
+    /// The settings that also apply to build scripts and proc macros, which run on the build
+    /// machine, like cargo uses the linker of `target.<host>` for them.
+    pub fn linker_only(&self) -> Self {
+        Self {
+            linker: self.linker.clone(),
+            linker_flavor: self.linker_flavor.clone(),
+            ..Self::default()
+        }
+    }
+
+    /// Whether a CPU or features are set, which the build machine may not support.
+    pub fn tunes_cpu(&self) -> bool {
+        self.target_cpu.is_some() || !self.target_features.is_empty()
+    }
+
+    /// The `-C` arguments passing the settings to rustc.
+    pub fn rustc_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut arg = |name: &str, value: &str| {
+            args.push("-C".to_string());
+            args.push(format!("{name}={value}"));
+        };
+        if let Some(linker) = &self.linker {
+            arg("linker", linker);
+        }
+        if let Some(flavor) = &self.linker_flavor {
+            arg("linker-flavor", flavor);
+        }
+        if let Some(cpu) = &self.target_cpu {
+            arg("target-cpu", cpu);
+        }
+        if !self.target_features.is_empty() {
+            arg("target-feature", &self.target_features.join(","));
+        }
+        args
+    }
+}
+
+/// Codegen units of reproducible builds, rustc's default for non-incremental builds.
+const REPRODUCIBLE_CODEGEN_UNITS: u32 = 16;
+
+/// Codegen units of `compiler_builtins`, as in the profile of the `library` workspace. Every
+/// intrinsic gets its own object, so the linker doesn't pull in ones clashing with libgcc.
+const COMPILER_BUILTINS_CODEGEN_UNITS: u32 = 10000;
+
+/// A single rustc invocation, together with the state collected from its build script
+/// and dependencies.
+#[derive(Debug)]
+pub struct CrateJob {
+    job: schema::CrateJob,
+    metadata: BTreeMap<String, String>,
+    lib_path: BTreeSet<String>,
     link_lib: Vec<String>,
-    #[serde(default)]
-    all_deps: HashSet<PathBuf>,
-    #[serde(default)]
+    dep_link_lib: BTreeSet<String>,
+    all_deps: BTreeSet<PathBuf>,
+    dep_hashes: BTreeMap<String, String>,
     check_cfgs: Vec<String>,
-    #[serde(default)]
-    envs: HashMap<String, String>,
+    envs: BTreeMap<String, String>,
+    lint: bool,
+    doc: bool,
 }
 
 impl CrateJob {
-    fn with_build_script(&mut self) -> Result<&mut Self> {
-        if let Some(path) = self.build_script_run.as_ref() {
+    pub fn new(job: schema::CrateJob) -> Self {
+        Self {
+            job,
+            metadata: BTreeMap::new(),
+            lib_path: BTreeSet::new(),
+            link_lib: Vec::new(),
+            dep_link_lib: BTreeSet::new(),
+            all_deps: BTreeSet::new(),
+            dep_hashes: BTreeMap::new(),
+            check_cfgs: Vec::new(),
+            envs: BTreeMap::new(),
+            lint: false,
+            doc: false,
+        }
+    }
+
+    pub fn job(&self) -> &schema::CrateJob {
+        &self.job
+    }
+
+    /// Outputs of the rust libraries the command reads, known after [`Self::command_common`].
+    pub fn dep_dirs(&self) -> BTreeSet<PathBuf> {
+        let mut dirs = self.all_deps.clone();
+        dirs.extend(self.job.common.deps.iter().map(|dep| dep.path.clone()));
+        dirs
+    }
+
+    /// Applies the `result.toml` of the build script run, if the job has one.
+    pub fn with_build_script(&mut self) -> Result<&mut Self> {
+        if let Some(path) = self.job.build_script_run.as_ref() {
             let result_path = path.join("result.toml");
             println!("reading build script output from {}", result_path.display());
             let mut build_script: BuildScriptResult = toml::from_str(
@@ -134,20 +168,27 @@ impl CrateJob {
             self.metadata = build_script.metadata;
             self.lib_path = build_script.lib_path;
             self.link_lib = build_script.link_lib;
-            self.common.rustc_flags.append(&mut build_script.flags);
-            self.common.cfgs.append(&mut build_script.cfgs);
-            self.common.link_args.append(&mut build_script.link_args);
-            match self.crate_type.as_str() {
+            self.job.common.rustc_flags.append(&mut build_script.flags);
+            self.job.common.cfgs.append(&mut build_script.cfgs);
+            self.job
+                .common
+                .link_args
+                .append(&mut build_script.link_args);
+            match self.job.crate_type.as_str() {
                 "cdylib" => self
+                    .job
                     .common
                     .link_args
                     .append(&mut build_script.link_args_cdylib),
                 "bin" => {
-                    self.common
+                    self.job
+                        .common
                         .link_args
                         .append(&mut build_script.link_args_bins);
-                    if let Some(specific) = build_script.link_args_bin.get_mut(&self.common.pname) {
-                        self.common.link_args.append(specific);
+                    if let Some(specific) =
+                        build_script.link_args_bin.get_mut(&self.job.common.pname)
+                    {
+                        self.job.common.link_args.append(specific);
                     }
                 }
                 _ => {}
@@ -167,7 +208,8 @@ impl CrateJob {
         Ok(self)
     }
 
-    fn lib_path_from_env(&mut self) -> &mut Self {
+    /// Adds every entry of `LD_LIBRARY_PATH` as a native search path.
+    pub fn lib_path_from_env(&mut self) -> &mut Self {
         if let Ok(var) = env::var("LD_LIBRARY_PATH") {
             for path in var.split(":") {
                 self.lib_path.insert(format!("native={}", path));
@@ -176,46 +218,125 @@ impl CrateJob {
         self
     }
 
-    fn command_common(&mut self, cargo: &Path, rustc: &Path, src: &Path) -> Result<Command> {
+    /// Configures the command for a lint run: lints are not capped, diagnostics are reported as
+    /// json and only metadata is emitted.
+    pub fn lint(&mut self) -> &mut Self {
+        self.lint = true;
+        self
+    }
+
+    /// Configures the command for `rustdoc`: nothing is emitted or linked and dependencies are
+    /// read from their metadata.
+    pub fn doc(&mut self) -> &mut Self {
+        self.doc = true;
+        self
+    }
+
+    /// Builds the rustc command shared by all crate types.
+    ///
+    /// Reads the `rust-lib.toml` of every dependency, so their outputs have to exist.
+    pub fn command_common(&mut self, cargo: &Path, rustc: &Path, src: &Path) -> Result<Command> {
         let mut command = Command::new(rustc);
-        self.common.add_metadata_env(cargo, src, &mut command)?;
-        let cores = if env::var("enableParallelBuilding")
-            .ok()
-            .map(|n| n == "1")
-            .unwrap_or(false)
-        {
-            env::var("NIX_BUILD_CORES").context("getting max numbe rof used cores")?
-        } else {
-            "1".to_string()
-        };
+        self.job.common.add_metadata_env(cargo, src, &mut command)?;
+        let cores =
+            if self.job.common.sysroot_crate && self.job.common.crate_name == "compiler_builtins" {
+                COMPILER_BUILTINS_CODEGEN_UNITS.to_string()
+            } else if self.job.common.reproducible {
+                REPRODUCIBLE_CODEGEN_UNITS.to_string()
+            } else if env::var("enableParallelBuilding")
+                .ok()
+                .map(|n| n == "1")
+                .unwrap_or(false)
+            {
+                env::var("NIX_BUILD_CORES").context("getting max numbe rof used cores")?
+            } else {
+                "1".to_string()
+            };
         command
             .arg("--crate-name")
-            .arg(&self.common.crate_name)
-            .arg("--edition=".to_string() + self.common.edition.as_str())
-            .arg(src.join(&self.entrypoint))
+            .arg(&self.job.common.crate_name)
+            .arg("--edition=".to_string() + self.job.common.edition.as_str())
+            .arg(src.join(&self.job.entrypoint))
             .arg("--check-cfg")
             .arg("cfg(docsrs,test)")
             .arg("-C")
-            .arg("embed-bitcode=no")
-            .arg("--cap-lints")
-            .arg("allow")
-            .arg("--target")
-            .arg(&self.common.target)
-            .arg("--emit")
-            .arg("link")
-            .arg("--crate-type")
-            .arg(&self.crate_type)
+            .arg("embed-bitcode=no");
+        sysroot::target_args(&mut command, &self.job.common.target)
             .arg("-C")
             .arg(format!("codegen-units={cores}"));
+        if let Some(sysroot) = &self.job.common.sysroot {
+            command.arg("--sysroot").arg(sysroot);
+        }
+        if self.job.common.sysroot_crate {
+            // the standard library is built with the unstable features it uses, like bootstrap
+            command
+                .env("RUSTC_BOOTSTRAP", "1")
+                .args(["-Z", "force-unstable-if-unmarked"]);
+        }
+        if let Some(panic) = self.panic() {
+            command.arg("-C").arg(format!("panic={panic}"));
+        }
+        command.args(self.codegen().rustc_args());
+        if self.coverage() {
+            command.args(["-C", "instrument-coverage"]);
+        }
+        if let Some(sanitizer) = self.sanitizer() {
+            // the standard library isn't built with the sanitizer, rustc refuses to link against
+            // it without the override, frame pointers give the reports stack traces
+            command
+                .env("RUSTC_BOOTSTRAP", "1")
+                .args(["-Z", &format!("sanitizer={sanitizer}")])
+                .args(["-C", "unsafe-allow-abi-mismatch=sanitizer"])
+                .args(["-C", "force-frame-pointers=yes"]);
+        }
+        if self.pgo() {
+            if self.job.common.profile_generate {
+                command.args(["-C", "profile-generate"]);
+            }
+            if let Some(profile) = &self.job.common.profile_use {
+                let mut arg = OsString::from("profile-use=");
+                arg.push(profile);
+                command.arg("-C").arg(arg);
+            }
+        }
+        if self.doc {
+            command.arg("--crate-version").arg(&self.job.common.version);
+            if self.job.crate_type == "proc-macro" {
+                command.arg("--extern").arg("proc_macro");
+            }
+        } else {
+            command.arg("--emit").arg(self.emit());
+        }
+        if !self.lint {
+            command.arg("--cap-lints").arg("allow");
+        }
+        if self.job.common.reproducible {
+            self.remap_paths(&mut command, src);
+        }
+        if !self.doc {
+            command.args([
+                "--error-format=json",
+                "--json=artifacts,diagnostic-rendered-ansi",
+            ]);
+        }
+        if self.job.crate_type == "test" {
+            command.arg("--test");
+        } else {
+            command.arg("--crate-type").arg(&self.job.crate_type);
+            if self.job.doctest {
+                command.arg("--test");
+            }
+        }
         command.envs(self.envs.iter());
-        command.args(&self.common.rustc_flags);
-        for arg in &self.common.cfgs {
+        command.args(&self.job.common.rustc_flags);
+        for arg in &self.job.common.cfgs {
             command.arg("--cfg").arg(arg);
         }
         for cfg in &self.check_cfgs {
             command.arg("--check-cfg").arg(cfg);
         }
-        for dep in &self.common.deps {
+        let mut outputs = HashMap::new();
+        for dep in &self.job.common.deps {
             let metadata_path = dep.path.join("rust-lib.toml");
             println!(
                 "reading dependency metadata from {}",
@@ -225,34 +346,95 @@ impl CrateJob {
                 &fs::read_to_string(&metadata_path).context("reading rust lib metadata")?,
             )
             .context("deserializing rust lib metadata")?;
+            let lib = match dep_metadata.rmeta {
+                Some(rmeta)
+                    if !self.links() || self.job.check || (self.doc && !self.job.doctest) =>
+                {
+                    rmeta
+                }
+                _ => dep_metadata.lib,
+            };
             command
                 .arg("--extern")
-                .arg(format!("{}={}", &dep.name, dep_metadata.lib.display()));
+                .arg(format!("{}={}", dep.name, lib.display()));
+            if let Some(other) = outputs.insert(dep_metadata.hash.clone(), &dep.path)
+                && other != &dep.path
+            {
+                bail!(
+                    "{} and {} are different builds with the same crate hash {}",
+                    other.display(),
+                    dep.path.display(),
+                    dep_metadata.hash
+                );
+            }
+            self.dep_hashes.insert(dep.name.clone(), dep_metadata.hash);
             self.lib_path.extend(dep_metadata.lib_path);
+            self.dep_link_lib.extend(dep_metadata.link_lib);
             self.all_deps.insert(dep.path.clone());
-            self.all_deps.extend(dep_metadata.deps);
+            // libraries built against metadata only record the metadata outputs of their deps
+            if !self.links() || self.job.link_deps.is_empty() {
+                self.all_deps.extend(dep_metadata.deps);
+            }
         }
-        if ["bin", "cdylib", "proc-macro"].contains(&self.crate_type.as_str()) {
-            for arg in &self.common.link_args {
+        if self.links() && self.job.crate_type != "test" {
+            for arg in &self.job.common.link_args {
                 command.arg("-C").arg(format!("link-arg={arg}"));
             }
         }
+        if self.links()
+            && self.job.crate_type == "cdylib"
+            && let Some(version_script) = &self.job.version_script
+        {
+            let mut arg = OsString::from("link-arg=-Wl,--version-script=");
+            arg.push(src.join(version_script));
+            command.arg("-C").arg(arg);
+        }
+        // rustc links the sanitizer runtime into executables without exporting it, unlike
+        // clang, so sanitized shared objects loaded at runtime couldn't resolve its symbols
+        if self.links_artifact()
+            && self.sanitizer().is_some()
+            && ["bin", "test"].contains(&self.job.crate_type.as_str())
+            && self.job.common.target.contains("-linux")
+        {
+            command.args(["-C", "link-arg=-Wl,--export-dynamic"]);
+        }
+        if self.links() {
+            self.all_deps.extend(self.job.link_deps.iter().cloned());
+        }
         for lib in &self.lib_path {
             command.arg("-L").arg(lib);
         }
-        for lib in &self.link_lib {
-            command.arg("-l").arg(lib);
+        if !self.doc {
+            for lib in &self.link_lib {
+                command.arg("-l").arg(lib);
+            }
+        }
+        // without shared libraries on the target there is nothing to find at runtime
+        if self.links_artifact()
+            && let Some((prefix, suffix)) =
+                artifact_affixes(command.get_program(), &self.job.common.target, "cdylib")?
+        {
+            let link_libs = self.link_lib.iter().chain(&self.dep_link_lib);
+            for dir in rpath::rpath(
+                &self.lib_path,
+                link_libs.map(String::as_str),
+                (&prefix, &suffix),
+            ) {
+                let mut arg = OsString::from("link-arg=-Wl,-rpath,");
+                arg.push(dir);
+                command.arg("-C").arg(arg);
+            }
         }
         for dep in &self.all_deps {
             command
                 .arg("-L")
                 .arg(format!("dependency={}", dep.display()));
         }
-        for feature in &self.common.features {
+        for feature in &self.job.common.features {
             command.arg("--cfg").arg(format!("feature=\"{feature}\""));
         }
         let mut check_features = "cfg(feature, values(".to_string();
-        for (index, feature) in self.common.all_features.iter().enumerate() {
+        for (index, feature) in self.job.common.all_features.iter().enumerate() {
             if index != 0 {
                 check_features.push_str(", ");
             }
@@ -262,35 +444,261 @@ impl CrateJob {
         }
         check_features.push_str("))");
         command.arg("--check-cfg").arg(check_features);
-        if self.common.debuginfo {
-            command.args(["-C", "debuginfo=2"]);
+        if self.job.common.debuginfo {
+            let level = self.job.common.debuginfo_level.as_deref().unwrap_or("2");
+            command.arg("-C").arg(format!("debuginfo={level}"));
+            if let Some(split) = self.split_debuginfo() {
+                command.arg("-C").arg(format!("split-debuginfo={split}"));
+            }
         } else {
             command.args(["-C", "strip=debuginfo"]);
         }
-        if self.common.optimize {
+        if self.job.common.optimize {
             command.args(["-C", "opt-level=3"]);
         }
         Ok(command)
     }
+    /// Remaps the absolute paths rustc embeds in debuginfo, panic messages and `file!()`.
+    ///
+    /// The crate source becomes `<pname>-<version>`, its `OUT_DIR` `<pname>-<version>/out` and
+    /// the nix build directory `/build`.
+    fn remap_paths(&self, command: &mut Command, src: &Path) {
+        let prefix = format!("{}-{}", self.job.common.pname, self.job.common.version);
+        let mut remap = |from: &Path, to: &str| {
+            command.arg(format!("--remap-path-prefix={}={to}", from.display()));
+        };
+        remap(src, &prefix);
+        if let Some(run) = &self.job.build_script_run {
+            remap(&run.join("output"), &format!("{prefix}/out"));
+        }
+        if let Some(build_top) = env::var_os("NIX_BUILD_TOP") {
+            remap(Path::new(&build_top), "/build");
+        }
+    }
+
+    /// The panic strategy of the job. Tests, doctests and proc macros always unwind, and build
+    /// scripts run on the host like them.
+    fn panic(&self) -> Option<&str> {
+        if self.job.doctest
+            || self.job.target_name == "build_script"
+            || ["proc-macro", "test"].contains(&self.job.crate_type.as_str())
+        {
+            return None;
+        }
+        self.job.common.panic.as_deref()
+    }
+
+    /// Whether the job is instrumented for coverage. Build scripts and proc macros only run
+    /// during the build, and doctests are compiled by rustdoc.
+    fn coverage(&self) -> bool {
+        self.job.common.coverage
+            && !self.job.doctest
+            && !self.doc
+            && self.job.target_name != "build_script"
+            && self.job.crate_type != "proc-macro"
+    }
+
+    /// The linker and code generation settings of the job. Build scripts and proc macros run
+    /// on the build machine, so they only use the linker.
+    fn codegen(&self) -> TargetCodegen {
+        let codegen = &self.job.common.codegen;
+        if self.job.target_name == "build_script" || self.job.crate_type == "proc-macro" {
+            codegen.linker_only()
+        } else {
+            codegen.clone()
+        }
+    }
+
+    /// The `-C split-debuginfo` mode of the job. Build scripts and proc macros are only run
+    /// during the build and doctests aren't kept, so they keep their debuginfo.
+    fn split_debuginfo(&self) -> Option<&str> {
+        if self.job.doctest
+            || self.doc
+            || self.job.target_name == "build_script"
+            || self.job.crate_type == "proc-macro"
+        {
+            return None;
+        }
+        self.job.common.split_debuginfo.as_deref()
+    }
+
+    /// Whether the job takes part in a profile-guided optimization. Build scripts and proc
+    /// macros don't run in the training, and the standard library is prebuilt.
+    fn pgo(&self) -> bool {
+        !self.job.common.sysroot_crate
+            && !self.job.doctest
+            && !self.doc
+            && self.job.target_name != "build_script"
+            && self.job.crate_type != "proc-macro"
+    }
+
+    /// The sanitizer of the job. Build scripts and proc macros are run by the uninstrumented
+    /// build, so they and their dependencies are built without it, like the standard library.
+    fn sanitizer(&self) -> Option<&str> {
+        if self.job.common.sysroot_crate
+            || self.job.target_name == "build_script"
+            || self.job.crate_type == "proc-macro"
+        {
+            return None;
+        }
+        self.job.common.sanitizer.as_deref()
+    }
+
+    /// Whether rustc links a bin, cdylib or test the dynamic loader has to resolve at runtime.
+    pub fn links_artifact(&self) -> bool {
+        !self.job.check
+            && !self.doc
+            && ["bin", "cdylib", "test"].contains(&self.job.crate_type.as_str())
+    }
+
+    /// Whether rustc links an executable or shared object, which needs the rlibs of all
+    /// dependencies instead of their metadata.
+    fn links(&self) -> bool {
+        self.job.doctest
+            || ["bin", "cdylib", "proc-macro", "test"].contains(&self.job.crate_type.as_str())
+    }
+
+    /// Hash passed as `-C metadata` and `extra-filename`.
+    ///
+    /// Covers everything that changes the symbols of the crate, including the hashes of all
+    /// dependencies, so differently configured builds of one crate can be linked together.
+    fn crate_hash(&self) -> String {
+        let common = &self.job.common;
+        let mut hash = Sha256::new();
+        let mut field = |value: &str| {
+            hash.update(value);
+            hash.update([0]);
+        };
+        field(&common.pname);
+        field(&common.version);
+        field(&common.target);
+        field(common.edition.as_str());
+        field(&self.job.crate_type);
+        field(if common.optimize { "optimize" } else { "" });
+        field(if common.debuginfo { "debuginfo" } else { "" });
+        if common.debuginfo {
+            if let Some(level) = &common.debuginfo_level {
+                field("debuginfo-level");
+                field(level);
+            }
+            if let Some(split) = self.split_debuginfo() {
+                field("split-debuginfo");
+                field(split);
+            }
+        }
+        field(if self.job.check { "check" } else { "" });
+        if common.reproducible {
+            field("reproducible");
+        }
+        if let Some(panic) = self.panic() {
+            field("panic");
+            field(panic);
+        }
+        if self.coverage() {
+            field("coverage");
+        }
+        if let Some(sanitizer) = self.sanitizer() {
+            field("sanitizer");
+            field(sanitizer);
+        }
+        let codegen = self.codegen();
+        if let Some(cpu) = &codegen.target_cpu {
+            field("target-cpu");
+            field(cpu);
+        }
+        for feature in &codegen.target_features {
+            field(feature);
+        }
+        if self.pgo() && common.profile_generate {
+            field("profile-generate");
+        }
+        if self.pgo() && common.profile_use.is_some() {
+            field("profile-use");
+        }
+        // a target has a single sysroot, its path would only keep the cache from being shared
+        if common.sysroot.is_some() {
+            field("sysroot");
+        }
+        for feature in &common.features {
+            field(feature);
+        }
+        for flag in &common.rustc_flags {
+            field(flag);
+        }
+        for cfg in &common.cfgs {
+            field(cfg);
+        }
+        for (name, dep) in &self.dep_hashes {
+            field(name);
+            field(dep);
+        }
+        hex::encode(&hash.finalize().as_slice()[0..8])
+    }
+
+    fn emit(&self) -> &'static str {
+        if self.job.check || self.lint {
+            "metadata"
+        } else if self.job.crate_type == "lib" {
+            "metadata,link"
+        } else {
+            "link"
+        }
+    }
+
+    /// Adds the crate type specific output arguments to `command` and prepares `out`.
+    pub fn output(self, command: &mut Command, out: &Path) -> Result<()> {
+        match self.job.crate_type.as_str() {
+            "bin" | "cdylib" | "test" if self.job.check => self.check(command, out),
+            "bin" => self.bin(command, out),
+            "lib" => self.lib(command, out),
+            "proc-macro" => self.proc_macro(command, out),
+            "cdylib" => self.cdylib(command, out),
+            "test" => self.test(command, out),
+            c => Err(eyre!("unknown crate type {c}")),
+        }
+    }
+
+    /// The file name rustc gives an artifact of `crate_type` called `name` on the job's target.
+    fn file_name(&self, command: &Command, crate_type: &str, name: &str) -> Result<String> {
+        let target = &self.job.common.target;
+        let (prefix, suffix) = artifact_affixes(command.get_program(), target, crate_type)?
+            .ok_or_else(|| eyre!("rustc does not support {crate_type} artifacts for {target}"))?;
+        Ok(format!("{prefix}{name}{suffix}"))
+    }
+
     fn bin(self, command: &mut Command, out: &Path) -> Result<()> {
         let bin = out.join("bin");
         fs::create_dir_all(&bin).context("creating output dir")?;
+        let file_name = self.file_name(command, "bin", &self.job.target_name)?;
         command
             .current_dir(bin)
             .arg("-o")
-            .arg(&self.target_name)
-            .env("CARGO_BIN_NAME", self.target_name);
+            .arg(file_name)
+            .env("CARGO_BIN_NAME", self.job.target_name);
+        Ok(())
+    }
+    fn check(self, command: &mut Command, out: &Path) -> Result<()> {
+        fs::create_dir_all(out).context("creating output dir")?;
+        let hash = self.crate_hash();
+        command
+            .arg("-C")
+            .arg(format!("metadata={hash}"))
+            .arg("-C")
+            .arg(format!("extra-filename=-{hash}"))
+            .arg("--out-dir")
+            .arg(out);
+        Ok(())
+    }
+    fn test(self, command: &mut Command, out: &Path) -> Result<()> {
+        let bin = out.join("bin");
+        fs::create_dir_all(&bin).context("creating output dir")?;
+        let file_name = self.file_name(command, "bin", &self.job.target_name)?;
+        command.current_dir(bin).arg("-o").arg(file_name);
         Ok(())
     }
     fn lib(self, command: &mut Command, out: &Path) -> Result<()> {
         fs::create_dir_all(out).context("creating output dir")?;
-        let mut hash = Sha256::new();
-        hash.update(&self.common.pname);
-        hash.update(&self.common.version);
-        for f in &self.common.features {
-            hash.update(f);
-        }
-        let hash = hex::encode(&hash.finalize().as_slice()[0..8]);
+        let hash = self.crate_hash();
         command
             .arg("-C")
             .arg(format!("metadata={hash}"))
@@ -298,19 +706,28 @@ impl CrateJob {
             .arg(format!("extra-filename=-{hash}"))
             .arg("--out-dir")
             .arg(out);
-        let lib_path = out.join(format!("lib{}-{hash}.rlib", &self.common.crate_name));
+        let rmeta_path = out.join(format!("lib{}-{hash}.rmeta", self.job.common.crate_name));
+        let lib_path = if self.job.metadata_only || self.job.check {
+            rmeta_path.clone()
+        } else {
+            out.join(format!("lib{}-{hash}.rlib", self.job.common.crate_name))
+        };
         let metadata_path = out.join("rust-lib.toml");
-        if !self.metadata.is_empty() && self.common.links.is_none() {
+        if !self.metadata.is_empty() && self.job.common.links.is_none() {
             bail!("metadata without links");
         }
         fs::write(
             metadata_path,
             toml::to_string_pretty(&RustLibMetadata {
+                schema_version: SchemaVersion::default(),
                 lib: lib_path,
+                rmeta: Some(rmeta_path),
+                hash,
                 deps: self.all_deps,
                 metadata: self.metadata,
                 lib_path: self.lib_path,
-                links: self.common.links,
+                links: self.job.common.links,
+                link_lib: self.link_lib.into_iter().chain(self.dep_link_lib).collect(),
             })
             .context("serializing library metadata")?,
         )
@@ -319,13 +736,7 @@ impl CrateJob {
     }
     fn proc_macro(self, command: &mut Command, out: &Path) -> Result<()> {
         fs::create_dir_all(out).context("creating output dir")?;
-        let mut hash = Sha256::new();
-        hash.update(&self.common.pname);
-        hash.update(&self.common.version);
-        for f in &self.common.features {
-            hash.update(f);
-        }
-        let hash = hex::encode(&hash.finalize().as_slice()[0..8]);
+        let hash = self.crate_hash();
         command
             .arg("-C")
             .arg(format!("metadata={hash}"))
@@ -335,56 +746,206 @@ impl CrateJob {
             .arg(out)
             .arg("--extern")
             .arg("proc_macro");
-        let lib_path = out.join(format!("lib{}-{hash}.so", &self.common.crate_name));
+        let file_name = self.file_name(
+            command,
+            "proc-macro",
+            &format!("{}-{hash}", self.job.common.crate_name),
+        )?;
+        let lib_path = out.join(file_name);
         let metadata_path = out.join("rust-lib.toml");
         fs::write(
             metadata_path,
             toml::to_string_pretty(&RustLibMetadata {
+                schema_version: SchemaVersion::default(),
                 lib: lib_path,
-                deps: HashSet::new(),
+                rmeta: None,
+                hash,
+                deps: BTreeSet::new(),
                 metadata: self.metadata,
-                lib_path: HashSet::new(),
+                lib_path: BTreeSet::new(),
                 links: None,
+                link_lib: BTreeSet::new(),
             })
             .context("serializing library metadata")?,
         )
         .context("writing library metadata")?;
         Ok(())
     }
+    /// Writes `lib/pkgconfig/<name>.pc`, so C consumers can link the cdylib in `out`. No headers
+    /// are installed, so there are no `Cflags`.
+    fn pkg_config(&self, out: &Path) -> Result<()> {
+        let common = &self.job.common;
+        let dir = out.join("lib/pkgconfig");
+        fs::create_dir_all(&dir).context("creating pkg-config dir")?;
+        let mut pc = format!("prefix={}\nlibdir=${{prefix}}/lib\n\n", out.display());
+        pc += &format!("Name: {}\n", common.pname);
+        pc += &format!(
+            "Description: {}\n",
+            s(&common.description).replace('\n', " ")
+        );
+        pc += &format!("Version: {}\n", common.version);
+        if let Some(homepage) = &common.homepage {
+            pc += &format!("URL: {homepage}\n");
+        }
+        pc += &format!("Libs: -L${{libdir}} -l{}\n", self.job.target_name);
+        fs::write(dir.join(format!("{}.pc", self.job.target_name)), pc)
+            .context("writing pkg-config file")
+    }
     fn cdylib(self, command: &mut Command, out: &Path) -> Result<()> {
         let lib_dir = out.join("lib");
         fs::create_dir_all(&lib_dir).context("creating output dir")?;
-        let version = cargo_metadata::semver::Version::parse(&self.common.version)
+        let version = cargo_metadata::semver::Version::parse(&self.job.common.version)
             .context("parsing crate version")?;
         command.current_dir(&lib_dir);
-        let lib_name = format!("lib{}.so", self.target_name);
+        let lib_name = self.file_name(command, "cdylib", &self.job.target_name)?;
         let lib_path = lib_dir.join(&lib_name);
-        let lib_major_path = lib_dir.join(format!("{}.{}", &lib_name, version.major));
+        // only ELF shared objects are installed with version suffixes and symlinks to them, and
+        // a pkg-config file to find them
+        if !lib_name.ends_with(".so") {
+            command.arg("-o").arg(&lib_path);
+            return Ok(());
+        }
+        self.pkg_config(out)?;
+        let lib_major_path = lib_dir.join(format!("{}.{}", lib_name, version.major));
         let lib_full_path = lib_dir.join(format!(
             "{}.{}.{}.{}",
-            &lib_name, version.major, version.minor, version.patch
+            lib_name, version.major, version.minor, version.patch
         ));
-        command.arg("-o").arg(&lib_full_path);
+        command
+            .arg("-o")
+            .arg(&lib_full_path)
+            .arg("-C")
+            .arg(format!("link-arg=-Wl,-soname,{lib_name}.{}", version.major));
         symlink(&lib_full_path, &lib_path).context("creating symlink without version")?;
         symlink(&lib_full_path, &lib_major_path).context("creating symlink with major version")?;
         Ok(())
     }
 }
 
+/// Prefix and suffix of the file names rustc gives `crate_type` artifacts on `target`, like
+/// `lib` and `.so` for a linux cdylib or just `.exe` for a windows bin. `None` if the target
+/// doesn't support the crate type, like cdylibs on static musl.
+fn artifact_affixes(
+    rustc: &OsStr,
+    target: &str,
+    crate_type: &str,
+) -> Result<Option<(String, String)>> {
+    const PLACEHOLDER: &str = "nix_rust_build_artifact";
+    let mut command = Command::new(rustc);
+    command
+        .args(["--print", "file-names", "--crate-type", crate_type])
+        .args(["--crate-name", PLACEHOLDER]);
+    let output = sysroot::target_args(&mut command, target)
+        .arg("-")
+        .stdin(Stdio::null())
+        .output()
+        .context("getting artifact file names from rustc")?;
+    if !output.status.success() {
+        bail!(
+            "rustc failed to print the file names of {crate_type} artifacts for {target}: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let file_names = String::from_utf8(output.stdout).context("outputs includes non utf-8")?;
+    Ok(file_names
+        .lines()
+        .next()
+        .and_then(|file_name| file_name.split_once(PLACEHOLDER))
+        .map(|(prefix, suffix)| (prefix.to_string(), suffix.to_string())))
+}
+
+/// Runs rustc, rendering its json diagnostics and recording all messages in
+/// `out/nix-support/diagnostics.json`.
+///
+/// With `metadata_only` rustc is stopped once it reported the `.rmeta` and everything else is
+/// removed from `out`. `--emit metadata` on its own leaves out the MIR dependents need for codegen
+/// and the json flags are part of the crate hash, so both runs have to use the same invocation.
+fn run_rustc(mut command: Command, out: &Path, metadata_only: bool) -> Result<()> {
+    let output = diagnostics::run_json(&mut command, metadata_only)?;
+    let succeeded = if metadata_only {
+        output.metadata
+    } else {
+        output.status.success()
+    };
+    if metadata_only && succeeded {
+        for entry in fs::read_dir(out).context("reading output dir")? {
+            let path = entry.context("reading output dir entry")?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("rmeta" | "toml") => {}
+                // holds the dep-info, written before the metadata
+                _ if path.ends_with("nix-support") => {}
+                _ if path.is_dir() => {
+                    fs::remove_dir_all(&path).context("removing partial output")?
+                }
+                _ => fs::remove_file(&path).context("removing partial output")?,
+            }
+        }
+    }
+    diagnostics::write(
+        &out.join("nix-support/diagnostics.json"),
+        &output.messages,
+        succeeded,
+    )?;
+    if !succeeded && metadata_only {
+        bail!(
+            "rustc exited with {} before writing metadata",
+            output.status
+        );
+    }
+    if !succeeded {
+        bail!("rustc failed with {}", output.status);
+    }
+    Ok(())
+}
+
 pub fn run(src: PathBuf, cargo: PathBuf, rustc: PathBuf, job: PathBuf, out: PathBuf) -> Result<()> {
-    let mut job: CrateJob = serde_json::from_slice(&fs::read(job).context("reading job")?)
-        .context("deserializing job")?;
+    let mut job = CrateJob::new(
+        serde_json::from_slice(&fs::read(job).context("reading job")?)
+            .context("deserializing job")?,
+    );
     let mut command = job
         .with_build_script()?
         .lib_path_from_env()
         .command_common(&cargo, &rustc, &src)?;
-    match job.crate_type.as_str() {
-        "bin" => job.bin(&mut command, &out),
-        "lib" => job.lib(&mut command, &out),
-        "proc-macro" => job.proc_macro(&mut command, &out),
-        "cdylib" => job.cdylib(&mut command, &out),
-        c => Err(eyre!("unknown crate type {c}")),
-    }?;
-    println!("executing {command:?}");
-    Err(command.exec()).context("executing rustc")
+    let metadata_only = job.job().metadata_only;
+    // rustc is stopped early for metadata, which leaves nothing worth caching
+    let cache = Cache::from_env().filter(|_| !metadata_only);
+    let dep_dirs = job.dep_dirs();
+    let build_script = job.job().build_script_run.clone();
+    // the cache key replaces every `--emit` value, so this has to be a separate argument
+    let mut emit_dep_info = OsString::from("dep-info=");
+    emit_dep_info.push(dep_info::raw_path(&out));
+    fs::create_dir_all(out.join("nix-support")).context("creating nix-support dir")?;
+    command.arg("--emit").arg(emit_dep_info);
+    let dep_info = DepInfo::new(&job.job().common);
+    let links_artifact = job.links_artifact();
+    job.output(&mut command, &out)?;
+    let cwd = command
+        .get_current_dir()
+        .unwrap_or(Path::new(""))
+        .to_path_buf();
+    let finish = || {
+        dep_info::write_compile(dep_info, &src, build_script.as_deref(), &cwd, &out)?;
+        if links_artifact {
+            rpath::check_outputs(&out)?;
+        }
+        Ok(())
+    };
+    let Some(cache) = cache else {
+        run_rustc(command, &out, metadata_only)?;
+        return finish();
+    };
+    let key = cache.key(&command, &out, &dep_dirs, build_script.as_deref())?;
+    if let Some(key) = &key
+        && cache.restore(key, &out)?
+    {
+        return Ok(());
+    }
+    let before = cache::files_below(&out)?;
+    run_rustc(command, &out, metadata_only)?;
+    finish()?;
+    if let Some(key) = &key {
+        cache.store(key, &out, &before)?;
+    }
+    Ok(())
 }
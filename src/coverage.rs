@@ -0,0 +1,117 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use color_eyre::eyre::{Context, Result, bail};
+
+use crate::test::{run_binaries, test_binaries};
+
+/// Runs `llvm_cov` `subcommand` over `binaries`, restricted to the sources in `package_dir`,
+/// and writes its stdout to `path`.
+fn llvm_cov(
+    llvm_cov: &Path,
+    subcommand: &str,
+    args: &[&str],
+    profdata: &Path,
+    binaries: &[PathBuf],
+    package_dir: &Path,
+    path: &Path,
+) -> Result<()> {
+    let mut command = Command::new(llvm_cov);
+    command
+        .arg(subcommand)
+        .args(args)
+        .arg("-instr-profile")
+        .arg(profdata);
+    for (index, binary) in binaries.iter().enumerate() {
+        if index != 0 {
+            command.arg("-object");
+        }
+        command.arg(binary);
+    }
+    command.arg(package_dir).stderr(Stdio::inherit());
+    println!("executing {command:?}");
+    let output = command.output().context("executing llvm-cov")?;
+    if !output.status.success() {
+        bail!("llvm-cov {subcommand} failed with {}", output.status);
+    }
+    fs::write(path, output.stdout).with_context(|| format!("writing {}", path.display()))
+}
+
+/// Merges the `.profraw` files in `profraw` into `profdata` with `llvm_profdata`.
+pub fn merge_profiles(llvm_profdata: &Path, profraw: &Path, profdata: &Path) -> Result<()> {
+    let mut command = Command::new(llvm_profdata);
+    command.args(["merge", "-sparse"]);
+    for entry in fs::read_dir(profraw).context("reading profraw dir")? {
+        command.arg(entry.context("reading profraw dir entry")?.path());
+    }
+    command.arg("-o").arg(profdata);
+    println!("executing {command:?}");
+    let status = command.status().context("executing llvm-profdata")?;
+    if !status.success() {
+        bail!("llvm-profdata failed with {status}");
+    }
+    Ok(())
+}
+
+/// Runs the instrumented test binaries of a workspace member and reports their coverage.
+///
+/// `tests` are the outputs of its `--test` compile jobs built with
+/// [`crate::schema::CrateJobCommon::coverage`]. Every binary runs in `package_dir`, like
+/// `cargo test` runs them, with `LLVM_PROFILE_FILE` pointing into `out/profraw`. The profiles
+/// are merged with `llvm_profdata` into `out/coverage.profdata`, from which `llvm_cov` writes
+/// `out/lcov.info` and the summary table `out/summary.txt`. Both only cover the sources in
+/// `package_dir`, dependencies aren't instrumented and other workspace members have their own
+/// reports. Failing tests fail the run, after the reports are written.
+pub fn run(
+    llvm_profdata: PathBuf,
+    llvm_cov_path: PathBuf,
+    package_dir: PathBuf,
+    out: PathBuf,
+    tests: Vec<PathBuf>,
+) -> Result<()> {
+    let binaries = test_binaries(&tests)?;
+    let profraw = out.join("profraw");
+    fs::create_dir_all(&profraw).context("creating profraw dir")?;
+    let results = run_binaries(&binaries, &package_dir, |index, command| {
+        command.env(
+            "LLVM_PROFILE_FILE",
+            profraw.join(format!("{index}-%p-%m.profraw")),
+        );
+    })?;
+
+    let profdata = out.join("coverage.profdata");
+    merge_profiles(&llvm_profdata, &profraw, &profdata)?;
+    println!("writing lcov.info");
+    llvm_cov(
+        &llvm_cov_path,
+        "export",
+        &["-format=lcov"],
+        &profdata,
+        &binaries,
+        &package_dir,
+        &out.join("lcov.info"),
+    )?;
+    println!("writing summary.txt");
+    let summary = out.join("summary.txt");
+    llvm_cov(
+        &llvm_cov_path,
+        "report",
+        &[],
+        &profdata,
+        &binaries,
+        &package_dir,
+        &summary,
+    )?;
+    print!(
+        "{}",
+        fs::read_to_string(&summary).context("reading coverage summary")?
+    );
+    let failed = results.iter().filter(|success| !**success).count();
+    if failed != 0 {
+        bail!("{failed} of {} test binaries failed", binaries.len());
+    }
+    Ok(())
+}
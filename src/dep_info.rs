@@ -0,0 +1,200 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{Context, Result};
+
+use crate::{
+    resolve::normalize,
+    schema::{CrateJobCommon, DepInfo, SchemaVersion},
+};
+
+/// Parses the first rule of a makefile style dep-info file into its inputs and the `env-dep`
+/// comments, which carry the variables read with `env!` together with their values.
+pub(crate) fn parse(content: &str) -> (Vec<PathBuf>, Vec<&str>) {
+    let mut inputs = Vec::new();
+    let mut env_deps = Vec::new();
+    for line in content.lines() {
+        if let Some(env_dep) = line.strip_prefix("# env-dep:") {
+            env_deps.push(env_dep);
+        } else if inputs.is_empty()
+            && !line.starts_with('#')
+            && let Some((_, deps)) = line.split_once(": ")
+        {
+            let mut current = String::new();
+            let mut chars = deps.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => current.extend(chars.next()),
+                    ' ' => {
+                        if !current.is_empty() {
+                            inputs.push(PathBuf::from(std::mem::take(&mut current)));
+                        }
+                    }
+                    c => current.push(c),
+                }
+            }
+            if !current.is_empty() {
+                inputs.push(PathBuf::from(current));
+            }
+        }
+    }
+    (inputs, env_deps)
+}
+
+impl DepInfo {
+    /// An empty record for the package of `common`.
+    pub fn new(common: &CrateJobCommon) -> Self {
+        Self {
+            schema_version: SchemaVersion::default(),
+            pname: common.pname.clone(),
+            version: common.version.clone(),
+            manifest_path: common.manifest_path.clone(),
+            sources: BTreeSet::new(),
+            out_dir: BTreeSet::new(),
+            external: BTreeSet::new(),
+            env: BTreeSet::new(),
+        }
+    }
+
+    /// Sorts `path`, absolute or relative to `cwd`, into the sources below `src`, the files
+    /// below the `OUT_DIR` of the build script run and everything else.
+    fn add(&mut self, src: &Path, out_dir: Option<&Path>, cwd: &Path, path: &Path) {
+        let path = normalize(&cwd.join(path));
+        if let Some(out_dir) = out_dir
+            && let Ok(relative) = path.strip_prefix(out_dir)
+        {
+            self.out_dir.insert(relative.to_path_buf());
+        } else if let Ok(relative) = path.strip_prefix(src) {
+            self.sources.insert(relative.to_path_buf());
+        } else {
+            self.external.insert(path);
+        }
+    }
+
+    fn write(&self, out: &Path) -> Result<()> {
+        let path = out.join("nix-support/dep-info.json");
+        fs::create_dir_all(out.join("nix-support")).context("creating nix-support dir")?;
+        fs::write(
+            path,
+            serde_json::to_vec_pretty(self).context("serializing dep-info")?,
+        )
+        .context("writing dep-info")
+    }
+}
+
+/// Where [`crate::compile`] asks rustc for the dep-info of a crate.
+pub fn raw_path(out: &Path) -> PathBuf {
+    out.join("nix-support/dep-info.d")
+}
+
+/// Adds the raw dep-info rustc wrote to [`raw_path`] to `dep_info` and writes it to
+/// `out/nix-support/dep-info.json`.
+///
+/// Paths in the raw file are relative to `cwd`, the directory rustc ran in.
+pub fn write_compile(
+    mut dep_info: DepInfo,
+    src: &Path,
+    build_script_run: Option<&Path>,
+    cwd: &Path,
+    out: &Path,
+) -> Result<()> {
+    let raw = raw_path(out);
+    let content = fs::read_to_string(&raw).context("reading dep-info")?;
+    fs::remove_file(&raw).context("removing dep-info")?;
+    let (inputs, env_deps) = parse(&content);
+    let out_dir = build_script_run.map(|run| run.join("output"));
+    for input in inputs {
+        dep_info.add(src, out_dir.as_deref(), cwd, &input);
+    }
+    dep_info.env.extend(
+        env_deps
+            .iter()
+            .map(|env_dep| env_dep.split_once('=').map_or(*env_dep, |(name, _)| name))
+            .map(String::from),
+    );
+    dep_info.write(out)
+}
+
+/// Records the `rerun-if-changed` paths and `rerun-if-env-changed` variables of a build script
+/// run in `out/nix-support/dep-info.json`.
+///
+/// Like cargo, a script without `rerun-if-changed` depends on its whole package.
+pub fn write_build_script(
+    common: &CrateJobCommon,
+    src: &Path,
+    rerun_if_changed: &[String],
+    rerun_if_env_changed: &[String],
+    out: &Path,
+) -> Result<()> {
+    let manifest_dir = src
+        .join(&common.manifest_path)
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    let mut dep_info = DepInfo::new(common);
+    if rerun_if_changed.is_empty() {
+        dep_info.add(src, None, &manifest_dir, Path::new(""));
+    }
+    for path in rerun_if_changed {
+        dep_info.add(src, None, &manifest_dir, Path::new(path));
+    }
+    dep_info.env.extend(rerun_if_env_changed.iter().cloned());
+    dep_info.write(out)
+}
+
+/// Collects every `dep-info.json` below `input` or `input` itself.
+fn collect(input: &Path, found: &mut Vec<DepInfo>) -> Result<()> {
+    let metadata = fs::metadata(input).with_context(|| format!("reading {}", input.display()))?;
+    if metadata.is_dir() {
+        let mut entries = fs::read_dir(input)
+            .with_context(|| format!("reading {}", input.display()))?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .context("reading directory entry")?;
+        entries.sort();
+        for entry in entries {
+            if entry.is_dir() || entry.file_name().is_some_and(|n| n == "dep-info.json") {
+                collect(&entry, found)?;
+            }
+        }
+        return Ok(());
+    }
+    found.push(
+        serde_json::from_slice(&fs::read(input).context("reading dep-info")?)
+            .with_context(|| format!("deserializing {}", input.display()))?,
+    );
+    Ok(())
+}
+
+/// Writes the source files the build outputs in `inputs` read, keyed by `pname-version` like
+/// the vendored sources and relative to the source of the package, to `out`.
+///
+/// The manifest is always part of the list, files generated into `OUT_DIR` or read from outside
+/// the source are left out.
+pub fn run(out: PathBuf, inputs: Vec<PathBuf>) -> Result<()> {
+    let mut found = Vec::new();
+    for input in &inputs {
+        collect(input, &mut found)?;
+    }
+    let mut packages: BTreeMap<String, BTreeSet<PathBuf>> = BTreeMap::new();
+    for dep_info in &found {
+        let files = packages
+            .entry(format!("{}-{}", dep_info.pname, dep_info.version))
+            .or_default();
+        files.insert(dep_info.manifest_path.clone());
+        files.extend(dep_info.sources.iter().cloned());
+    }
+    println!(
+        "collected the sources of {} packages from {} dep-info files",
+        packages.len(),
+        found.len()
+    );
+    fs::write(
+        &out,
+        serde_json::to_vec_pretty(&packages).context("serializing source files")?,
+    )
+    .context("writing source files")
+}
@@ -0,0 +1,269 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+};
+
+use color_eyre::eyre::{Context, Result, eyre};
+use serde_json::{Value, json};
+
+/// Prefix of the log line carrying the diagnostics of a failed build, which has no output to
+/// write them to.
+pub const FAILURE_LOG_PREFIX: &str = "nix-rust-build diagnostics: ";
+
+/// Result of a compiler invocation with json output.
+pub(crate) struct JsonOutput {
+    pub status: ExitStatus,
+    /// Every json message, diagnostics as well as artifact notifications.
+    pub messages: Vec<Value>,
+    /// Whether the compiler reported the `.rmeta` of the crate.
+    pub metadata: bool,
+}
+
+/// Runs `command`, which has to use `--error-format=json`, rendering its diagnostics on stderr.
+///
+/// With `stop_at_metadata` the compiler is killed as soon as it reported the `.rmeta`.
+pub(crate) fn run_json(command: &mut Command, stop_at_metadata: bool) -> Result<JsonOutput> {
+    let program = Path::new(command.get_program())
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    println!("executing {command:?}");
+    let mut child = command
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("executing {program}"))?;
+    let stderr = child
+        .stderr
+        .take()
+        .ok_or_else(|| eyre!("{program} has no stderr"))?;
+    let mut messages = Vec::new();
+    let mut metadata = false;
+    for line in BufReader::new(stderr).lines() {
+        let line = line.with_context(|| format!("reading {program} output"))?;
+        let Ok(message) = serde_json::from_str::<Value>(&line) else {
+            eprintln!("{line}");
+            continue;
+        };
+        if let Some(rendered) = message["rendered"].as_str() {
+            eprint!("{rendered}");
+        }
+        let is_metadata = message["emit"] == "metadata";
+        messages.push(message);
+        if is_metadata {
+            metadata = true;
+            if stop_at_metadata {
+                println!("metadata written, stopping {program}");
+                child
+                    .kill()
+                    .with_context(|| format!("stopping {program}"))?;
+                break;
+            }
+        }
+    }
+    let status = child
+        .wait()
+        .with_context(|| format!("waiting for {program}"))?;
+    Ok(JsonOutput {
+        status,
+        messages,
+        metadata,
+    })
+}
+
+/// Writes `messages` to `path`. Unless the build `succeeded` they are also printed to the log,
+/// as the output is discarded with the failed build.
+pub(crate) fn write(path: &Path, messages: &[Value], succeeded: bool) -> Result<()> {
+    if !succeeded {
+        eprintln!(
+            "{FAILURE_LOG_PREFIX}{}",
+            serde_json::to_string(messages).context("serializing diagnostics")?
+        );
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context("creating diagnostics dir")?;
+    }
+    println!("writing {}", path.display());
+    fs::write(
+        path,
+        serde_json::to_vec_pretty(messages).context("serializing diagnostics")?,
+    )
+    .context("writing diagnostics")
+}
+
+/// Collects the diagnostics from `input`: every `diagnostics.json` below a directory, a
+/// `diagnostics.json` itself or the failure lines of a build log.
+fn collect(input: &Path, found: &mut Vec<(PathBuf, Vec<Value>)>) -> Result<()> {
+    let metadata = fs::metadata(input).with_context(|| format!("reading {}", input.display()))?;
+    if metadata.is_dir() {
+        let mut entries = fs::read_dir(input)
+            .with_context(|| format!("reading {}", input.display()))?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .context("reading directory entry")?;
+        entries.sort();
+        for entry in entries {
+            if entry.is_dir() || entry.file_name().is_some_and(|n| n == "diagnostics.json") {
+                collect(&entry, found)?;
+            }
+        }
+        return Ok(());
+    }
+    let content =
+        fs::read_to_string(input).with_context(|| format!("reading {}", input.display()))?;
+    if let Ok(messages) = serde_json::from_str::<Vec<Value>>(&content) {
+        found.push((input.to_path_buf(), messages));
+        return Ok(());
+    }
+    let mut messages = Vec::new();
+    for line in content.lines() {
+        if let Some((_, failure)) = line.split_once(FAILURE_LOG_PREFIX) {
+            messages.extend(
+                serde_json::from_str::<Vec<Value>>(failure)
+                    .with_context(|| format!("parsing diagnostics in {}", input.display()))?,
+            );
+        }
+    }
+    found.push((input.to_path_buf(), messages));
+    Ok(())
+}
+
+/// Whether `message` is a diagnostic pointing at source code.
+pub(crate) fn is_diagnostic(message: &Value) -> bool {
+    // summaries like "aborting due to 2 previous errors" carry no spans
+    message["$message_type"] == "diagnostic"
+        && message["spans"].as_array().is_some_and(|s| !s.is_empty())
+}
+
+fn sarif_level(level: &str) -> &'static str {
+    match level {
+        "error" | "error: internal compiler error" => "error",
+        "warning" => "warning",
+        "note" | "help" => "note",
+        _ => "none",
+    }
+}
+
+/// Converts the diagnostics to a SARIF 2.1.0 log with one result per primary span.
+fn sarif(diagnostics: &[&Value], strip_prefix: Option<&Path>) -> Value {
+    let mut rules = BTreeMap::new();
+    let mut results = Vec::new();
+    let mut seen = BTreeSet::new();
+    for diagnostic in diagnostics {
+        let rule = diagnostic["code"]["code"].as_str().unwrap_or("rustc");
+        let spans = diagnostic["spans"].as_array().into_iter().flatten();
+        let locations: Vec<Value> = spans
+            .filter(|span| span["is_primary"] == true)
+            .map(|span| {
+                let file = span["file_name"].as_str().unwrap_or_default();
+                let uri = strip_prefix
+                    .and_then(|prefix| Path::new(file).strip_prefix(prefix).ok())
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_else(|| file.to_string());
+                json!({
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": uri },
+                        "region": {
+                            "startLine": span["line_start"],
+                            "startColumn": span["column_start"],
+                            "endLine": span["line_end"],
+                            "endColumn": span["column_end"],
+                        },
+                    },
+                })
+            })
+            .collect();
+        let result = json!({
+            "ruleId": rule,
+            "level": sarif_level(diagnostic["level"].as_str().unwrap_or_default()),
+            "message": { "text": diagnostic["message"] },
+            "locations": locations,
+        });
+        // the same crate is often built more than once, e.g. for its metadata and its rlib
+        if !seen.insert(result.to_string()) {
+            continue;
+        }
+        rules
+            .entry(rule.to_string())
+            .or_insert_with(|| json!({ "id": rule }));
+        results.push(result);
+    }
+    json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "rustc",
+                    "informationUri": "https://doc.rust-lang.org/rustc/",
+                    "rules": rules.into_values().collect::<Vec<_>>(),
+                },
+            },
+            "results": results,
+        }],
+    })
+}
+
+/// Aggregates the diagnostics of build outputs or failed build logs into the report `out`.
+///
+/// The report lists the diagnostics of every input with their error and warning counts.
+/// With `sarif`, the diagnostics are also written as a SARIF log, with file names made
+/// relative to `strip_prefix`.
+pub fn run(
+    out: PathBuf,
+    sarif_out: Option<PathBuf>,
+    strip_prefix: Option<PathBuf>,
+    inputs: Vec<PathBuf>,
+) -> Result<()> {
+    let mut found = Vec::new();
+    for input in &inputs {
+        collect(input, &mut found)?;
+    }
+    let count = |diagnostics: &[&Value], level: &str| {
+        diagnostics
+            .iter()
+            .filter(|d| sarif_level(d["level"].as_str().unwrap_or_default()) == level)
+            .count()
+    };
+    let mut all = Vec::new();
+    let mut sources = Vec::new();
+    for (path, messages) in &found {
+        let diagnostics: Vec<&Value> = messages.iter().filter(|m| is_diagnostic(m)).collect();
+        if diagnostics.is_empty() {
+            continue;
+        }
+        sources.push(json!({
+            "path": path,
+            "errors": count(&diagnostics, "error"),
+            "warnings": count(&diagnostics, "warning"),
+            "diagnostics": diagnostics,
+        }));
+        all.extend(diagnostics);
+    }
+    let errors = count(&all, "error");
+    let warnings = count(&all, "warning");
+    println!(
+        "{errors} errors and {warnings} warnings in {} of {} outputs",
+        sources.len(),
+        found.len()
+    );
+    let report = json!({ "errors": errors, "warnings": warnings, "sources": sources });
+    fs::write(
+        &out,
+        serde_json::to_vec_pretty(&report).context("serializing report")?,
+    )
+    .context("writing report")?;
+    if let Some(sarif_out) = sarif_out {
+        println!("writing {}", sarif_out.display());
+        fs::write(
+            sarif_out,
+            serde_json::to_vec_pretty(&sarif(&all, strip_prefix.as_deref()))
+                .context("serializing sarif log")?,
+        )
+        .context("writing sarif log")?;
+    }
+    Ok(())
+}
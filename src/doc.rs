@@ -0,0 +1,42 @@
+use std::{fs, path::PathBuf};
+
+use color_eyre::eyre::{Context, Result, bail};
+
+use crate::{compile::CrateJob, schema::DocJob};
+
+/// Documents every crate of `jobs` into the single doc tree `out`.
+///
+/// The commands are built by [`CrateJob::command_common`], so rustdoc sees the same cfgs,
+/// features and externs as the real build. rustdoc merges `crates.js` and the search index of
+/// an existing tree, so the crates are documented one after the other into the same directory.
+/// A directory is created for every crate up front, which makes rustdoc link to the local docs
+/// of all crates in `jobs` instead of leaving their items unlinked.
+pub fn run(cargo: PathBuf, rustdoc: PathBuf, jobs: PathBuf, out: PathBuf) -> Result<()> {
+    let jobs: Vec<DocJob> = serde_json::from_slice(&fs::read(jobs).context("reading doc jobs")?)
+        .context("deserializing doc jobs")?;
+    fs::create_dir_all(&out).context("creating output dir")?;
+    // rustdoc runs in the manifest dir of each crate
+    let out = out.canonicalize().context("resolving output dir")?;
+    for job in &jobs {
+        fs::create_dir_all(out.join(&job.job.common.crate_name))
+            .context("creating crate doc dir")?;
+    }
+    for DocJob { src, job } in jobs {
+        let mut job = CrateJob::new(job);
+        let mut command = job
+            .with_build_script()?
+            .lib_path_from_env()
+            .doc()
+            .command_common(&cargo, &rustdoc, &src)?;
+        command.arg("--out-dir").arg(&out);
+        println!("executing {command:?}");
+        let status = command.status().context("executing rustdoc")?;
+        if !status.success() {
+            bail!(
+                "rustdoc failed for {} with {status}",
+                job.job().common.crate_name
+            );
+        }
+    }
+    Ok(())
+}
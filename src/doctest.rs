@@ -0,0 +1,71 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+    process::Stdio,
+};
+
+use color_eyre::eyre::{Context, OptionExt, Result, bail};
+use serde_json::json;
+
+use crate::compile::CrateJob;
+
+/// Runs the doctests of `job` with `rustdoc --test` and writes their outcome to
+/// `out/doctests.json`.
+///
+/// The command is built by [`CrateJob::command_common`], so the doctests see the same cfgs,
+/// features and `CARGO_*` environment as the library and link against the rlibs of its
+/// dependencies, dev-dependencies and the library itself. The outcomes are parsed from the
+/// `test <name> ... <outcome>` lines libtest prints, one `{ "name", "outcome" }` object per
+/// doctest.
+pub fn run(
+    src: PathBuf,
+    cargo: PathBuf,
+    rustdoc: PathBuf,
+    job: PathBuf,
+    out: PathBuf,
+) -> Result<()> {
+    let mut job = CrateJob::new(
+        serde_json::from_slice(&fs::read(job).context("reading job")?)
+            .context("deserializing job")?,
+    );
+    if !job.job().doctest {
+        bail!("{} is not a doctest job", job.job().common.crate_name);
+    }
+    let mut command = job
+        .with_build_script()?
+        .lib_path_from_env()
+        .doc()
+        .command_common(&cargo, &rustdoc, &src)?;
+    fs::create_dir_all(&out).context("creating output dir")?;
+    command
+        .env("CARGO_PRIMARY_PACKAGE", "1")
+        .stdout(Stdio::piped());
+    println!("executing {command:?}");
+    let mut child = command.spawn().context("executing rustdoc")?;
+    let stdout = child.stdout.take().ok_or_eyre("rustdoc has no stdout")?;
+    let mut outcomes = Vec::new();
+    for line in BufReader::new(stdout).lines() {
+        let line = line.context("reading rustdoc output")?;
+        println!("{line}");
+        if let Some((name, outcome)) = line
+            .strip_prefix("test ")
+            .and_then(|line| line.rsplit_once(" ... "))
+        {
+            // ignored tests may carry a reason like `ignored, requires network`
+            let outcome = outcome.split(',').next().unwrap_or_default();
+            outcomes.push(json!({ "name": name, "outcome": outcome.to_lowercase() }));
+        }
+    }
+    let status = child.wait().context("waiting for rustdoc")?;
+    println!("writing doctests.json");
+    fs::write(
+        out.join("doctests.json"),
+        serde_json::to_vec_pretty(&outcomes).context("serializing doctest outcomes")?,
+    )
+    .context("writing doctest outcomes")?;
+    if !status.success() {
+        bail!("rustdoc failed with {status}");
+    }
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{Context, OptionExt, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Serialize)]
+struct Hashes {
+    package: String,
+    files: BTreeMap<String, String>,
+}
+
+fn copy_files(
+    dir: &Path,
+    root: &Path,
+    out: &Path,
+    files: &mut BTreeMap<String, String>,
+) -> Result<()> {
+    for entry in fs::read_dir(dir).context("reading source directory")? {
+        let entry = entry.context("reading source directory entry")?;
+        let path = entry.path();
+        let relative = path.strip_prefix(root).context("getting relative path")?;
+        let file_type = entry.file_type().context("getting file type")?;
+        if file_type.is_dir() {
+            fs::create_dir_all(out.join(relative)).context("creating directory")?;
+            copy_files(&path, root, out, files)?;
+        } else if file_type.is_file() {
+            let data = fs::read(&path).context("reading source file")?;
+            fs::write(out.join(relative), &data).context("copying source file")?;
+            files.insert(
+                relative
+                    .to_str()
+                    .ok_or_eyre("converting path to utf-8")?
+                    .to_string(),
+                hex::encode(Sha256::digest(&data).as_slice()),
+            );
+        }
+    }
+    Ok(())
+}
+
+pub fn run(archive: PathBuf, dir: PathBuf, mut out: PathBuf) -> Result<()> {
+    let package = hex::encode(
+        Sha256::digest(fs::read(archive).context("reading source archive")?).as_slice(),
+    );
+    fs::create_dir_all(&out).context("creating output dir")?;
+    let mut files = BTreeMap::new();
+    copy_files(&dir, &dir, &out, &mut files)?;
+    println!("writing .cargo-checksum.json");
+    out.push(".cargo-checksum.json");
+    fs::write(
+        &out,
+        serde_json::to_vec(&Hashes { package, files }).context("serializing hashes")?,
+    )
+    .context("writing hashes")?;
+    Ok(())
+}
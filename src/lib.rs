@@ -0,0 +1,48 @@
+//! Planning and execution of rust crate builds without cargo.
+//!
+//! The `nix-rust-build` binary is a thin wrapper around these modules,
+//! each of which implements one of its subcommands:
+//!
+//! - [`prepare_lockfile`] and [`write_vendor`] vendor the registry crates of a `Cargo.lock`,
+//!   [`unpack_vendor`] unpacks a single crate archive and [`install_src_hash`] installs an
+//!   already unpacked one.
+//! - [`metadata`] resolves a workspace into the per target build plan described by
+//!   [`schema::Metadata`] using `cargo metadata`, [`resolve`] builds the same document from
+//!   `Cargo.lock` and the vendored manifests alone.
+//! - [`compile`] turns a [`schema::CrateJob`] into a rustc invocation, recording its json
+//!   diagnostics which [`diagnostics`] aggregates into a report. [`lint`] runs the same
+//!   invocation through `clippy-driver` and [`doc`] through `rustdoc`, merging several crates
+//!   into one doc tree. [`doctest`] runs the doctests of a library with `rustdoc --test`,
+//!   [`test`] runs the test binaries of a workspace member and [`coverage`] reports the
+//!   coverage of instrumented ones. [`pgo`] collects the profile of a training run for
+//!   profile-guided optimization.
+//!   [`dep_info`] records the files every compile read and lists them per package, [`rpath`]
+//!   sets the runpath of linked artifacts and checks their shared libraries resolve.
+//! - [`run_build_script`] runs a build script and parses its output into a
+//!   [`schema::BuildScriptResult`].
+//! - [`sysroot`] adds the standard library crates to the plan of targets without a prebuilt one
+//!   and links them into a sysroot.
+//! - [`build`] runs the whole plan of a [`schema::Metadata`] locally, without nix.
+//!   [`cache`] lets [`compile`] reuse the outputs of identical rustc invocations across builds.
+
+pub mod build;
+pub mod cache;
+pub mod compile;
+pub mod coverage;
+pub mod dep_info;
+pub mod diagnostics;
+pub mod doc;
+pub mod doctest;
+pub mod install_src_hash;
+pub mod lint;
+pub mod metadata;
+pub mod pgo;
+pub mod prepare_lockfile;
+pub mod resolve;
+pub mod rpath;
+pub mod run_build_script;
+pub mod schema;
+pub mod sysroot;
+pub mod test;
+pub mod unpack_vendor;
+pub mod write_vendor;
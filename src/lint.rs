@@ -0,0 +1,72 @@
+use std::{fs, path::PathBuf};
+
+use clap::ValueEnum;
+use color_eyre::eyre::{Context, Result, bail};
+
+use crate::{compile::CrateJob, diagnostics};
+
+/// Lowest diagnostic level that fails the lint run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum DenyLevel {
+    Warning,
+    Error,
+    Never,
+}
+
+impl DenyLevel {
+    fn denies(self, level: &str) -> bool {
+        match level {
+            "error" | "error: internal compiler error" => self <= Self::Error,
+            "warning" => self <= Self::Warning,
+            _ => false,
+        }
+    }
+}
+
+/// Runs `job` through `clippy_driver` and writes all diagnostics to `out/diagnostics.json`.
+///
+/// The command is built by [`CrateJob::command_common`], so clippy sees the same cfgs, features
+/// and externs as the real build. Fails if rustc failed or a diagnostic reached `deny`.
+pub fn run(
+    src: PathBuf,
+    cargo: PathBuf,
+    clippy_driver: PathBuf,
+    job: PathBuf,
+    out: PathBuf,
+    deny: DenyLevel,
+    args: Vec<String>,
+) -> Result<()> {
+    let mut job = CrateJob::new(
+        serde_json::from_slice(&fs::read(job).context("reading job")?)
+            .context("deserializing job")?,
+    );
+    let mut command = job
+        .with_build_script()?
+        .lib_path_from_env()
+        .lint()
+        .command_common(&cargo, &clippy_driver, &src)?;
+    fs::create_dir_all(&out).context("creating output dir")?;
+    command
+        .arg("--out-dir")
+        .arg(&out)
+        .args(&args)
+        .env("CARGO_PRIMARY_PACKAGE", "1");
+    let output = diagnostics::run_json(&mut command, false)?;
+    let denied = output
+        .messages
+        .iter()
+        .filter(|message| {
+            diagnostics::is_diagnostic(message)
+                && message["level"].as_str().is_some_and(|l| deny.denies(l))
+        })
+        .count();
+    let succeeded = output.status.success() && denied == 0;
+    diagnostics::write(&out.join("diagnostics.json"), &output.messages, succeeded)?;
+    if !output.status.success() {
+        bail!("clippy-driver failed with {}", output.status);
+    }
+    if denied != 0 {
+        bail!("{denied} diagnostics reached the deny level {deny:?}");
+    }
+    Ok(())
+}
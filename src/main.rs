@@ -2,6 +2,11 @@ use std::path::PathBuf;
 
 use clap::{Parser, Subcommand};
 use color_eyre::eyre::Result;
+use nix_rust_build::{
+    build, cache, compile, coverage, dep_info, diagnostics, doc, doctest, install_src_hash, lint,
+    metadata, pgo, prepare_lockfile, resolve, run_build_script, schema, sysroot, test,
+    unpack_vendor, write_vendor,
+};
 
 #[derive(Subcommand)]
 enum Command {
@@ -12,8 +17,24 @@ enum Command {
     Metadata {
         project_dir: PathBuf,
         vendor_dir: PathBuf,
-        target: String,
         out: PathBuf,
+        #[arg(required = true)]
+        targets: Vec<String>,
+        /// Build the standard library of this target from `rust-src` instead of using the
+        /// prebuilt one, like `cargo -Z build-std`.
+        #[arg(long)]
+        build_std: Vec<String>,
+    },
+    Resolve {
+        project_dir: PathBuf,
+        vendor_dir: PathBuf,
+        out: PathBuf,
+        #[arg(required = true)]
+        targets: Vec<String>,
+        /// Build the standard library of this target from `rust-src` instead of using the
+        /// prebuilt one, like `cargo -Z build-std`.
+        #[arg(long)]
+        build_std: Vec<String>,
     },
     WriteVendor {
         job: PathBuf,
@@ -23,6 +44,11 @@ enum Command {
         src: PathBuf,
         out: PathBuf,
     },
+    InstallSrcHash {
+        archive: PathBuf,
+        dir: PathBuf,
+        out: PathBuf,
+    },
     Compile {
         src: PathBuf,
         cargo: PathBuf,
@@ -30,6 +56,82 @@ enum Command {
         job: PathBuf,
         out: PathBuf,
     },
+    Lint {
+        src: PathBuf,
+        cargo: PathBuf,
+        clippy_driver: PathBuf,
+        job: PathBuf,
+        out: PathBuf,
+        #[arg(long, value_enum, default_value_t = lint::DenyLevel::Warning)]
+        deny: lint::DenyLevel,
+        /// Extra arguments for clippy-driver, like `-W clippy::pedantic`.
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Aggregates the diagnostics of build outputs or failed build logs into one report.
+    Diagnostics {
+        out: PathBuf,
+        /// Additionally write the diagnostics as a SARIF log.
+        #[arg(long)]
+        sarif: Option<PathBuf>,
+        /// Prefix removed from the file names in the SARIF log, like the workspace source.
+        #[arg(long)]
+        strip_prefix: Option<PathBuf>,
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+    },
+    /// Lists the source files of every package read by the given build outputs.
+    SourceFiles {
+        out: PathBuf,
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+    },
+    Doc {
+        cargo: PathBuf,
+        rustdoc: PathBuf,
+        jobs: PathBuf,
+        out: PathBuf,
+    },
+    Doctest {
+        src: PathBuf,
+        cargo: PathBuf,
+        rustdoc: PathBuf,
+        job: PathBuf,
+        out: PathBuf,
+    },
+    /// Runs the test binaries of a workspace member.
+    Test {
+        package_dir: PathBuf,
+        out: PathBuf,
+        #[arg(required = true)]
+        tests: Vec<PathBuf>,
+    },
+    /// Runs instrumented test binaries and reports the coverage of a workspace member as lcov
+    /// and summary.
+    Coverage {
+        llvm_profdata: PathBuf,
+        llvm_cov: PathBuf,
+        package_dir: PathBuf,
+        out: PathBuf,
+        #[arg(required = true)]
+        tests: Vec<PathBuf>,
+    },
+    /// Runs a training command against instrumented bins and merges the profiles it collected
+    /// for `-C profile-use`.
+    Pgo {
+        llvm_profdata: PathBuf,
+        dir: PathBuf,
+        out: PathBuf,
+        bins: Vec<PathBuf>,
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Links the standard library crates built for a target into a sysroot.
+    Sysroot {
+        target: String,
+        out: PathBuf,
+        libs: Vec<PathBuf>,
+    },
     RunBuildScript {
         script: PathBuf,
         cargo: PathBuf,
@@ -39,6 +141,23 @@ enum Command {
         info_path: PathBuf,
         out: PathBuf,
     },
+    /// Builds the workspace members from a metadata document locally, skipping up to date jobs.
+    Build {
+        metadata: PathBuf,
+        project_dir: PathBuf,
+        vendor_dir: PathBuf,
+        out: PathBuf,
+        #[command(flatten)]
+        options: Box<build::BuildOptions>,
+    },
+    /// Manages the compile cache enabled by `NIX_RUST_BUILD_CACHE`.
+    Cache {
+        #[command(subcommand)]
+        command: cache::CacheCommand,
+    },
+    Schema {
+        document: schema::Document,
+    },
 }
 
 #[derive(Parser)]
@@ -47,24 +166,26 @@ struct Cli {
     command: Command,
 }
 
-mod compile;
-mod metadata;
-mod prepare_lockfile;
-mod run_build_script;
-mod unpack_vendor;
-mod write_vendor;
-
 fn main() -> Result<()> {
     match Cli::parse().command {
         Command::Lockfile { lock_file, out } => prepare_lockfile::run(&lock_file, &out),
         Command::Metadata {
             project_dir,
             vendor_dir,
-            target,
             out,
-        } => metadata::run(project_dir, vendor_dir, target, out),
+            targets,
+            build_std,
+        } => metadata::run(project_dir, vendor_dir, out, targets, build_std),
+        Command::Resolve {
+            project_dir,
+            vendor_dir,
+            out,
+            targets,
+            build_std,
+        } => resolve::run(project_dir, vendor_dir, out, targets, build_std),
         Command::WriteVendor { job, out } => write_vendor::run(job, out),
         Command::UnpackVendor { src, out } => unpack_vendor::run(src, out),
+        Command::InstallSrcHash { archive, dir, out } => install_src_hash::run(archive, dir, out),
         Command::Compile {
             src,
             cargo,
@@ -72,6 +193,55 @@ fn main() -> Result<()> {
             job,
             out,
         } => compile::run(src, cargo, rustc, job, out),
+        Command::Lint {
+            src,
+            cargo,
+            clippy_driver,
+            job,
+            out,
+            deny,
+            args,
+        } => lint::run(src, cargo, clippy_driver, job, out, deny, args),
+        Command::Diagnostics {
+            out,
+            sarif,
+            strip_prefix,
+            inputs,
+        } => diagnostics::run(out, sarif, strip_prefix, inputs),
+        Command::SourceFiles { out, inputs } => dep_info::run(out, inputs),
+        Command::Doc {
+            cargo,
+            rustdoc,
+            jobs,
+            out,
+        } => doc::run(cargo, rustdoc, jobs, out),
+        Command::Doctest {
+            src,
+            cargo,
+            rustdoc,
+            job,
+            out,
+        } => doctest::run(src, cargo, rustdoc, job, out),
+        Command::Test {
+            package_dir,
+            out,
+            tests,
+        } => test::run(package_dir, out, tests),
+        Command::Coverage {
+            llvm_profdata,
+            llvm_cov,
+            package_dir,
+            out,
+            tests,
+        } => coverage::run(llvm_profdata, llvm_cov, package_dir, out, tests),
+        Command::Pgo {
+            llvm_profdata,
+            dir,
+            out,
+            bins,
+            command,
+        } => pgo::run(llvm_profdata, dir, out, bins, command),
+        Command::Sysroot { target, out, libs } => sysroot::run(target, out, libs),
         Command::RunBuildScript {
             script,
             cargo,
@@ -81,5 +251,14 @@ fn main() -> Result<()> {
             info_path,
             out,
         } => run_build_script::run(script, cargo, rustc, rustdoc, src, info_path, out),
+        Command::Build {
+            metadata,
+            project_dir,
+            vendor_dir,
+            out,
+            options,
+        } => build::run(metadata, project_dir, vendor_dir, out, *options),
+        Command::Cache { command } => cache::run(command),
+        Command::Schema { document } => schema::run(document),
     }
 }
@@ -1,307 +1,311 @@
-use color_eyre::eyre::{bail, eyre, Context, OptionExt, Result};
-use serde::Serialize;
+use color_eyre::eyre::{Context, OptionExt, Result, bail, eyre};
 use std::{
-    borrow::Cow,
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap},
     env, fs,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
 use cargo_metadata::{
-    CargoOpt, CrateType, Edition, MetadataCommand, Node, Package, PackageId, TargetKind,
+    CargoOpt, CrateType, DependencyKind, MetadataCommand, Node, Package, PackageId, TargetKind,
 };
+use cargo_platform::{Cfg, Platform};
 
-use cargo_util_schemas::manifest::FeatureName;
-
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-enum PkgId<'s> {
-    Original(&'s PackageId),
-    Modified(String),
-}
-
-impl<'s> PkgId<'s> {
-    fn new(pkg: &'s PackageId, src: &Path) -> Self {
-        let src = src.to_str().expect("package id has non unicode char");
-        if pkg.repr.contains(src) {
-            Self::Modified(pkg.repr.replace(src, "source"))
-        } else {
-            Self::Original(pkg)
-        }
-    }
-}
-
-impl<'s> AsRef<str> for PkgId<'s> {
-    fn as_ref(&self) -> &str {
-        match self {
-            PkgId::Original(p) => p.repr.as_str(),
-            PkgId::Modified(p) => p.as_str(),
-        }
-    }
-}
-
-impl<'s> Serialize for PkgId<'s> {
-    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        serializer.serialize_str(self.as_ref())
-    }
-}
+use crate::{
+    resolve::{
+        DepKind, FeatureDep, FeatureNode, FeatureResolver, profiles, read_manifest, target_codegen,
+    },
+    run_build_script::cfg_from_rustc,
+    schema::{
+        BuildScriptTarget, CompileTarget, Dep, Metadata, PackageMetadata, ResolvedPackage,
+        SchemaVersion, TargetCodegen, TargetMetadata,
+    },
+    sysroot,
+};
 
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct Common<'s> {
-    manifest_path: &'s Path,
-    version: String,
-    authors: Option<String>,
-    pname: &'s str,
-    description: Option<&'s str>,
-    homepage: Option<&'s str>,
-    repository: Option<&'s str>,
-    license: Option<&'s str>,
-    license_file: Option<&'s Path>,
-    rust_version: Option<String>,
-    readme: Option<&'s Path>,
-    target: &'s str,
-    features: &'s Vec<FeatureName>,
-    all_features: Vec<&'s String>,
-    edition: Edition,
-    main_workspace: bool,
-    links: Option<&'s str>,
+fn pkg_id(pkg: &PackageId, src: &Path) -> String {
+    let src = src.to_str().expect("package id has non unicode char");
+    pkg.repr.replace(src, "source")
 }
 
-impl<'s> Common<'s> {
-    fn from_package(
-        package: &'s Package,
-        node: &'s Node,
-        project_dir: &Path,
-        vendor_dir: &Path,
-        target: &'s str,
-    ) -> Result<Self> {
+impl PackageMetadata {
+    fn from_package(package: &Package, project_dir: &Path, vendor_dir: &Path) -> Result<Self> {
         Ok(Self {
             manifest_path: make_relative(
                 package.manifest_path.as_std_path(),
                 project_dir,
                 vendor_dir,
-            )?,
+            )?
+            .to_path_buf(),
             version: package.version.to_string(),
             authors: if package.authors.is_empty() {
                 None
             } else {
-                Some(package.authors.join(":"))
+                Some(package.authors.clone())
             },
-            pname: &package.name,
-            description: package.description.as_deref(),
-            homepage: package.homepage.as_deref(),
-            repository: package.repository.as_deref(),
-            license: package.repository.as_deref(),
+            pname: package.name.to_string(),
+            description: package.description.clone(),
+            homepage: package.homepage.clone(),
+            repository: package.repository.clone(),
+            license: package.license.clone(),
             license_file: if let Some(file) = package.license_file.as_ref() {
-                Some(make_relative(file.as_std_path(), project_dir, vendor_dir)?)
+                Some(make_relative(file.as_std_path(), project_dir, vendor_dir)?.to_path_buf())
             } else {
                 None
             },
             rust_version: package.rust_version.as_ref().map(ToString::to_string),
             readme: if let Some(file) = package.readme.as_ref() {
-                Some(make_relative(file.as_std_path(), project_dir, vendor_dir)?)
+                Some(make_relative(file.as_std_path(), project_dir, vendor_dir)?.to_path_buf())
             } else {
                 None
             },
-            target,
-            features: &node.features,
-            all_features: package.features.keys().collect(),
+            all_features: package.features.keys().cloned().collect(),
             edition: package.edition,
             main_workspace: package.source.is_none(),
-            links: package.links.as_deref(),
+            links: package.links.clone(),
+            sysroot_crate: false,
         })
     }
 }
 
-#[derive(Debug, Serialize, Clone)]
-struct Dep<'s> {
-    name: Cow<'s, str>,
-    pkg: PkgId<'s>,
-}
-
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct CompileJobCommon<'s> {
-    target_name: &'s str,
-    crate_name: String,
-    deps: Vec<Dep<'s>>,
-    crate_type: &'static str,
-    entrypoint: &'s Path,
-}
-
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct CompileJobBuildScript<'s> {
-    main_deps: Vec<Dep<'s>>,
-    main_crate_name: String,
-    #[serde(flatten)]
-    common: CompileJobCommon<'s>,
-}
-
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct ResolvedPackage<'s> {
-    common: Common<'s>,
-    build_script: Option<CompileJobBuildScript<'s>>,
-    rust_lib: Option<CompileJobCommon<'s>>,
-    c_lib: Option<CompileJobCommon<'s>>,
-    bins: Option<Vec<CompileJobCommon<'s>>>,
-}
-
-fn make_crate_name(name: &str) -> String {
+pub(crate) fn make_crate_name(name: &str) -> String {
     name.replace("-", "_")
 }
 
-impl<'s> ResolvedPackage<'s> {
+impl ResolvedPackage {
+    /// The plan of `package` on a target, with `node` reduced to the deps activated on it.
     fn from_package(
-        package: &'s Package,
-        node: &'s Node,
+        package: &Package,
+        node: &Node,
+        features: Vec<String>,
         project_dir: &Path,
         vendor_dir: &Path,
-        target: &'s str,
+        member: bool,
     ) -> Result<Self> {
         let mut build_deps: Vec<Dep> = vec![];
         let mut deps: Vec<Dep> = vec![];
+        let mut dev_deps: Vec<Dep> = vec![];
         for dep in &node.deps {
             let d = Dep {
-                name: Cow::Borrowed(&dep.name),
-                pkg: PkgId::new(&dep.pkg, project_dir),
+                name: dep.name.clone(),
+                pkg: pkg_id(&dep.pkg, project_dir),
             };
             for kind in &dep.dep_kinds {
-                match kind.kind {
-                    cargo_metadata::DependencyKind::Normal => deps.push(d.clone()),
-                    cargo_metadata::DependencyKind::Build => build_deps.push(d.clone()),
-                    _ => {}
+                let list = match kind.kind {
+                    DependencyKind::Normal => &mut deps,
+                    DependencyKind::Build => &mut build_deps,
+                    DependencyKind::Development => &mut dev_deps,
+                    _ => continue,
+                };
+                // a dep declared for all targets and a matching cfg is passed once
+                if !list.iter().any(|e| e.name == d.name && e.pkg == d.pkg) {
+                    list.push(d.clone());
                 }
             }
         }
-        let common = Common::from_package(package, node, project_dir, vendor_dir, target)
-            .context("collecting commmon metadata")?;
         let mut build_script = None;
         let mut rust_lib = None;
         let mut c_lib = None;
         let mut bins = Vec::new();
+        let mut doctest = false;
+        let mut lib_test = false;
+        let mut tested_bins = BTreeSet::new();
+        let mut tests = Vec::new();
 
         for target in &package.targets {
+            let entrypoint = make_relative(target.src_path.as_std_path(), project_dir, vendor_dir)?
+                .to_path_buf();
             if target.kind.contains(&TargetKind::CustomBuild)
                 && target.crate_types.contains(&CrateType::Bin)
             {
-                let script = CompileJobBuildScript {
+                let script = BuildScriptTarget {
                     main_deps: deps.clone(),
                     main_crate_name: make_crate_name(&package.name),
-                    common: CompileJobCommon {
-                        crate_name: "build_script".to_string(),
-                        deps: build_deps.clone(),
-                        crate_type: "bin",
-                        target_name: "build_script",
-                        entrypoint: make_relative(
-                            target.src_path.as_std_path(),
-                            project_dir,
-                            vendor_dir,
-                        )?,
-                    },
+                    crate_name: "build_script".to_string(),
+                    deps: build_deps.clone(),
+                    crate_type: "bin".to_string(),
+                    target_name: "build_script".to_string(),
+                    entrypoint: entrypoint.clone(),
+                    edition: target.edition,
                 };
                 if build_script.replace(script).is_some() {
-                    bail!("more than one buildscript in crate")
+                    bail!("more than one buildscript in crate");
                 }
             }
             if target.kind.contains(&TargetKind::Lib)
                 && target.crate_types.contains(&CrateType::Lib)
             {
-                let job = CompileJobCommon {
+                let job = CompileTarget {
                     crate_name: make_crate_name(&target.name),
                     deps: deps.clone(),
-                    crate_type: "lib",
-                    target_name: &target.name,
-                    entrypoint: make_relative(
-                        target.src_path.as_std_path(),
-                        project_dir,
-                        vendor_dir,
-                    )?,
+                    crate_type: "lib".to_string(),
+                    target_name: target.name.clone(),
+                    entrypoint,
+                    edition: target.edition,
                 };
                 if rust_lib.replace(job).is_some() {
-                    bail!("more than one lib in crate")
+                    bail!("more than one lib in crate");
                 }
+                doctest = target.doctest;
+                lib_test = target.test;
             } else if target.kind.contains(&TargetKind::ProcMacro)
                 && target.crate_types.contains(&CrateType::ProcMacro)
             {
-                let job = CompileJobCommon {
+                let job = CompileTarget {
                     crate_name: make_crate_name(&target.name),
                     deps: deps.clone(),
-                    target_name: &target.name,
-                    crate_type: "proc-macro",
-                    entrypoint: make_relative(
-                        target.src_path.as_std_path(),
-                        project_dir,
-                        vendor_dir,
-                    )?,
+                    target_name: target.name.clone(),
+                    crate_type: "proc-macro".to_string(),
+                    entrypoint,
+                    edition: target.edition,
                 };
                 if rust_lib.replace(job).is_some() {
-                    bail!("more than one lib in crate")
+                    bail!("more than one lib in crate");
                 }
+                doctest = target.doctest;
             } else if target.kind.contains(&TargetKind::CDyLib)
                 && target.crate_types.contains(&CrateType::CDyLib)
             {
-                let job = CompileJobCommon {
+                let job = CompileTarget {
                     crate_name: make_crate_name(&target.name),
                     deps: deps.clone(),
-                    target_name: &target.name,
-                    crate_type: "cdylib",
-                    entrypoint: make_relative(
-                        target.src_path.as_std_path(),
-                        project_dir,
-                        vendor_dir,
-                    )?,
+                    target_name: target.name.clone(),
+                    crate_type: "cdylib".to_string(),
+                    entrypoint,
+                    edition: target.edition,
                 };
                 if c_lib.replace(job).is_some() {
-                    bail!("more than one clib in crate")
+                    bail!("more than one clib in crate");
                 }
             } else if target.kind.contains(&TargetKind::Bin)
                 && target.crate_types.contains(&CrateType::Bin)
             {
-                let job = CompileJobCommon {
+                let job = CompileTarget {
                     crate_name: make_crate_name(&target.name),
                     deps: deps.clone(),
-                    target_name: &target.name,
-                    crate_type: "bin",
-                    entrypoint: make_relative(
-                        target.src_path.as_std_path(),
-                        project_dir,
-                        vendor_dir,
-                    )?,
+                    target_name: target.name.clone(),
+                    crate_type: "bin".to_string(),
+                    entrypoint,
+                    edition: target.edition,
                 };
+                if target.test {
+                    tested_bins.insert(target.name.clone());
+                }
                 bins.push(job);
+            } else if target.kind.contains(&TargetKind::Test) && target.test {
+                tests.push(CompileTarget {
+                    crate_name: make_crate_name(&target.name),
+                    deps: deps.clone(),
+                    target_name: target.name.clone(),
+                    crate_type: "test".to_string(),
+                    entrypoint,
+                    edition: target.edition,
+                });
             }
         }
 
+        let mut doctest_target = None;
+        let mut lib_dep = None;
         if let Some(lib) = rust_lib.as_ref() {
+            let dep = Dep {
+                name: lib.crate_name.clone(),
+                pkg: pkg_id(&package.id, project_dir),
+            };
             for bin in &mut bins {
-                bin.deps.push(Dep {
-                    name: Cow::Owned(lib.crate_name.clone()),
-                    pkg: PkgId::new(&package.id, project_dir),
-                });
+                bin.deps.push(dep.clone());
             }
+            if member && doctest {
+                doctest_target = Some(doctest_job(lib, &dev_deps, dep.clone()));
+            }
+            lib_dep = Some(dep);
         }
+        let tests = if member {
+            let unit_tests = rust_lib
+                .iter()
+                .filter(|lib| lib_test && lib.crate_type == "lib")
+                .chain(
+                    bins.iter()
+                        .filter(|bin| tested_bins.contains(&bin.target_name)),
+                )
+                .map(|target| test_job(target, &dev_deps, None));
+            let integration_tests = tests
+                .iter()
+                .map(|test| test_job(test, &dev_deps, lib_dep.clone()));
+            unit_tests.chain(integration_tests).collect()
+        } else {
+            Vec::new()
+        };
 
         Ok(Self {
-            common,
+            features,
             build_script,
             rust_lib,
             c_lib,
             bins: if bins.is_empty() { None } else { Some(bins) },
+            doctest: doctest_target,
+            tests,
         })
     }
 }
 
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct Output<'s> {
-    packages: HashMap<PkgId<'s>, ResolvedPackage<'s>>,
-    workspace: HashMap<&'s str, PkgId<'s>>,
-    main_package: Option<PkgId<'s>>,
+/// The deps of `target` followed by `extra` ones it doesn't depend on yet.
+fn merge_deps(target: &CompileTarget, extra: impl IntoIterator<Item = Dep>) -> Vec<Dep> {
+    let mut deps = target.deps.clone();
+    for dep in extra {
+        if !deps.iter().any(|d| d.name == dep.name && d.pkg == dep.pkg) {
+            deps.push(dep);
+        }
+    }
+    deps
+}
+
+/// The doctest target of `lib`, which additionally depends on `dev_deps` and the library itself.
+pub(crate) fn doctest_job(lib: &CompileTarget, dev_deps: &[Dep], lib_dep: Dep) -> CompileTarget {
+    CompileTarget {
+        target_name: lib.target_name.clone(),
+        crate_name: lib.crate_name.clone(),
+        deps: merge_deps(lib, dev_deps.iter().cloned().chain([lib_dep])),
+        crate_type: lib.crate_type.clone(),
+        entrypoint: lib.entrypoint.clone(),
+        edition: lib.edition,
+    }
+}
+
+/// The `--test` target of `target`, which additionally depends on `dev_deps` and, for
+/// integration tests, the library of the package.
+pub(crate) fn test_job(
+    target: &CompileTarget,
+    dev_deps: &[Dep],
+    lib_dep: Option<Dep>,
+) -> CompileTarget {
+    CompileTarget {
+        target_name: target.target_name.clone(),
+        crate_name: target.crate_name.clone(),
+        deps: merge_deps(target, dev_deps.iter().cloned().chain(lib_dep)),
+        crate_type: "test".to_string(),
+        entrypoint: target.entrypoint.clone(),
+        edition: target.edition,
+    }
+}
+
+pub(crate) struct TargetPlatform<'s> {
+    name: &'s str,
+    cfgs: Vec<Cfg>,
+}
+
+impl<'s> TargetPlatform<'s> {
+    pub(crate) fn new(name: &'s str, rustc: &Path, codegen: &TargetCodegen) -> Result<Self> {
+        let cfgs = cfg_from_rustc(name, rustc, codegen)?
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| Cfg::from_str(line).with_context(|| format!("parsing cfg {line}")))
+            .collect::<Result<_>>()?;
+        Ok(Self { name, cfgs })
+    }
+
+    pub(crate) fn matches(&self, platform: Option<&Platform>) -> bool {
+        platform.is_none_or(|platform| platform.matches(self.name, &self.cfgs))
+    }
 }
 
 fn make_relative<'s>(path: &'s Path, project_dir: &Path, vendor_dir: &Path) -> Result<&'s Path> {
@@ -321,71 +325,311 @@ fn make_relative<'s>(path: &'s Path, project_dir: &Path, vendor_dir: &Path) -> R
     }
 }
 
-pub fn run(project_dir: PathBuf, vendor_dir: PathBuf, target: String, out: PathBuf) -> Result<()> {
-    let features = env::var("features").unwrap_or_default();
-    let no_default_features = env::var("noDefaultFeatures")
-        .map(|v| v == "1")
-        .unwrap_or(false);
+/// Feature selection applied to the workspace while resolving metadata.
+#[derive(Debug, Clone, Default)]
+pub struct FeatureSelection {
+    pub features: Vec<String>,
+    pub no_default_features: bool,
+}
+
+impl FeatureSelection {
+    /// Reads the selection from the `features` and `noDefaultFeatures` derivation attributes.
+    pub fn from_env() -> Self {
+        Self {
+            features: env::var("features")
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+            no_default_features: env::var("noDefaultFeatures")
+                .map(|v| v == "1")
+                .unwrap_or(false),
+        }
+    }
+}
+
+fn dep_kind(kind: DependencyKind) -> Option<DepKind> {
+    match kind {
+        DependencyKind::Normal => Some(DepKind::Normal),
+        DependencyKind::Build => Some(DepKind::Build),
+        DependencyKind::Development => Some(DepKind::Development),
+        DependencyKind::Unknown => None,
+    }
+}
+
+/// The feature graph of the resolve `nodes`, in their order.
+///
+/// The declared deps of a package are matched to the edges of its node by name, and if it depends
+/// on several versions by the path of path dependencies and the version requirement of others.
+/// Deps without an edge aren't activated on any target.
+fn feature_nodes(
+    nodes: &[Node],
+    packages: &HashMap<&PackageId, &Package>,
+    indices: &HashMap<&PackageId, usize>,
+) -> Result<Vec<FeatureNode>> {
+    nodes
+        .iter()
+        .map(|node| {
+            let package = *packages
+                .get(&node.id)
+                .ok_or_eyre("getting package for resolve node")?;
+            let mut deps = Vec::new();
+            for dep in &package.dependencies {
+                let Some(kind) = dep_kind(dep.kind) else {
+                    continue;
+                };
+                let candidates: Vec<&Package> = node
+                    .deps
+                    .iter()
+                    .filter_map(|edge| packages.get(&edge.pkg).copied())
+                    .filter(|candidate| candidate.name.as_str() == dep.name)
+                    .collect();
+                let resolved = match candidates[..] {
+                    [candidate] => candidate,
+                    _ => match candidates.into_iter().find(|candidate| match &dep.path {
+                        Some(path) => candidate.manifest_path.parent() == Some(path.as_path()),
+                        None => dep.req.matches(&candidate.version),
+                    }) {
+                        Some(candidate) => candidate,
+                        None => continue,
+                    },
+                };
+                deps.push(FeatureDep {
+                    key: dep.rename.clone().unwrap_or_else(|| dep.name.clone()),
+                    kind,
+                    platform: dep.target.clone(),
+                    optional: dep.optional,
+                    default_features: dep.uses_default_features,
+                    features: dep.features.clone(),
+                    package: *indices
+                        .get(&resolved.id)
+                        .ok_or_eyre("getting resolve node of dependency")?,
+                });
+            }
+            Ok(FeatureNode {
+                name: package.name.to_string(),
+                features: package.features.clone(),
+                deps,
+            })
+        })
+        .collect()
+}
+
+/// `node` with only the edge kinds of the deps `resolver` activated at `index`.
+fn activated_node(
+    node: &Node,
+    index: usize,
+    nodes: &[FeatureNode],
+    resolver: &FeatureResolver,
+    indices: &HashMap<&PackageId, usize>,
+) -> Node {
+    let activated: Vec<&FeatureDep> = resolver
+        .deps
+        .get(&index)
+        .into_iter()
+        .flatten()
+        .map(|dep| &nodes[index].deps[*dep])
+        .collect();
+    let mut node = node.clone();
+    for edge in &mut node.deps {
+        let package = indices.get(&edge.pkg).copied();
+        edge.dep_kinds.retain(|kind| {
+            activated.iter().any(|dep| {
+                Some(dep.package) == package
+                    && Some(dep.kind) == dep_kind(kind.kind)
+                    && dep.platform == kind.target
+            })
+        });
+    }
+    node.deps.retain(|edge| !edge.dep_kinds.is_empty());
+    node
+}
 
+/// Resolves the workspace in `project_dir` against the vendored sources in `vendor_dir`.
+///
+/// Runs `cargo metadata` once for all `targets`. Its features are unified across all of them,
+/// so the features and activated deps per target are resolved again on its graph using the cfgs
+/// reported by `rustc`, like [`crate::resolve::resolve`] does. Paths in the result are relative
+/// to the crate sources.
+pub fn resolve(
+    project_dir: &Path,
+    vendor_dir: &Path,
+    targets: &[String],
+    features: &FeatureSelection,
+    rustc: &Path,
+) -> Result<Metadata> {
     let mut command = MetadataCommand::new();
 
-    if no_default_features {
+    if features.no_default_features {
         command.features(CargoOpt::NoDefaultFeatures);
     }
-    if !features.is_empty() {
-        command.features(CargoOpt::SomeFeatures(
-            features.split_whitespace().map(str::to_string).collect(),
-        ));
+    if !features.features.is_empty() {
+        command.features(CargoOpt::SomeFeatures(features.features.clone()));
     }
-    let mut vendor_config = vendor_dir.clone();
+    let mut vendor_config = vendor_dir.to_path_buf();
     vendor_config.push("config.toml");
     let vendor_config = vendor_config.to_string_lossy().into_owned();
 
+    let mut options = vec![
+        "--frozen".to_string(),
+        "--config".to_string(),
+        vendor_config,
+    ];
+    // cargo only takes target specs with unstable flags, without any filter the graph covers
+    // all platforms and is split per target below anyway
+    if !targets.iter().any(|target| sysroot::is_target_spec(target)) {
+        for target in targets {
+            options.push("--filter-platform".to_string());
+            options.push(target.clone());
+        }
+    }
     let metadata = command
-        .other_options(vec![
-            "--frozen".to_string(),
-            "--config".to_string(),
-            vendor_config,
-            "--filter-platform".to_string(),
-            target.clone(),
-        ])
-        .current_dir(&project_dir)
+        .other_options(options)
+        .current_dir(project_dir)
         .exec()
         .context("collecting metadata")?;
     let packages: HashMap<&PackageId, &Package> =
         metadata.packages.iter().map(|p| (&p.id, p)).collect();
-    let workspace_members: HashMap<&str, PkgId> = metadata
+    let workspace_members: BTreeMap<String, String> = metadata
         .workspace_members
         .iter()
         .map(|p| -> Result<_> {
             Ok((
-                packages.get(p).ok_or_eyre("unknown package")?.name.as_str(),
-                PkgId::new(p, &project_dir),
+                packages
+                    .get(p)
+                    .ok_or_eyre("unknown package")?
+                    .name
+                    .to_string(),
+                pkg_id(p, project_dir),
             ))
         })
         .collect::<Result<_>>()?;
-    let resolve = metadata.resolve.ok_or_eyre("no resolve in metadata")?;
-    let main_package = resolve.root.as_ref().map(|p| PkgId::new(p, &project_dir));
-    let mut ready_packages: HashMap<PkgId, ResolvedPackage> = HashMap::new();
-    for node in &resolve.nodes {
-        let id = PkgId::new(&node.id, &project_dir);
+    let resolve = metadata
+        .resolve
+        .as_ref()
+        .ok_or_eyre("no resolve in metadata")?;
+    let main_package = resolve.root.as_ref().map(|p| pkg_id(p, project_dir));
+    let indices: HashMap<&PackageId, usize> = resolve
+        .nodes
+        .iter()
+        .enumerate()
+        .map(|(index, node)| (&node.id, index))
+        .collect();
+    let feature_nodes = feature_nodes(&resolve.nodes, &packages, &indices)?;
+    let members: BTreeMap<String, usize> = metadata
+        .workspace_members
+        .iter()
+        .map(|id| -> Result<_> {
+            Ok((
+                packages
+                    .get(id)
+                    .ok_or_eyre("unknown package")?
+                    .name
+                    .to_string(),
+                *indices
+                    .get(id)
+                    .ok_or_eyre("getting resolve node of member")?,
+            ))
+        })
+        .collect::<Result<_>>()?;
+
+    let mut target_outputs: BTreeMap<String, TargetMetadata> = BTreeMap::new();
+    let mut used = BTreeSet::new();
+    for target in targets {
+        let codegen = target_codegen(project_dir, target, rustc)
+            .with_context(|| format!("reading the config of target {target}"))?;
+        let platform = TargetPlatform::new(target, rustc, &codegen)
+            .with_context(|| format!("getting cfgs for target {target}"))?;
+        let resolver = FeatureResolver::new(&feature_nodes, &members, features, &platform)
+            .and_then(FeatureResolver::resolve)
+            .with_context(|| format!("resolving features for target {target}"))?;
+        let mut ready_packages: BTreeMap<String, ResolvedPackage> = BTreeMap::new();
+        used.extend(resolver.active.iter().copied());
+        for index in &resolver.active {
+            let node = &resolve.nodes[*index];
+            let package = *packages
+                .get(&node.id)
+                .ok_or_eyre("getting package for resolve node")?;
+            let id = &node.id;
+            ready_packages.insert(
+                pkg_id(id, project_dir),
+                ResolvedPackage::from_package(
+                    package,
+                    &activated_node(node, *index, &feature_nodes, &resolver, &indices),
+                    resolver
+                        .features
+                        .get(index)
+                        .into_iter()
+                        .flatten()
+                        .cloned()
+                        .collect(),
+                    project_dir,
+                    vendor_dir,
+                    metadata.workspace_members.contains(id),
+                )
+                .with_context(|| format!("resolving package {id} for target {target}"))?,
+            );
+        }
+        target_outputs.insert(
+            target.clone(),
+            TargetMetadata {
+                packages: ready_packages,
+                sysroot: Vec::new(),
+                codegen,
+            },
+        );
+    }
+
+    // the graph of `cargo metadata` also has the packages only activated by unified features
+    let mut common_packages: BTreeMap<String, PackageMetadata> = BTreeMap::new();
+    for index in used {
+        let node = &resolve.nodes[index];
         let package = *packages
             .get(&node.id)
             .ok_or_eyre("getting package for resolve node")?;
-        ready_packages.insert(
-            id,
-            ResolvedPackage::from_package(package, node, &project_dir, &vendor_dir, &target)
-                .with_context(|| format!("resolving package {}", &node.id))?,
+        common_packages.insert(
+            pkg_id(&node.id, project_dir),
+            PackageMetadata::from_package(package, project_dir, vendor_dir)
+                .with_context(|| format!("collecting common metadata of {}", node.id))?,
         );
     }
+    Ok(Metadata {
+        schema_version: SchemaVersion::default(),
+        packages: common_packages,
+        targets: target_outputs,
+        workspace: workspace_members,
+        main_package,
+        profiles: profiles(
+            &read_manifest(metadata.workspace_root.as_std_path())
+                .context("reading workspace manifest")?,
+        )?,
+    })
+}
+
+pub fn run(
+    project_dir: PathBuf,
+    vendor_dir: PathBuf,
+    out: PathBuf,
+    targets: Vec<String>,
+    build_std: Vec<String>,
+) -> Result<()> {
+    let features = FeatureSelection::from_env();
+    let targets = targets
+        .into_iter()
+        .map(sysroot::resolve_target)
+        .collect::<Result<Vec<_>>>()?;
+    let build_std = build_std
+        .into_iter()
+        .map(sysroot::resolve_target)
+        .collect::<Result<Vec<_>>>()?;
+    let rustc = env::var_os("RUSTC")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("rustc"));
+    let mut metadata = resolve(&project_dir, &vendor_dir, &targets, &features, &rustc)?;
+    sysroot::add_packages(&mut metadata, &rustc, &build_std)?;
     fs::write(
         out,
-        serde_json::to_string(&Output {
-            packages: ready_packages,
-            workspace: workspace_members,
-            main_package,
-        })
-        .context("serializing output")?,
+        serde_json::to_string(&metadata).context("serializing output")?,
     )
     .context("writing output")?;
     Ok(())
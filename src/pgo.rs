@@ -0,0 +1,55 @@
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use color_eyre::eyre::{Context, OptionExt, Result, bail};
+
+use crate::coverage::merge_profiles;
+
+/// File in the output of [`run`] that `-C profile-use` reads.
+pub const PROFDATA: &str = "merged.profdata";
+
+/// Runs the training `command` of a profile-guided optimization in `dir` and merges the
+/// profiles it collected into `out/merged.profdata`.
+///
+/// `bins` are the outputs of compile jobs built with
+/// [`crate::schema::CrateJobCommon::profile_generate`], their `bin` dirs are put in front of
+/// `PATH` so the command runs the instrumented builds. Every process writes its profile to
+/// `out/profraw` through `LLVM_PROFILE_FILE`, which `llvm_profdata` merges afterwards.
+pub fn run(
+    llvm_profdata: PathBuf,
+    dir: PathBuf,
+    out: PathBuf,
+    bins: Vec<PathBuf>,
+    command: Vec<String>,
+) -> Result<()> {
+    let (program, args) = command.split_first().ok_or_eyre("no training command")?;
+    let profraw = out.join("profraw");
+    fs::create_dir_all(&profraw).context("creating profraw dir")?;
+    let mut path = Vec::from_iter(bins.iter().map(|bin| bin.join("bin")));
+    if let Some(existing) = env::var_os("PATH") {
+        path.extend(env::split_paths(&existing));
+    }
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .current_dir(&dir)
+        .env("PATH", env::join_paths(path).context("joining PATH")?)
+        .env("LLVM_PROFILE_FILE", profraw.join("%p-%m.profraw"))
+        .stdin(Stdio::null());
+    println!("executing {command:?}");
+    let status = command.status().context("executing training command")?;
+    if !status.success() {
+        bail!("training command failed with {status}");
+    }
+    if fs::read_dir(&profraw)
+        .context("reading profraw dir")?
+        .next()
+        .is_none()
+    {
+        bail!("training command didn't run any instrumented bin");
+    }
+    merge_profiles(&llvm_profdata, &profraw, &out.join(PROFDATA))
+}
@@ -7,12 +7,14 @@ use std::{
 };
 
 use base64::Engine;
-use color_eyre::eyre::{Context, OptionExt, Result};
+use color_eyre::eyre::{Context, OptionExt, Result, bail};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+/// A parsed `Cargo.lock`.
 #[derive(Debug, Deserialize)]
-struct Lockfile {
+pub struct Lockfile {
+    version: Option<u32>,
     package: Vec<Package>,
 }
 
@@ -24,13 +26,16 @@ struct Package {
     checksum: Option<String>,
 }
 
+/// A registry crate that has to be fetched into the vendor directory.
 #[derive(Debug, Serialize)]
-struct Vendor<'s> {
-    name: &'s str,
-    version: &'s str,
-    registry: &'s str,
-    checksum: String,
-    dir_name: Cow<'s, str>,
+pub struct Vendor<'s> {
+    pub name: &'s str,
+    pub version: &'s str,
+    pub registry: &'s str,
+    /// SRI hash of the crate archive.
+    pub checksum: String,
+    /// Directory name below the vendor directory, only suffixed with the version for older duplicates.
+    pub dir_name: Cow<'s, str>,
 }
 
 static REGISTRY_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^registry\+(.*)$").unwrap());
@@ -59,37 +64,63 @@ impl<'s> Vendor<'s> {
     }
 }
 
-pub fn run(lock_file: &Path, out: &Path) -> Result<()> {
-    let lock = fs::read_to_string(lock_file)?;
-    let lockfile: Lockfile = toml::from_str(&lock)?;
-    let mut by_name: HashMap<&'_ str, BTreeMap<&'_ str, &'_ Package>> = HashMap::new();
+impl Lockfile {
+    pub fn parse(lock: &str) -> Result<Self> {
+        let lockfile: Self = toml::from_str(lock).context("parsing lock file")?;
+        match lockfile.version {
+            Some(4) => Ok(lockfile),
+            Some(version) => {
+                bail!("unknown lock file version {version}");
+            }
+            None => {
+                bail!("lock file has no version");
+            }
+        }
+    }
 
-    for package in &lockfile.package {
-        if package.source.is_some() && package.checksum.is_some() {
-            match by_name.entry(&package.name) {
-                std::collections::hash_map::Entry::Vacant(e) => {
-                    let mut map = BTreeMap::new();
-                    map.insert(package.version.as_str(), package);
-                    e.insert(map);
-                }
-                std::collections::hash_map::Entry::Occupied(mut e) => {
-                    e.get_mut().insert(&package.version, package);
+    /// Collects all registry crates, keyed by `<name>-<version>`.
+    pub fn vendor_crates(&self) -> Result<HashMap<String, Vendor<'_>>> {
+        let mut by_name: HashMap<&'_ str, BTreeMap<&'_ str, &'_ Package>> = HashMap::new();
+
+        for package in &self.package {
+            if package.source.is_some() && package.checksum.is_some() {
+                match by_name.entry(&package.name) {
+                    std::collections::hash_map::Entry::Vacant(e) => {
+                        let mut map = BTreeMap::new();
+                        map.insert(package.version.as_str(), package);
+                        e.insert(map);
+                    }
+                    std::collections::hash_map::Entry::Occupied(mut e) => {
+                        if e.get_mut().insert(&package.version, package).is_some() {
+                            bail!(
+                                "version {} of package {} appears more than once",
+                                package.version,
+                                package.name
+                            );
+                        }
+                    }
                 }
             }
         }
-    }
-    let mut packages: HashMap<String, Vendor<'_>> = HashMap::new();
-    for package in by_name.into_values() {
-        let mut iter = package.into_values().rev();
-        let package = iter.next().ok_or_eyre("always at least one entry")?;
-        let vendor = Vendor::from_package(package, Cow::Borrowed(&package.name))?;
-        packages.insert(format!("{}-{}", package.name, package.version), vendor);
-        for package in iter {
-            let name = format!("{}-{}", package.name, package.version);
-            let vendor = Vendor::from_package(package, Cow::Owned(name.clone()))?;
-            packages.insert(name, vendor);
+        let mut packages: HashMap<String, Vendor<'_>> = HashMap::new();
+        for package in by_name.into_values() {
+            let mut iter = package.into_values().rev();
+            let package = iter.next().ok_or_eyre("always at least one entry")?;
+            let vendor = Vendor::from_package(package, Cow::Borrowed(&package.name))?;
+            packages.insert(format!("{}-{}", package.name, package.version), vendor);
+            for package in iter {
+                let name = format!("{}-{}", package.name, package.version);
+                let vendor = Vendor::from_package(package, Cow::Owned(name.clone()))?;
+                packages.insert(name, vendor);
+            }
         }
+        Ok(packages)
     }
-    fs::write(out, serde_json::to_string(&packages)?)?;
+}
+
+pub fn run(lock_file: &Path, out: &Path) -> Result<()> {
+    let lock = fs::read_to_string(lock_file)?;
+    let lockfile = Lockfile::parse(&lock)?;
+    fs::write(out, serde_json::to_string(&lockfile.vendor_crates()?)?)?;
     Ok(())
 }
@@ -0,0 +1,1692 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env, fs,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use cargo_metadata::{
+    Edition,
+    semver::{Version, VersionReq},
+};
+use cargo_platform::Platform;
+use cargo_util_schemas::manifest::{
+    InheritableDependency, InheritableField, StringOrBool, TomlDebugInfo, TomlDependency,
+    TomlManifest, TomlProfile, TomlTarget, TomlWorkspace,
+};
+use color_eyre::eyre::{Context, OptionExt, Result, bail, eyre};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{
+    metadata::{FeatureSelection, TargetPlatform, doctest_job, make_crate_name, test_job},
+    schema::{
+        BuildScriptTarget, CompileTarget, Dep, Metadata, PackageMetadata, Profile, ResolvedPackage,
+        SchemaVersion, TargetCodegen, TargetMetadata,
+    },
+    sysroot,
+};
+
+#[derive(Debug, Deserialize)]
+struct Lockfile {
+    package: Vec<LockPackage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LockPackage {
+    name: String,
+    version: Version,
+    source: Option<String>,
+    #[serde(default)]
+    dependencies: Vec<String>,
+}
+
+/// The parts of `.cargo/config.toml` that configure targets.
+#[derive(Debug, Default, Deserialize)]
+struct CargoConfig {
+    #[serde(default)]
+    build: ConfigBuild,
+    #[serde(default)]
+    target: BTreeMap<String, ConfigTarget>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ConfigBuild {
+    rustflags: Option<ConfigFlags>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigTarget {
+    linker: Option<String>,
+    rustflags: Option<ConfigFlags>,
+}
+
+/// Flags given as a list or as one string split at whitespace.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ConfigFlags {
+    Joined(String),
+    List(Vec<String>),
+}
+
+impl ConfigFlags {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            ConfigFlags::Joined(flags) => flags.split_whitespace().map(str::to_string).collect(),
+            ConfigFlags::List(flags) => flags,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DepKind {
+    Normal,
+    Build,
+    Development,
+}
+
+#[derive(Debug)]
+struct DeclaredDep {
+    key: String,
+    package: String,
+    req: Option<VersionReq>,
+    kind: DepKind,
+    platform: Option<Platform>,
+    optional: bool,
+    default_features: bool,
+    features: Vec<String>,
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+struct ResolvedDep {
+    decl: DeclaredDep,
+    package: usize,
+}
+
+#[derive(Debug)]
+struct Crate {
+    id: String,
+    lib_name: Option<String>,
+    metadata: PackageMetadata,
+    features: BTreeMap<String, Vec<String>>,
+    deps: Vec<ResolvedDep>,
+    build_script: Option<(PathBuf, Edition)>,
+    rust_lib: Option<CompileTarget>,
+    c_lib: Option<CompileTarget>,
+    bins: Vec<CompileTarget>,
+    doctest: bool,
+    /// Whether the unit tests of the library are run, and of which bins.
+    lib_test: bool,
+    tested_bins: BTreeSet<String>,
+    /// Integration tests, without any deps like `bins`.
+    tests: Vec<CompileTarget>,
+}
+
+struct LoadedManifest {
+    dir: PathBuf,
+    manifest: TomlManifest,
+}
+
+impl LoadedManifest {
+    /// The name and version of the package, which may inherit its version from `workspace`.
+    fn name_and_version(&self, workspace: Option<&TomlWorkspace>) -> Result<(String, Version)> {
+        let package = self
+            .manifest
+            .package
+            .as_deref()
+            .or(self.manifest.project.as_deref())
+            .ok_or_eyre("manifest has no package")?;
+        let name = package
+            .name
+            .as_ref()
+            .ok_or_eyre("package has no name")?
+            .to_string();
+        let version = inherit(
+            package.version.as_ref(),
+            workspace
+                .and_then(|w| w.package.as_ref())
+                .and_then(|p| p.version.as_ref()),
+            "version",
+        )?
+        .unwrap_or_else(|| Version::new(0, 0, 0));
+        Ok((name, version))
+    }
+}
+
+pub(crate) fn read_manifest(dir: &Path) -> Result<TomlManifest> {
+    let path = dir.join("Cargo.toml");
+    toml::from_str(
+        &fs::read_to_string(&path)
+            .with_context(|| format!("reading manifest {}", path.display()))?,
+    )
+    .with_context(|| format!("parsing manifest {}", path.display()))
+}
+
+/// The profiles of the workspace manifest `root`, following `inherits` like cargo. Without
+/// `inherits`, `test` inherits from `dev` and `bench` from `release`.
+pub(crate) fn profiles(root: &TomlManifest) -> Result<BTreeMap<String, Profile>> {
+    let declared: BTreeMap<String, &TomlProfile> = root
+        .profile
+        .iter()
+        .flat_map(|profiles| profiles.get_all())
+        .map(|(name, profile)| (name.to_string(), profile))
+        .collect();
+    let mut names: BTreeSet<&str> = ["dev", "release", "test", "bench"].into();
+    names.extend(declared.keys().map(String::as_str));
+    let mut profiles = BTreeMap::new();
+    for name in names {
+        let mut current = name;
+        let mut seen = vec![name];
+        // the profile followed by the ones it inherits from, the first setting wins
+        let mut chain = Vec::new();
+        loop {
+            let profile = declared.get(current).copied();
+            chain.extend(profile);
+            current = match profile.and_then(|p| p.inherits.as_deref()) {
+                Some(parent) if seen.contains(&parent) => {
+                    return Err(eyre!("profile {name} inherits from itself"));
+                }
+                Some(parent) => parent,
+                None if matches!(current, "dev" | "release") => break,
+                None if current == "test" => "dev",
+                None if current == "bench" => "release",
+                None => {
+                    return Err(eyre!(
+                        "profile {current} has to inherit from another profile"
+                    ));
+                }
+            };
+            seen.push(current);
+        }
+        let debug = chain.iter().find_map(|p| p.debug).map(|debug| {
+            match debug {
+                TomlDebugInfo::None => "none",
+                TomlDebugInfo::LineDirectivesOnly => "line-directives-only",
+                TomlDebugInfo::LineTablesOnly => "line-tables-only",
+                TomlDebugInfo::Limited => "limited",
+                TomlDebugInfo::Full => "full",
+            }
+            .to_string()
+        });
+        profiles.insert(
+            name.to_string(),
+            Profile {
+                panic: chain.iter().find_map(|p| p.panic.clone()),
+                debug,
+                split_debuginfo: chain.iter().find_map(|p| p.split_debuginfo.clone()),
+            },
+        );
+    }
+    Ok(profiles)
+}
+
+/// The linker and code generation settings of `target` in the `.cargo/config.toml` of
+/// `project_dir`.
+///
+/// Like cargo, the `linker` of `[target.<triple>]` takes precedence over the one of a matching
+/// `[target.'cfg(..)']`, and the `rustflags` of all matching tables over those of `[build]`.
+/// Only the `-C` options of [`TargetCodegen`] are taken from the rustflags, later ones win.
+pub(crate) fn target_codegen(
+    project_dir: &Path,
+    target: &str,
+    rustc: &Path,
+) -> Result<TargetCodegen> {
+    let path = project_dir.join(".cargo").join("config.toml");
+    let mut codegen = TargetCodegen::default();
+    if !path.is_file() {
+        return Ok(codegen);
+    }
+    let mut config: CargoConfig = toml::from_str(
+        &fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?,
+    )
+    .with_context(|| format!("parsing {}", path.display()))?;
+    let mut rustflags: Option<Vec<String>> = None;
+    if let Some(table) = config.target.remove(sysroot::target_name(target)) {
+        codegen.linker = table.linker;
+        rustflags = table.rustflags.map(ConfigFlags::into_vec);
+    }
+    let mut platform = None;
+    for (key, table) in config.target {
+        if !key.starts_with("cfg(") {
+            continue;
+        }
+        let cfg = Platform::from_str(&key).with_context(|| format!("parsing {key}"))?;
+        let platform = match &platform {
+            Some(platform) => platform,
+            None => platform.insert(TargetPlatform::new(
+                target,
+                rustc,
+                &TargetCodegen::default(),
+            )?),
+        };
+        if !platform.matches(Some(&cfg)) {
+            continue;
+        }
+        codegen.linker = codegen.linker.or(table.linker);
+        if let Some(flags) = table.rustflags {
+            rustflags.get_or_insert_default().extend(flags.into_vec());
+        }
+    }
+    // paths with a slash are relative to the dir of `.cargo`
+    if let Some(linker) = &mut codegen.linker
+        && linker.contains('/')
+        && Path::new(linker).is_relative()
+    {
+        *linker = project_dir.join(&linker).display().to_string();
+    }
+    let rustflags = rustflags
+        .or_else(|| config.build.rustflags.map(ConfigFlags::into_vec))
+        .unwrap_or_default();
+    let mut rustflags = rustflags.into_iter();
+    while let Some(flag) = rustflags.next() {
+        let option = match flag.as_str() {
+            "-C" | "--codegen" => rustflags.next().unwrap_or_default(),
+            _ => match flag
+                .strip_prefix("--codegen=")
+                .or_else(|| flag.strip_prefix("-C"))
+            {
+                Some(option) => option.to_string(),
+                None => continue,
+            },
+        };
+        let Some((name, value)) = option.split_once('=') else {
+            continue;
+        };
+        match name {
+            "linker" => codegen.linker = Some(value.to_string()),
+            "linker-flavor" => codegen.linker_flavor = Some(value.to_string()),
+            "target-cpu" => codegen.target_cpu = Some(value.to_string()),
+            "target-feature" => codegen.target_features.extend(
+                value
+                    .split(',')
+                    .filter(|feature| !feature.is_empty())
+                    .map(str::to_string),
+            ),
+            _ => {}
+        }
+    }
+    Ok(codegen)
+}
+
+fn inherit<T: Clone>(
+    field: Option<&InheritableField<T>>,
+    workspace: Option<&T>,
+    name: &str,
+) -> Result<Option<T>> {
+    match field {
+        None => Ok(None),
+        Some(InheritableField::Value(value)) => Ok(Some(value.clone())),
+        Some(InheritableField::Inherit(_)) => workspace
+            .cloned()
+            .map(Some)
+            .ok_or_else(|| eyre!("workspace does not define package.{name}")),
+    }
+}
+
+fn glob_regex(pattern: &str) -> Result<Regex> {
+    let mut regex = "^".to_string();
+    for c in pattern.chars() {
+        match c {
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).context("building workspace member pattern")
+}
+
+fn expand_members(root: &Path, patterns: &[String], exclude: &[String]) -> Result<Vec<PathBuf>> {
+    let mut members = Vec::new();
+    for pattern in patterns {
+        let mut dirs = vec![root.to_path_buf()];
+        for part in Path::new(pattern).components() {
+            let part = part
+                .as_os_str()
+                .to_str()
+                .ok_or_eyre("member pattern is not unicode")?;
+            if part.contains(['*', '?']) {
+                let regex = glob_regex(part)?;
+                let mut next = Vec::new();
+                for dir in dirs {
+                    for entry in fs::read_dir(&dir).context("expanding workspace members")? {
+                        let entry = entry.context("reading workspace directory entry")?;
+                        if entry
+                            .file_name()
+                            .to_str()
+                            .is_some_and(|n| regex.is_match(n))
+                            && entry.path().is_dir()
+                        {
+                            next.push(entry.path());
+                        }
+                    }
+                }
+                dirs = next;
+            } else {
+                dirs = dirs.into_iter().map(|dir| dir.join(part)).collect();
+            }
+        }
+        for dir in dirs {
+            let relative = dir.strip_prefix(root).context("member outside workspace")?;
+            if dir.join("Cargo.toml").is_file()
+                && !exclude.iter().any(|e| Path::new(e) == relative)
+                && !members.contains(&dir)
+            {
+                members.push(dir);
+            }
+        }
+    }
+    members.sort();
+    Ok(members)
+}
+
+/// A dependency after applying `workspace = true` inheritance.
+struct InheritedDep {
+    dep: TomlDependency,
+    /// Path of a workspace path dependency, relative paths are resolved against the workspace.
+    path: Option<PathBuf>,
+    features: Vec<String>,
+    optional: Option<bool>,
+}
+
+fn workspace_dep(
+    key: &str,
+    dep: &InheritableDependency,
+    workspace: Option<&TomlWorkspace>,
+    workspace_dir: &Path,
+) -> Result<InheritedDep> {
+    match dep {
+        InheritableDependency::Value(dep) => Ok(InheritedDep {
+            dep: dep.clone(),
+            path: None,
+            features: Vec::new(),
+            optional: None,
+        }),
+        InheritableDependency::Inherit(inherited) => {
+            let dep = workspace
+                .and_then(|w| w.dependencies.as_ref())
+                .and_then(|deps| deps.get(key))
+                .ok_or_else(|| eyre!("workspace does not define dependency {key}"))?;
+            let path = match dep {
+                TomlDependency::Detailed(d) => d.path.as_ref().map(|p| workspace_dir.join(p)),
+                TomlDependency::Simple(_) => None,
+            };
+            Ok(InheritedDep {
+                dep: dep.clone(),
+                path,
+                features: inherited.features.clone().unwrap_or_default(),
+                optional: inherited.optional,
+            })
+        }
+    }
+}
+
+fn declared_deps(
+    loaded: &LoadedManifest,
+    workspace: Option<&TomlWorkspace>,
+    workspace_dir: &Path,
+) -> Result<Vec<DeclaredDep>> {
+    let manifest = &loaded.manifest;
+    let mut tables = vec![
+        (None, DepKind::Normal, manifest.dependencies.as_ref()),
+        (None, DepKind::Build, manifest.build_dependencies()),
+        (None, DepKind::Development, manifest.dev_dependencies()),
+    ];
+    for (platform, table) in manifest.target.iter().flatten() {
+        let platform = Platform::from_str(platform)
+            .with_context(|| format!("parsing target platform {platform}"))?;
+        tables.push((
+            Some(platform.clone()),
+            DepKind::Normal,
+            table.dependencies.as_ref(),
+        ));
+        tables.push((
+            Some(platform.clone()),
+            DepKind::Build,
+            table.build_dependencies(),
+        ));
+        tables.push((
+            Some(platform),
+            DepKind::Development,
+            table.dev_dependencies(),
+        ));
+    }
+    let mut deps = Vec::new();
+    for (platform, kind, table) in tables {
+        for (key, dep) in table.into_iter().flatten() {
+            let InheritedDep {
+                dep,
+                path: inherited_path,
+                features: mut extra_features,
+                optional,
+            } = workspace_dep(key, dep, workspace, workspace_dir)
+                .with_context(|| format!("resolving dependency {key}"))?;
+            let decl = match dep {
+                TomlDependency::Simple(version) => DeclaredDep {
+                    key: key.to_string(),
+                    package: key.to_string(),
+                    req: Some(VersionReq::parse(&version).context("parsing version requirement")?),
+                    kind,
+                    platform: platform.clone(),
+                    optional: optional.unwrap_or(false),
+                    default_features: true,
+                    features: extra_features,
+                    path: inherited_path,
+                },
+                TomlDependency::Detailed(detailed) => {
+                    let mut features = detailed.features.clone().unwrap_or_default();
+                    features.append(&mut extra_features);
+                    DeclaredDep {
+                        key: key.to_string(),
+                        package: detailed
+                            .package
+                            .as_ref()
+                            .map(ToString::to_string)
+                            .unwrap_or_else(|| key.to_string()),
+                        req: detailed
+                            .version
+                            .as_deref()
+                            .map(VersionReq::parse)
+                            .transpose()
+                            .context("parsing version requirement")?,
+                        kind,
+                        platform: platform.clone(),
+                        optional: optional.or(detailed.optional).unwrap_or(false),
+                        default_features: detailed.default_features().unwrap_or(true),
+                        features,
+                        path: inherited_path.or_else(|| {
+                            detailed
+                                .path
+                                .as_ref()
+                                .map(|p| normalize(&loaded.dir.join(p)))
+                        }),
+                    }
+                }
+            };
+            deps.push(decl);
+        }
+    }
+    Ok(deps)
+}
+
+/// Resolves `.` and `..` without touching the file system, like cargo does for path dependencies,
+/// so the path doesn't have to exist (anymore). A leading `..` is kept.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if normalized.file_name().is_some() => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+fn find_readme(dir: &Path, readme: Option<StringOrBool>) -> Option<PathBuf> {
+    match readme {
+        Some(StringOrBool::String(readme)) => Some(PathBuf::from(readme)),
+        Some(StringOrBool::Bool(false)) => None,
+        Some(StringOrBool::Bool(true)) => Some(PathBuf::from("README.md")),
+        None => ["README.md", "README.txt", "README"]
+            .into_iter()
+            .map(PathBuf::from)
+            .find(|readme| dir.join(readme).is_file()),
+    }
+}
+
+fn parse_edition(edition: Option<&str>) -> Result<Edition> {
+    serde_json::from_value(serde_json::Value::String(
+        edition.unwrap_or("2015").to_string(),
+    ))
+    .context("parsing edition")
+}
+
+fn target_edition(target: &TomlTarget, edition: Edition) -> Result<Edition> {
+    match target.edition.as_deref() {
+        Some(edition) => parse_edition(Some(edition)),
+        None => Ok(edition),
+    }
+}
+
+fn target_path(target: &TomlTarget) -> Option<PathBuf> {
+    target.path.as_ref().map(|p| p.0.clone())
+}
+
+impl Crate {
+    fn load(
+        id: String,
+        loaded: &LoadedManifest,
+        base: &Path,
+        workspace: Option<&TomlWorkspace>,
+        workspace_dir: &Path,
+        main_workspace: bool,
+    ) -> Result<(Self, Vec<DeclaredDep>)> {
+        let manifest = &loaded.manifest;
+        let dir = &loaded.dir;
+        let package = manifest
+            .package
+            .as_deref()
+            .or(manifest.project.as_deref())
+            .ok_or_eyre("manifest has no package")?;
+        let inherited = workspace.and_then(|w| w.package.as_ref());
+        let (name, version) = loaded.name_and_version(workspace)?;
+        let edition = parse_edition(
+            inherit(
+                package.edition.as_ref(),
+                inherited.and_then(|p| p.edition.as_ref()),
+                "edition",
+            )?
+            .as_deref(),
+        )?;
+        let relative = dir
+            .strip_prefix(base)
+            .context("package outside of source")?;
+
+        let deps = declared_deps(loaded, workspace, workspace_dir)?;
+        let mut features: BTreeMap<String, Vec<String>> = manifest
+            .features
+            .iter()
+            .flatten()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        let explicit_deps: HashSet<&str> = features
+            .values()
+            .flatten()
+            .filter_map(|v| v.strip_prefix("dep:"))
+            .collect();
+        let implicit: Vec<String> = deps
+            .iter()
+            .filter(|d| d.optional && !explicit_deps.contains(d.key.as_str()))
+            .map(|d| d.key.clone())
+            .collect();
+        for key in implicit {
+            features
+                .entry(key.clone())
+                .or_insert_with(|| vec![format!("dep:{key}")]);
+        }
+
+        let lib_types = |target: &TomlTarget| -> Vec<String> {
+            if target.proc_macro() == Some(true) {
+                vec!["proc-macro".to_string()]
+            } else {
+                target
+                    .crate_types()
+                    .cloned()
+                    .unwrap_or_else(|| vec!["lib".to_string()])
+            }
+        };
+        let lib = match manifest.lib.as_ref() {
+            Some(lib) => Some(lib.clone()),
+            None if package.autolib != Some(false) && dir.join("src/lib.rs").is_file() => {
+                Some(TomlTarget::new())
+            }
+            None => None,
+        };
+        let mut rust_lib = None;
+        let mut c_lib = None;
+        let mut lib_name = None;
+        let mut doctest = false;
+        let mut lib_test = false;
+        if let Some(lib) = lib {
+            let target_name = lib.name.clone().unwrap_or_else(|| make_crate_name(&name));
+            let entrypoint =
+                relative.join(target_path(&lib).unwrap_or_else(|| PathBuf::from("src/lib.rs")));
+            let types = lib_types(&lib);
+            let crate_type = if types.iter().any(|t| t == "lib") {
+                Some("lib")
+            } else if types.iter().any(|t| t == "proc-macro") {
+                Some("proc-macro")
+            } else {
+                None
+            };
+            let job = |crate_type: &str| -> Result<CompileTarget> {
+                Ok(CompileTarget {
+                    crate_name: make_crate_name(&target_name),
+                    deps: Vec::new(),
+                    crate_type: crate_type.to_string(),
+                    target_name: target_name.clone(),
+                    entrypoint: entrypoint.clone(),
+                    edition: target_edition(&lib, edition)?,
+                })
+            };
+            if let Some(crate_type) = crate_type {
+                rust_lib = Some(job(crate_type)?);
+                lib_name = Some(make_crate_name(&target_name));
+                doctest = lib.doctest != Some(false);
+                lib_test = crate_type == "lib" && lib.test != Some(false);
+            } else if types.iter().any(|t| t == "cdylib") {
+                c_lib = Some(job("cdylib")?);
+            }
+        }
+
+        let mut bin_targets: Vec<(String, PathBuf, Edition)> = Vec::new();
+        let mut tested_bins = BTreeSet::new();
+        for bin in manifest.bin.iter().flatten() {
+            let target_name = bin.name.clone().ok_or_eyre("bin target without name")?;
+            let path = match target_path(bin) {
+                Some(path) => path,
+                None => [
+                    PathBuf::from(format!("src/bin/{target_name}.rs")),
+                    PathBuf::from(format!("src/bin/{target_name}/main.rs")),
+                    PathBuf::from("src/main.rs"),
+                ]
+                .into_iter()
+                .find(|p| dir.join(p).is_file())
+                .ok_or_else(|| eyre!("no source for bin {target_name}"))?,
+            };
+            if bin.test != Some(false) {
+                tested_bins.insert(target_name.clone());
+            }
+            bin_targets.push((target_name, path, target_edition(bin, edition)?));
+        }
+        if package.autobins != Some(false) {
+            let mut auto = Vec::new();
+            if dir.join("src/main.rs").is_file() {
+                auto.push((name.clone(), PathBuf::from("src/main.rs")));
+            }
+            if let Ok(entries) = fs::read_dir(dir.join("src/bin")) {
+                for entry in entries {
+                    let entry = entry.context("reading src/bin")?;
+                    let path = entry.path();
+                    let file_name = entry.file_name();
+                    let file_name = file_name.to_str().ok_or_eyre("bin name is not unicode")?;
+                    if let Some(bin) = file_name.strip_suffix(".rs")
+                        && path.is_file()
+                    {
+                        auto.push((bin.to_string(), PathBuf::from("src/bin").join(file_name)));
+                    } else if path.join("main.rs").is_file() {
+                        auto.push((
+                            file_name.to_string(),
+                            PathBuf::from("src/bin").join(file_name).join("main.rs"),
+                        ));
+                    }
+                }
+            }
+            auto.sort();
+            for (target_name, path) in auto {
+                if !bin_targets
+                    .iter()
+                    .any(|(n, p, _)| *n == target_name || *p == path)
+                {
+                    tested_bins.insert(target_name.clone());
+                    bin_targets.push((target_name, path, edition));
+                }
+            }
+        }
+        let bins = bin_targets
+            .into_iter()
+            .map(|(target_name, path, edition)| CompileTarget {
+                crate_name: make_crate_name(&target_name),
+                deps: Vec::new(),
+                crate_type: "bin".to_string(),
+                target_name,
+                entrypoint: relative.join(path),
+                edition,
+            })
+            .collect();
+
+        let mut test_targets: Vec<(String, PathBuf, Edition)> = Vec::new();
+        let mut explicit_tests = Vec::new();
+        for test in manifest.test.iter().flatten() {
+            let target_name = test.name.clone().ok_or_eyre("test target without name")?;
+            let path = match target_path(test) {
+                Some(path) => path,
+                None => [
+                    PathBuf::from(format!("tests/{target_name}.rs")),
+                    PathBuf::from(format!("tests/{target_name}/main.rs")),
+                ]
+                .into_iter()
+                .find(|p| dir.join(p).is_file())
+                .ok_or_else(|| eyre!("no source for test {target_name}"))?,
+            };
+            explicit_tests.push((target_name.clone(), path.clone()));
+            if test.test != Some(false) {
+                test_targets.push((target_name, path, target_edition(test, edition)?));
+            }
+        }
+        if package.autotests != Some(false)
+            && let Ok(entries) = fs::read_dir(dir.join("tests"))
+        {
+            let mut auto = Vec::new();
+            for entry in entries {
+                let entry = entry.context("reading tests")?;
+                let path = entry.path();
+                let file_name = entry.file_name();
+                let file_name = file_name.to_str().ok_or_eyre("test name is not unicode")?;
+                if let Some(test) = file_name.strip_suffix(".rs")
+                    && path.is_file()
+                {
+                    auto.push((test.to_string(), PathBuf::from("tests").join(file_name)));
+                } else if path.join("main.rs").is_file() {
+                    auto.push((
+                        file_name.to_string(),
+                        PathBuf::from("tests").join(file_name).join("main.rs"),
+                    ));
+                }
+            }
+            auto.sort();
+            for (target_name, path) in auto {
+                if !explicit_tests
+                    .iter()
+                    .any(|(n, p)| *n == target_name || *p == path)
+                {
+                    test_targets.push((target_name, path, edition));
+                }
+            }
+        }
+        let tests = test_targets
+            .into_iter()
+            .map(|(target_name, path, edition)| CompileTarget {
+                crate_name: make_crate_name(&target_name),
+                deps: Vec::new(),
+                crate_type: "test".to_string(),
+                target_name,
+                entrypoint: relative.join(path),
+                edition,
+            })
+            .collect();
+
+        let build_script = match package.build.as_ref() {
+            Some(StringOrBool::Bool(false)) => None,
+            Some(StringOrBool::String(path)) => Some(PathBuf::from(path)),
+            Some(StringOrBool::Bool(true)) => Some(PathBuf::from("build.rs")),
+            None => Some(PathBuf::from("build.rs")).filter(|p| dir.join(p).is_file()),
+        }
+        .map(|path| (relative.join(path), edition));
+
+        let metadata = PackageMetadata {
+            manifest_path: relative.join("Cargo.toml"),
+            version: version.to_string(),
+            authors: inherit(
+                package.authors.as_ref(),
+                inherited.and_then(|p| p.authors.as_ref()),
+                "authors",
+            )?
+            .filter(|a| !a.is_empty()),
+            pname: name,
+            description: inherit(
+                package.description.as_ref(),
+                inherited.and_then(|p| p.description.as_ref()),
+                "description",
+            )?,
+            homepage: inherit(
+                package.homepage.as_ref(),
+                inherited.and_then(|p| p.homepage.as_ref()),
+                "homepage",
+            )?,
+            repository: inherit(
+                package.repository.as_ref(),
+                inherited.and_then(|p| p.repository.as_ref()),
+                "repository",
+            )?,
+            license: inherit(
+                package.license.as_ref(),
+                inherited.and_then(|p| p.license.as_ref()),
+                "license",
+            )?,
+            license_file: inherit(
+                package.license_file.as_ref(),
+                inherited.and_then(|p| p.license_file.as_ref()),
+                "license-file",
+            )?
+            .map(PathBuf::from),
+            rust_version: inherit(
+                package.rust_version.as_ref(),
+                inherited.and_then(|p| p.rust_version.as_ref()),
+                "rust-version",
+            )?
+            .map(|v| full_version(&v.to_string())),
+            readme: find_readme(
+                dir,
+                inherit(
+                    package.readme.as_ref(),
+                    inherited.and_then(|p| p.readme.as_ref()),
+                    "readme",
+                )?,
+            ),
+            all_features: features.keys().cloned().collect(),
+            edition,
+            main_workspace,
+            links: package.links.clone(),
+            sysroot_crate: false,
+        };
+        Ok((
+            Self {
+                id,
+                lib_name,
+                metadata,
+                features,
+                deps: Vec::new(),
+                build_script,
+                rust_lib,
+                c_lib,
+                bins,
+                doctest,
+                lib_test,
+                tested_bins,
+                tests,
+            },
+            deps,
+        ))
+    }
+}
+
+/// The id cargo gives the path package in `dir`, which leaves out the name if the directory
+/// is called like the package.
+fn path_pkg_id(dir: &Path, relative: &Path, name: &str, version: &Version) -> String {
+    let url = if relative.as_os_str().is_empty() {
+        "source".to_string()
+    } else {
+        Path::new("source").join(relative).display().to_string()
+    };
+    if dir.file_name().is_some_and(|d| d == name) {
+        format!("path+file://{url}#{version}")
+    } else {
+        format!("path+file://{url}#{name}@{version}")
+    }
+}
+
+/// Pads a `rust-version` like `1.70` to the full version reported by cargo.
+fn full_version(version: &str) -> String {
+    let parts = version.split('.').count();
+    let mut version = version.to_string();
+    for _ in parts..3 {
+        version.push_str(".0");
+    }
+    version
+}
+
+fn parse_lock_dep(dep: &str) -> (&str, Option<&str>, Option<&str>) {
+    let mut parts = dep.splitn(3, ' ');
+    let name = parts.next().unwrap_or_default();
+    let version = parts.next();
+    let source = parts
+        .next()
+        .map(|s| s.trim_start_matches('(').trim_end_matches(')'));
+    (name, version, source)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Work {
+    Package(usize),
+    Value(usize, String),
+    Feature(usize, String),
+    Dep(usize, usize),
+    DepFeature(usize, usize, String),
+}
+
+/// A package as seen by the feature resolution, which [`crate::metadata::resolve`] builds from
+/// the `cargo metadata` graph.
+pub(crate) struct FeatureNode {
+    pub(crate) name: String,
+    pub(crate) features: BTreeMap<String, Vec<String>>,
+    pub(crate) deps: Vec<FeatureDep>,
+}
+
+/// A declared dependency of a [`FeatureNode`] on the node at index `package`.
+pub(crate) struct FeatureDep {
+    pub(crate) key: String,
+    pub(crate) kind: DepKind,
+    pub(crate) platform: Option<Platform>,
+    pub(crate) optional: bool,
+    pub(crate) default_features: bool,
+    pub(crate) features: Vec<String>,
+    pub(crate) package: usize,
+}
+
+impl Crate {
+    fn feature_node(&self) -> FeatureNode {
+        FeatureNode {
+            name: self.metadata.pname.clone(),
+            features: self.features.clone(),
+            deps: self
+                .deps
+                .iter()
+                .map(|dep| FeatureDep {
+                    key: dep.decl.key.clone(),
+                    kind: dep.decl.kind,
+                    platform: dep.decl.platform.clone(),
+                    optional: dep.decl.optional,
+                    default_features: dep.decl.default_features,
+                    features: dep.decl.features.clone(),
+                    package: dep.package,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Unifies the features of the packages reachable from the workspace members on one target,
+/// collecting the active packages and the indices of their activated deps.
+pub(crate) struct FeatureResolver<'c> {
+    nodes: &'c [FeatureNode],
+    members: BTreeSet<usize>,
+    platform: &'c TargetPlatform<'c>,
+    pub(crate) active: BTreeSet<usize>,
+    pub(crate) features: HashMap<usize, BTreeSet<String>>,
+    pub(crate) deps: HashMap<usize, BTreeSet<usize>>,
+    queue: Vec<Work>,
+}
+
+impl<'c> FeatureResolver<'c> {
+    /// Starts from the workspace `members` by name with the feature `selection` applied.
+    ///
+    /// Like cargo, a selected `dep/feature` applies to the member named `dep` or the members
+    /// depending on `dep`, and every selected feature has to apply to at least one member.
+    pub(crate) fn new(
+        nodes: &'c [FeatureNode],
+        members: &BTreeMap<String, usize>,
+        selection: &FeatureSelection,
+        platform: &'c TargetPlatform<'c>,
+    ) -> Result<Self> {
+        let mut queue = Vec::new();
+        for member in members.values() {
+            queue.push(Work::Package(*member));
+            if !selection.no_default_features && nodes[*member].features.contains_key("default") {
+                queue.push(Work::Feature(*member, "default".to_string()));
+            }
+        }
+        let mut unknown = Vec::new();
+        for feature in &selection.features {
+            let mut found = false;
+            for (name, member) in members {
+                let node = &nodes[*member];
+                let value = match feature.split_once('/') {
+                    Some((package, value)) if package == name => value,
+                    Some((dep, _)) if node.deps.iter().any(|d| d.key == dep) => feature.as_str(),
+                    None if node.features.contains_key(feature) => feature.as_str(),
+                    _ => continue,
+                };
+                queue.push(Work::Value(*member, value.to_string()));
+                found = true;
+            }
+            if !found {
+                unknown.push(feature.as_str());
+            }
+        }
+        if !unknown.is_empty() {
+            bail!(
+                "none of the selected packages contains these features: {}",
+                unknown.join(", ")
+            );
+        }
+        Ok(Self {
+            nodes,
+            members: members.values().copied().collect(),
+            platform,
+            active: BTreeSet::new(),
+            features: HashMap::new(),
+            deps: HashMap::new(),
+            queue,
+        })
+    }
+
+    fn dep_indices(&self, package: usize, key: &str) -> Vec<usize> {
+        self.nodes[package]
+            .deps
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| d.key == key)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Whether the dep at `index` of `package` is used on the platform at all.
+    fn usable(&self, package: usize, index: usize) -> bool {
+        let dep = &self.nodes[package].deps[index];
+        // dev-dependencies are only needed for the doctests and tests of workspace members
+        (dep.kind != DepKind::Development || self.members.contains(&package))
+            && self.platform.matches(dep.platform.as_ref())
+    }
+
+    pub(crate) fn resolve(mut self) -> Result<Self> {
+        while let Some(work) = self.queue.pop() {
+            match work {
+                Work::Package(package) => {
+                    if self.active.insert(package) {
+                        for (index, dep) in self.nodes[package].deps.iter().enumerate() {
+                            if !dep.optional {
+                                self.queue.push(Work::Dep(package, index));
+                            }
+                        }
+                    }
+                }
+                Work::Value(package, value) => {
+                    if let Some(dep) = value.strip_prefix("dep:") {
+                        for index in self.dep_indices(package, dep) {
+                            self.queue.push(Work::Dep(package, index));
+                        }
+                    } else if let Some((dep, feature)) = value.split_once('/') {
+                        // like cargo metadata, a weak `dep?/feature` activates the optional dep
+                        // as well, it just doesn't enable the implicit feature named after it
+                        let (dep, weak) = match dep.strip_suffix('?') {
+                            Some(dep) => (dep, true),
+                            None => (dep, false),
+                        };
+                        if !weak && self.nodes[package].features.contains_key(dep) {
+                            self.queue.push(Work::Feature(package, dep.to_string()));
+                        }
+                        for index in self.dep_indices(package, dep) {
+                            self.queue.push(Work::Dep(package, index));
+                            self.queue
+                                .push(Work::DepFeature(package, index, feature.to_string()));
+                        }
+                    } else {
+                        self.queue.push(Work::Feature(package, value));
+                    }
+                }
+                Work::Feature(package, feature) => {
+                    let node = &self.nodes[package];
+                    let values = node
+                        .features
+                        .get(&feature)
+                        .ok_or_else(|| eyre!("package {} has no feature {feature}", node.name))?;
+                    if self
+                        .features
+                        .entry(package)
+                        .or_default()
+                        .insert(feature.clone())
+                    {
+                        for value in values {
+                            self.queue.push(Work::Value(package, value.clone()));
+                        }
+                    }
+                }
+                Work::Dep(package, index) => {
+                    if !self.usable(package, index) {
+                        continue;
+                    }
+                    if self.deps.entry(package).or_default().insert(index) {
+                        let dep = &self.nodes[package].deps[index];
+                        self.queue.push(Work::Package(dep.package));
+                        for feature in &dep.features {
+                            self.queue.push(Work::Value(dep.package, feature.clone()));
+                        }
+                        if dep.default_features
+                            && self.nodes[dep.package].features.contains_key("default")
+                        {
+                            self.queue
+                                .push(Work::Feature(dep.package, "default".to_string()));
+                        }
+                    }
+                }
+                Work::DepFeature(package, index, feature) => {
+                    if self.usable(package, index) {
+                        let dep = &self.nodes[package].deps[index];
+                        self.queue.push(Work::Value(dep.package, feature));
+                    }
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    /// The plan of `package`, the deps of its [`FeatureNode`] are in the order of its [`Crate`].
+    fn resolved_package(&self, crates: &[Crate], package: usize) -> Result<ResolvedPackage> {
+        let krate = &crates[package];
+        let mut deps: Vec<Dep> = Vec::new();
+        let mut build_deps: Vec<Dep> = Vec::new();
+        let mut dev_deps: Vec<Dep> = Vec::new();
+        // lock file packages are ordered by name and version, which is the order cargo uses
+        let mut indices: Vec<usize> = self
+            .deps
+            .get(&package)
+            .into_iter()
+            .flatten()
+            .copied()
+            .collect();
+        indices.sort_by_key(|index| (krate.deps[*index].package, *index));
+        for index in indices {
+            let dep = &krate.deps[index];
+            let target = &crates[dep.package];
+            let name = if dep.decl.key != dep.decl.package {
+                make_crate_name(&dep.decl.key)
+            } else {
+                match target.lib_name.as_ref() {
+                    Some(name) => name.clone(),
+                    None => continue,
+                }
+            };
+            let d = Dep {
+                name,
+                pkg: target.id.clone(),
+            };
+            let list = match dep.decl.kind {
+                DepKind::Normal => &mut deps,
+                DepKind::Build => &mut build_deps,
+                DepKind::Development => &mut dev_deps,
+            };
+            if !list.iter().any(|e| e.name == d.name && e.pkg == d.pkg) {
+                list.push(d);
+            }
+        }
+        let job = |target: &CompileTarget, deps: &Vec<Dep>| CompileTarget {
+            deps: deps.clone(),
+            target_name: target.target_name.clone(),
+            crate_name: target.crate_name.clone(),
+            crate_type: target.crate_type.clone(),
+            entrypoint: target.entrypoint.clone(),
+            edition: target.edition,
+        };
+        let rust_lib = krate.rust_lib.as_ref().map(|lib| job(lib, &deps));
+        let mut bins: Vec<CompileTarget> = krate.bins.iter().map(|bin| job(bin, &deps)).collect();
+        let mut doctest = None;
+        let mut lib_dep = None;
+        let member = self.members.contains(&package);
+        if let Some(lib) = rust_lib.as_ref() {
+            let dep = Dep {
+                name: lib.crate_name.clone(),
+                pkg: krate.id.clone(),
+            };
+            for bin in &mut bins {
+                bin.deps.push(dep.clone());
+            }
+            if krate.doctest && member {
+                doctest = Some(doctest_job(lib, &dev_deps, dep.clone()));
+            }
+            lib_dep = Some(dep);
+        }
+        let mut tests = Vec::new();
+        if member {
+            let unit_tests = rust_lib
+                .iter()
+                .filter(|_| krate.lib_test)
+                .chain(
+                    bins.iter()
+                        .filter(|bin| krate.tested_bins.contains(&bin.target_name)),
+                )
+                .map(|target| test_job(target, &dev_deps, None));
+            let integration_tests = krate
+                .tests
+                .iter()
+                .map(|test| test_job(&job(test, &deps), &dev_deps, lib_dep.clone()));
+            tests = unit_tests.chain(integration_tests).collect();
+        }
+        Ok(ResolvedPackage {
+            features: self
+                .features
+                .get(&package)
+                .into_iter()
+                .flatten()
+                .cloned()
+                .collect(),
+            build_script: krate.build_script.as_ref().map(|(entrypoint, edition)| {
+                BuildScriptTarget {
+                    main_deps: deps.clone(),
+                    main_crate_name: make_crate_name(&krate.metadata.pname),
+                    target_name: "build_script".to_string(),
+                    crate_name: "build_script".to_string(),
+                    deps: build_deps.clone(),
+                    crate_type: "bin".to_string(),
+                    entrypoint: entrypoint.clone(),
+                    edition: *edition,
+                }
+            }),
+            rust_lib,
+            c_lib: krate.c_lib.as_ref().map(|lib| job(lib, &deps)),
+            bins: if bins.is_empty() { None } else { Some(bins) },
+            doctest,
+            tests,
+        })
+    }
+}
+
+/// Resolves the workspace in `project_dir` from its `Cargo.lock` and the vendored manifests,
+/// without invoking cargo.
+///
+/// Produces the same document as [`crate::metadata::resolve`]. Features are unified per target,
+/// including the dev-dependencies of workspace members. Like `cargo metadata`, an optional
+/// dependency named by a weak `dep?/feature` is activated together with that feature.
+pub fn resolve(
+    project_dir: &Path,
+    vendor_dir: &Path,
+    targets: &[String],
+    features: &FeatureSelection,
+    rustc: &Path,
+) -> Result<Metadata> {
+    let root = read_manifest(project_dir).context("reading workspace manifest")?;
+    let workspace = root.workspace.as_ref();
+    let lockfile: Lockfile = toml::from_str(
+        &fs::read_to_string(project_dir.join("Cargo.lock")).context("reading lock file")?,
+    )
+    .context("parsing lock file")?;
+
+    let exclude = workspace
+        .and_then(|w| w.exclude.as_deref())
+        .unwrap_or_default();
+    let mut member_dirs = match workspace {
+        Some(w) => expand_members(
+            project_dir,
+            w.members.as_deref().unwrap_or_default(),
+            exclude,
+        )?,
+        None => Vec::new(),
+    };
+    if root.package.is_some() || root.project.is_some() {
+        member_dirs.retain(|d| d != project_dir);
+        member_dirs.insert(0, project_dir.to_path_buf());
+    }
+
+    let mut path_manifests: Vec<(LoadedManifest, bool)> = Vec::new();
+    let mut queue: Vec<(PathBuf, bool)> = member_dirs.iter().map(|d| (d.clone(), true)).collect();
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    while let Some((dir, member)) = queue.pop() {
+        if !seen.insert(dir.clone()) {
+            continue;
+        }
+        let manifest = read_manifest(&dir)?;
+        let loaded = LoadedManifest { dir, manifest };
+        for dep in declared_deps(&loaded, workspace, project_dir)? {
+            // path dependencies inside the workspace are implicit members unless excluded
+            if let Some(path) = dep.path {
+                let member = path
+                    .strip_prefix(project_dir)
+                    .is_ok_and(|relative| !exclude.iter().any(|e| relative.starts_with(e)))
+                    || member_dirs.contains(&path);
+                queue.push((path, member));
+            }
+        }
+        path_manifests.push((loaded, member));
+    }
+
+    let mut crates: Vec<Crate> = Vec::new();
+    let mut declared: Vec<Vec<DeclaredDep>> = Vec::new();
+    // the directories of path packages, which their dependents declare
+    let mut dirs: Vec<Option<PathBuf>> = Vec::new();
+    let mut members: BTreeMap<String, usize> = BTreeMap::new();
+    let mut main_package = None;
+    for package in &lockfile.package {
+        let (crate_, decls) = match package.source.as_deref() {
+            None => {
+                let mut matching = Vec::new();
+                for (loaded, member) in &path_manifests {
+                    let (name, version) = loaded.name_and_version(workspace)?;
+                    if name == package.name && version == package.version {
+                        matching.push((loaded, member));
+                    }
+                }
+                let (loaded, member) = match matching[..] {
+                    [found] => found,
+                    [] => {
+                        bail!(
+                            "no path package {} {} in the workspace",
+                            package.name,
+                            package.version
+                        );
+                    }
+                    _ => {
+                        bail!(
+                            "more than one path package {} {} in the workspace",
+                            package.name,
+                            package.version
+                        );
+                    }
+                };
+                let relative = loaded
+                    .dir
+                    .strip_prefix(project_dir)
+                    .context("path dependency outside of the project")?;
+                let (crate_, decls) = Crate::load(
+                    path_pkg_id(&loaded.dir, relative, &package.name, &package.version),
+                    loaded,
+                    project_dir,
+                    workspace,
+                    project_dir,
+                    true,
+                )
+                .with_context(|| format!("loading package {}", package.name))?;
+                if *member {
+                    members.insert(package.name.clone(), crates.len());
+                    if loaded.dir == project_dir {
+                        main_package = Some(crate_.id.clone());
+                    }
+                }
+                dirs.push(Some(loaded.dir.clone()));
+                (crate_, decls)
+            }
+            Some(source) if source.starts_with("registry+") || source.starts_with("sparse+") => {
+                let versioned = vendor_dir.join(format!("{}-{}", package.name, package.version));
+                let dir = if versioned.is_dir() {
+                    versioned
+                } else {
+                    vendor_dir.join(&package.name)
+                };
+                let loaded = LoadedManifest {
+                    manifest: read_manifest(&dir)?,
+                    dir,
+                };
+                dirs.push(None);
+                Crate::load(
+                    format!("{source}#{}@{}", package.name, package.version),
+                    &loaded,
+                    &loaded.dir,
+                    None,
+                    &loaded.dir,
+                    false,
+                )
+                .with_context(|| format!("loading package {}-{}", package.name, package.version))?
+            }
+            Some(source) => {
+                bail!("unsupported source {source} of {}", package.name);
+            }
+        };
+        crates.push(crate_);
+        declared.push(decls);
+    }
+
+    for (index, (package, decls)) in lockfile.package.iter().zip(declared).enumerate() {
+        let locked: Vec<usize> = package
+            .dependencies
+            .iter()
+            .map(|dep| {
+                let (name, version, source) = parse_lock_dep(dep);
+                lockfile
+                    .package
+                    .iter()
+                    .position(|p| {
+                        p.name == name
+                            && version.is_none_or(|v| p.version.to_string() == v)
+                            && source.is_none_or(|s| p.source.as_deref() == Some(s))
+                    })
+                    .ok_or_else(|| eyre!("lock file dependency {dep} not found"))
+            })
+            .collect::<Result<_>>()?;
+        for decl in decls {
+            let candidates: Vec<usize> = locked
+                .iter()
+                .copied()
+                .filter(|l| {
+                    lockfile.package[*l].name == decl.package
+                        && decl
+                            .path
+                            .as_ref()
+                            .is_none_or(|path| dirs[*l].as_ref() == Some(path))
+                })
+                .collect();
+            let resolved = match candidates.as_slice() {
+                [single] => Some(*single),
+                _ => candidates.iter().copied().find(|c| {
+                    decl.req
+                        .as_ref()
+                        .is_none_or(|req| req.matches(&lockfile.package[*c].version))
+                }),
+            };
+            match resolved {
+                Some(resolved) => crates[index].deps.push(ResolvedDep {
+                    decl,
+                    package: resolved,
+                }),
+                None if decl.optional || decl.kind == DepKind::Development => {}
+                None => {
+                    bail!(
+                        "dependency {} of {} is not in the lock file",
+                        decl.package,
+                        package.name
+                    );
+                }
+            }
+        }
+    }
+
+    let nodes: Vec<FeatureNode> = crates.iter().map(Crate::feature_node).collect();
+    let mut target_outputs = BTreeMap::new();
+    let mut used = BTreeSet::new();
+    for target in targets {
+        let codegen = target_codegen(project_dir, target, rustc)
+            .with_context(|| format!("reading the config of target {target}"))?;
+        let platform = TargetPlatform::new(target, rustc, &codegen)
+            .with_context(|| format!("getting cfgs for target {target}"))?;
+        let resolver = FeatureResolver::new(&nodes, &members, features, &platform)
+            .and_then(FeatureResolver::resolve)
+            .with_context(|| format!("resolving features for target {target}"))?;
+        let mut packages = BTreeMap::new();
+        used.extend(resolver.active.iter().copied());
+        for package in &resolver.active {
+            packages.insert(
+                crates[*package].id.clone(),
+                resolver.resolved_package(&crates, *package)?,
+            );
+        }
+        target_outputs.insert(
+            target.clone(),
+            TargetMetadata {
+                packages,
+                sysroot: Vec::new(),
+                codegen,
+            },
+        );
+    }
+
+    Ok(Metadata {
+        schema_version: SchemaVersion::default(),
+        workspace: members
+            .iter()
+            .map(|(name, index)| (name.clone(), crates[*index].id.clone()))
+            .collect(),
+        main_package,
+        targets: target_outputs,
+        packages: crates
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| used.contains(index))
+            .map(|(_, krate)| (krate.id, krate.metadata))
+            .collect(),
+        profiles: profiles(&root)?,
+    })
+}
+
+pub fn run(
+    project_dir: PathBuf,
+    vendor_dir: PathBuf,
+    out: PathBuf,
+    targets: Vec<String>,
+    build_std: Vec<String>,
+) -> Result<()> {
+    let features = FeatureSelection::from_env();
+    let targets = targets
+        .into_iter()
+        .map(sysroot::resolve_target)
+        .collect::<Result<Vec<_>>>()?;
+    let build_std = build_std
+        .into_iter()
+        .map(sysroot::resolve_target)
+        .collect::<Result<Vec<_>>>()?;
+    let rustc = env::var_os("RUSTC")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("rustc"));
+    let mut metadata = resolve(&project_dir, &vendor_dir, &targets, &features, &rustc)?;
+    sysroot::add_packages(&mut metadata, &rustc, &build_std)?;
+    fs::write(
+        out,
+        serde_json::to_string(&metadata).context("serializing output")?,
+    )
+    .context("writing output")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The documents of both resolvers for the workspace `tests/fixtures/{name}`.
+    fn resolve_fixture(
+        name: &str,
+        target: &str,
+        features: &FeatureSelection,
+    ) -> (serde_json::Value, serde_json::Value) {
+        let project_dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name);
+        let vendor_dir = project_dir.join("vendor");
+        let targets = [target.to_string()];
+        let rustc = Path::new("rustc");
+        let cargo =
+            crate::metadata::resolve(&project_dir, &vendor_dir, &targets, features, rustc).unwrap();
+        let native = resolve(&project_dir, &vendor_dir, &targets, features, rustc).unwrap();
+        (
+            serde_json::to_value(cargo).unwrap(),
+            serde_json::to_value(native).unwrap(),
+        )
+    }
+
+    #[test]
+    fn weak_dependency_features_activate_the_dependency() {
+        let target = "x86_64-unknown-linux-gnu";
+        let (cargo, native) = resolve_fixture("features", target, &FeatureSelection::default());
+        assert_eq!(native, cargo);
+        let packages = &native["targets"][target]["packages"];
+        let app = &packages["path+file://source/app#0.1.0"];
+        assert_eq!(
+            app["rustLib"]["deps"],
+            serde_json::json!([
+                { "name": "opt", "pkg": "path+file://source/opt#0.1.0" },
+                { "name": "plat", "pkg": "path+file://source/plat#0.1.0" },
+            ])
+        );
+        assert_eq!(
+            packages["path+file://source/opt#0.1.0"]["features"],
+            serde_json::json!(["std"])
+        );
+    }
+
+    #[test]
+    fn selected_dependency_features_apply_to_the_dependents() {
+        let target = "x86_64-unknown-linux-gnu";
+        let selection = FeatureSelection {
+            features: vec!["plat/win".to_string()],
+            no_default_features: true,
+        };
+        let (cargo, native) = resolve_fixture("features", target, &selection);
+        assert_eq!(native, cargo);
+        let packages = &native["targets"][target]["packages"];
+        assert_eq!(
+            packages["path+file://source/plat#0.1.0"]["features"],
+            serde_json::json!(["win"])
+        );
+        assert_eq!(
+            packages["path+file://source/app#0.1.0"]["features"],
+            serde_json::json!([])
+        );
+    }
+
+    #[test]
+    fn unknown_selected_features_are_rejected() {
+        let project_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/features");
+        let selection = FeatureSelection {
+            features: vec![
+                "std".to_string(),
+                "nope".to_string(),
+                "nope/std".to_string(),
+            ],
+            no_default_features: false,
+        };
+        let error = resolve(
+            &project_dir,
+            &project_dir.join("vendor"),
+            &["x86_64-unknown-linux-gnu".to_string()],
+            &selection,
+            Path::new("rustc"),
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "resolving features for target x86_64-unknown-linux-gnu: \
+             none of the selected packages contains these features: nope, nope/std"
+        );
+    }
+
+    #[test]
+    fn path_packages_are_matched_by_name_and_version() {
+        let target = "x86_64-unknown-linux-gnu";
+        let (cargo, native) =
+            resolve_fixture("path-versions", target, &FeatureSelection::default());
+        assert_eq!(native, cargo);
+        let packages = native["packages"].as_object().unwrap();
+        let versions: Vec<_> = packages
+            .values()
+            .filter(|package| package["pname"] == "dep")
+            .map(|package| package["version"].as_str().unwrap())
+            .collect();
+        assert_eq!(versions, ["1.0.0", "2.0.0"]);
+    }
+
+    #[test]
+    fn target_specific_dependency_features_stay_on_their_target() {
+        for (target, features) in [
+            ("x86_64-unknown-linux-gnu", serde_json::json!([])),
+            ("x86_64-pc-windows-msvc", serde_json::json!(["win"])),
+        ] {
+            let (cargo, native) = resolve_fixture("features", target, &FeatureSelection::default());
+            assert_eq!(native, cargo);
+            assert_eq!(
+                native["targets"][target]["packages"]["path+file://source/plat#0.1.0"]["features"],
+                features,
+                "features of plat on {target}"
+            );
+        }
+    }
+
+    #[test]
+    fn normalize_keeps_leading_parent_dirs() {
+        assert_eq!(normalize(Path::new("/a/./b/../c")), Path::new("/a/c"));
+        assert_eq!(normalize(Path::new("a/../../b")), Path::new("../b"));
+        assert_eq!(normalize(Path::new("../../a/..")), Path::new("../.."));
+    }
+
+    #[test]
+    fn test_and_bench_profiles_inherit_by_default() {
+        let manifest: TomlManifest = toml::from_str(
+            r#"
+            [package]
+            name = "a"
+            version = "0.1.0"
+
+            [profile.dev]
+            panic = "abort"
+            debug = "limited"
+
+            [profile.release]
+            split-debuginfo = "packed"
+
+            [profile.test]
+            opt-level = 1
+
+            [profile.bench]
+            debug = "line-tables-only"
+            "#,
+        )
+        .unwrap();
+        let profiles = profiles(&manifest).unwrap();
+        let test = &profiles["test"];
+        assert_eq!(test.panic.as_deref(), Some("abort"));
+        assert_eq!(test.debug.as_deref(), Some("limited"));
+        let bench = &profiles["bench"];
+        assert_eq!(bench.debug.as_deref(), Some("line-tables-only"));
+        assert_eq!(bench.split_debuginfo.as_deref(), Some("packed"));
+        assert_eq!(bench.panic, None);
+    }
+
+    #[test]
+    fn custom_profiles_have_to_inherit() {
+        let manifest: TomlManifest = toml::from_str(
+            r#"
+            [package]
+            name = "a"
+            version = "0.1.0"
+
+            [profile.small]
+            opt-level = "z"
+            "#,
+        )
+        .unwrap();
+        let error = profiles(&manifest).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "profile small has to inherit from another profile"
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{Context, OptionExt, Result, bail};
+use elf::{
+    ElfBytes,
+    abi::{DT_NEEDED, DT_RPATH, DT_RUNPATH, PT_INTERP},
+    endian::AnyEndian,
+};
+
+/// Directories searched by the dynamic loader after the runpath.
+const DEFAULT_DIRS: [&str; 4] = ["/lib", "/usr/lib", "/lib64", "/usr/lib64"];
+
+/// The native search directories of `lib_path`, in rustc's `-L [KIND=]PATH` syntax.
+fn native_dirs(lib_path: &BTreeSet<String>) -> Vec<PathBuf> {
+    lib_path
+        .iter()
+        .filter_map(|entry| match entry.split_once('=') {
+            Some(("native" | "all", path)) => Some(PathBuf::from(path)),
+            Some(("dependency" | "crate" | "framework", _)) => None,
+            _ => Some(PathBuf::from(entry)),
+        })
+        .collect()
+}
+
+/// The file name of the shared library a `-l [KIND[:MODIFIERS]=]NAME[:RENAME]` value links
+/// against, if it is linked dynamically. `prefix` and `suffix` are the target's for shared
+/// libraries, which the linker adds unless the name is `+verbatim`.
+fn dylib_file_name(link_lib: &str, prefix: &str, suffix: &str) -> Option<String> {
+    let (kind, name) = link_lib.split_once('=').unwrap_or(("", link_lib));
+    let (kind, modifiers) = kind.split_once(':').unwrap_or((kind, ""));
+    if !matches!(kind, "" | "dylib") {
+        return None;
+    }
+    let name = name.split(':').next()?;
+    if modifiers.split(',').any(|modifier| modifier == "+verbatim") {
+        Some(name.to_string())
+    } else {
+        Some(format!("{prefix}{name}{suffix}"))
+    }
+}
+
+/// The directories of `lib_path` the linker takes the shared libraries of `link_libs` from,
+/// which is the runpath an artifact linking them needs. `affixes` are the prefix and suffix of
+/// shared libraries on the target, as rustc names cdylibs.
+pub fn rpath<'a>(
+    lib_path: &BTreeSet<String>,
+    link_libs: impl IntoIterator<Item = &'a str>,
+    (prefix, suffix): (&str, &str),
+) -> Vec<PathBuf> {
+    let dirs = native_dirs(lib_path);
+    let mut rpath = Vec::new();
+    for file_name in link_libs
+        .into_iter()
+        .filter_map(|link_lib| dylib_file_name(link_lib, prefix, suffix))
+    {
+        if let Some(dir) = dirs.iter().find(|dir| dir.join(&file_name).exists())
+            && !rpath.contains(dir)
+        {
+            rpath.push(dir.clone());
+        }
+    }
+    rpath
+}
+
+/// The directories listed in `ld.so.conf` and the files it includes.
+fn ld_so_conf(path: &Path, dirs: &mut Vec<PathBuf>) {
+    let Ok(content) = fs::read_to_string(path) else {
+        return;
+    };
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if let Some(pattern) = line.strip_prefix("include") {
+            // only the `dir/*.conf` form distributions use
+            let pattern = Path::new(pattern.trim());
+            let suffix = pattern
+                .file_name()
+                .map(|name| name.to_string_lossy().trim_start_matches('*').to_string())
+                .unwrap_or_default();
+            let dir = pattern.parent().unwrap_or(Path::new("/etc"));
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            let mut included: Vec<_> = entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.to_string_lossy().ends_with(&suffix))
+                .collect();
+            included.sort();
+            for path in included {
+                ld_so_conf(&path, dirs);
+            }
+        } else if !line.is_empty() {
+            dirs.push(PathBuf::from(line));
+        }
+    }
+}
+
+/// Fails with a report if a `DT_NEEDED` entry of the ELF file at `path` is found neither in its
+/// runpath nor in the directories the dynamic loader searches by default. Other files are skipped.
+pub fn check_needed(path: &Path) -> Result<()> {
+    let data = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    // wasm modules and PE executables have no DT_NEEDED entries to check
+    let Ok(file) = ElfBytes::<AnyEndian>::minimal_parse(&data) else {
+        return Ok(());
+    };
+    let Some(dynamic_header) = file
+        .section_header_by_name(".dynamic")
+        .context("reading ELF section headers")?
+    else {
+        return Ok(());
+    };
+    let strtab_header = file
+        .section_headers()
+        .ok_or_eyre("ELF file without section headers")?
+        .get(dynamic_header.sh_link as usize)
+        .context("reading dynamic string table header")?;
+    let strtab = file
+        .section_data_as_strtab(&strtab_header)
+        .context("reading dynamic string table")?;
+    let origin = path.parent().unwrap_or(Path::new("/")).to_string_lossy();
+    let mut needed = Vec::new();
+    let mut dirs = Vec::new();
+    for entry in file
+        .dynamic()
+        .context("reading dynamic section")?
+        .into_iter()
+        .flatten()
+    {
+        let tag = entry.d_tag;
+        let offset = entry.d_val() as usize;
+        let value = || strtab.get(offset).context("reading dynamic string");
+        match tag {
+            DT_NEEDED => needed.push(value()?),
+            DT_RUNPATH | DT_RPATH => dirs.extend(value()?.split(':').map(|dir| {
+                PathBuf::from(
+                    dir.replace("${ORIGIN}", &origin)
+                        .replace("$ORIGIN", &origin),
+                )
+            })),
+            _ => {}
+        }
+    }
+    // the loader finds its own libraries, like libc in nix, next to itself
+    for segment in file.segments().into_iter().flatten() {
+        if segment.p_type == PT_INTERP {
+            let interpreter = file
+                .segment_data(&segment)
+                .context("reading ELF interpreter")?;
+            let interpreter = String::from_utf8_lossy(interpreter);
+            if let Some(dir) = Path::new(interpreter.trim_end_matches('\0')).parent() {
+                dirs.push(dir.to_path_buf());
+            }
+        }
+    }
+    ld_so_conf(Path::new("/etc/ld.so.conf"), &mut dirs);
+    dirs.extend(DEFAULT_DIRS.iter().map(PathBuf::from));
+    let mut seen = BTreeSet::new();
+    dirs.retain(|dir| seen.insert(dir.clone()));
+    let missing: Vec<_> = needed
+        .into_iter()
+        .filter(|name| !dirs.iter().any(|dir| dir.join(name).exists()))
+        .collect();
+    if !missing.is_empty() {
+        bail!(
+            "{} needs shared libraries that are not found at runtime: {}\nsearched {}\n\
+             add the directories containing them with `cargo:rustc-link-search=native=DIR` in the \
+             build script or a crate override",
+            path.display(),
+            missing.join(", "),
+            dirs.iter()
+                .map(|dir| dir.display().to_string())
+                .collect::<Vec<_>>()
+                .join(":"),
+        );
+    }
+    Ok(())
+}
+
+/// Runs [`check_needed`] on every linked artifact in `out/bin` and `out/lib`.
+pub fn check_outputs(out: &Path) -> Result<()> {
+    for dir in [out.join("bin"), out.join("lib")] {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        let mut files = Vec::new();
+        for entry in entries {
+            let entry = entry.context("reading output dir entry")?;
+            // the versioned cdylib symlinks point at the same file
+            if entry.file_type().context("reading file type")?.is_file() {
+                files.push(entry.path());
+            }
+        }
+        files.sort();
+        for file in files {
+            check_needed(&file)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dylib_file_names_follow_the_kind_and_modifiers() {
+        let file_name = |link_lib| dylib_file_name(link_lib, "lib", ".so");
+        assert_eq!(file_name("z").as_deref(), Some("libz.so"));
+        assert_eq!(file_name("dylib=ssl:crypto").as_deref(), Some("libssl.so"));
+        assert_eq!(
+            file_name("dylib:+verbatim=libfoo.so.1").as_deref(),
+            Some("libfoo.so.1")
+        );
+        assert_eq!(
+            file_name("dylib:-bundle,+verbatim=foo.so").as_deref(),
+            Some("foo.so")
+        );
+        assert_eq!(file_name("static=z"), None);
+        assert_eq!(file_name("framework=Foundation"), None);
+    }
+}
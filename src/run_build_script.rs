@@ -1,7 +1,7 @@
 use std::{
     collections::{
-        hash_map::Entry::{Occupied, Vacant},
-        HashMap, HashSet,
+        BTreeMap, BTreeSet,
+        btree_map::Entry::{Occupied, Vacant},
     },
     env, fs,
     io::{BufRead, BufReader},
@@ -10,28 +10,15 @@ use std::{
     sync::LazyLock,
 };
 
-use color_eyre::eyre::{bail, eyre, Context, ContextCompat, OptionExt, Result};
+use color_eyre::eyre::{Context, ContextCompat, OptionExt, Result, bail, eyre};
 use owo_colors::OwoColorize;
 use regex::Regex;
-use serde::{Deserialize, Serialize};
 
-use crate::compile::{CrateJobCommon, RustLibMetadata};
-
-#[derive(Debug, Serialize, Deserialize, Default)]
-#[serde(rename_all = "camelCase")]
-pub struct BuildScriptResult {
-    pub metadata: HashMap<String, String>,
-    pub link_args: Vec<String>,
-    pub link_args_cdylib: Vec<String>,
-    pub link_args_bins: Vec<String>,
-    pub link_args_bin: HashMap<String, Vec<String>>,
-    pub link_lib: Vec<String>,
-    pub lib_path: HashSet<String>,
-    pub flags: Vec<String>,
-    pub cfgs: Vec<String>,
-    pub check_cfgs: Vec<String>,
-    pub envs: HashMap<String, String>,
-}
+use crate::{
+    dep_info,
+    schema::{BuildScriptJob, BuildScriptResult, RustLibMetadata, TargetCodegen},
+    sysroot,
+};
 
 pub fn run(
     script: PathBuf,
@@ -44,9 +31,10 @@ pub fn run(
 ) -> Result<()> {
     out.push("output");
     fs::create_dir_all(&out).context("creating script output dir")?;
-    let info: CrateJobCommon =
+    let job: BuildScriptJob =
         serde_json::from_slice(&fs::read(info_path).context("reading build script info")?)
             .context("deserializing build script info")?;
+    let info = job.common;
     let mut command = Command::new(script);
     let cores = if env::var("enableParallelBuilding")
         .ok()
@@ -69,13 +57,26 @@ pub fn run(
         )
         .env("CARGO_PKG_NAME", &info.pname)
         .env("OUT_DIR", &out)
-        .env("TARGET", &info.target)
+        .env("TARGET", sysroot::target_name(&info.target))
         .env("HOST", rustc_host_tripple(&rustc)?.trim())
         .env("NUM_JOBS", cores)
         .env("RUSTC", &rustc)
         .env("RUSTDOC", &rustdoc)
-        .env("CARGO_ENCODED_RUSTFLAGS", info.rustc_flags.join("\x1f"));
+        // like cargo passes the rustflags the codegen settings usually come from
+        .env(
+            "CARGO_ENCODED_RUSTFLAGS",
+            Vec::from_iter(
+                info.rustc_flags
+                    .iter()
+                    .cloned()
+                    .chain(info.codegen.rustc_args()),
+            )
+            .join("\x1f"),
+        );
     info.add_metadata_env(&cargo, &src, &mut command)?;
+    if let Some(linker) = &info.codegen.linker {
+        command.env("RUSTC_LINKER", linker);
+    }
     if let Some(links) = &info.links {
         command.env("CARGO_MANIFEST_LINKS", links);
     }
@@ -89,12 +90,13 @@ pub fn run(
     } else {
         command.env("DEBUG", "false");
     }
-    let mut cfgs: HashMap<&str, HashSet<&str>> = HashMap::new();
+    // sorted, so the joined values don't depend on hash order
+    let mut cfgs: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
     cfgs.insert(
         "feature",
         info.features.iter().map(String::as_str).collect(),
     );
-    let rustc_cfg = cfg_from_rustc(&info.target, &rustc)?;
+    let rustc_cfg = cfg_from_rustc(&info.target, &rustc, &info.codegen)?;
     for r in parse_cfgs(&rustc_cfg)
         .into_iter()
         .chain(info.cfgs.iter().map(|c| parse_cfg(c)))
@@ -106,25 +108,28 @@ pub fn run(
                 e.get_mut().insert(v);
             }
             (Vacant(e), None) => {
-                e.insert(HashSet::new());
+                e.insert(BTreeSet::new());
             }
             (Vacant(e), Some(v)) => {
-                let mut set = HashSet::new();
+                let mut set = BTreeSet::new();
                 set.insert(v);
                 e.insert(set);
             }
         }
     }
+    if let Some(panic) = &info.panic {
+        cfgs.insert("panic", BTreeSet::from([panic.as_str()]));
+    }
     for (name, vals) in cfgs {
         let name = "CARGO_CFG_".to_string() + &name.to_uppercase();
         let val = Vec::from_iter(vals).join(",");
         command.env(name, val);
     }
-    for f in info.features {
+    for f in &info.features {
         let name = "CARGO_FEATURE_".to_string() + &f.to_uppercase().replace("-", "_");
         command.env(name, "1");
     }
-    for dep in info.deps {
+    for dep in &info.deps {
         let dep_metadata: RustLibMetadata = toml::from_str(
             &fs::read_to_string(dep.path.join("rust-lib.toml"))
                 .context("reading rust lib metadata")?,
@@ -164,14 +169,22 @@ pub fn run(
     }
 
     if !result.metadata.is_empty() && info.links.is_none() {
-        bail!("build script has metadata without links")
+        bail!("build script has metadata without links");
     }
 
     fs::write(
-        out,
+        &out,
         toml::to_string_pretty(&result).context("serializing build script result")?,
     )
     .context("writing build script result")?;
+    out.pop();
+    dep_info::write_build_script(
+        &info,
+        &src,
+        &result.rerun_if_changed,
+        &result.rerun_if_env_changed,
+        &out,
+    )?;
     if error {
         Err(eyre!("build script reported error"))
     } else {
@@ -209,19 +222,19 @@ fn parse_cfg(str: &str) -> Result<(&str, Option<&str>)> {
         .ok_or_eyre("unable to parse cfg")
 }
 
-pub fn cfg_from_rustc(target: &str, rustc: &Path) -> Result<String> {
+/// Returns the output of `rustc --print=cfg` for `target`, whose `target_feature`s include the
+/// ones of the CPU and features of `codegen`.
+pub fn cfg_from_rustc(target: &str, rustc: &Path, codegen: &TargetCodegen) -> Result<String> {
     String::from_utf8(
-        Command::new(rustc)
-            .arg("-O")
-            .arg("--print=cfg")
-            .arg("--target")
-            .arg(target)
+        sysroot::target_args(Command::new(rustc).arg("-O").arg("--print=cfg"), target)
+            .args(codegen.rustc_args())
             .output()
             .context("getting cfg from rustc")?
             .stdout,
     )
     .context("outputs includes non utf-8")
 }
+/// Returns the host tuple of `rustc`, including the trailing newline.
 pub fn rustc_host_tripple(rustc: &Path) -> Result<String> {
     String::from_utf8(
         Command::new(rustc)
@@ -237,14 +250,24 @@ static SCRIPT_OUT_REGEX: LazyLock<Regex> = LazyLock::new(|| {
     Regex::new(r#"^\s*cargo(::?)([a-z\-_]+)=((?:([^=\s]+)=)?("(.*)"\s*|([^\s]+)\s*|.+))$"#).unwrap()
 });
 
-fn parse_script_output_line(line: &str, out: &mut BuildScriptResult, error: &mut bool) {
+/// Parses one line of build script output into `out`.
+///
+/// Lines that are not `cargo:` instructions are echoed, `cargo::error` sets `error`.
+pub fn parse_script_output_line(line: &str, out: &mut BuildScriptResult, error: &mut bool) {
     if let Some(capture) = SCRIPT_OUT_REGEX.captures(line) {
         match capture
             .get(2)
             .expect("the first group is not optional")
             .as_str()
         {
-            "rerun-if-changed" | "rerun-if-env-changed" => {}
+            "rerun-if-changed" => {
+                let path = capture.get(3).expect("not optional").as_str().trim();
+                out.rerun_if_changed.push(path.to_string());
+            }
+            "rerun-if-env-changed" => {
+                let name = capture.get(3).expect("not optional").as_str().trim();
+                out.rerun_if_env_changed.push(name.to_string());
+            }
             "rustc-link-arg" => {
                 let arg = capture.get(3).expect("not optional").as_str().trim();
                 out.link_args.push(arg.to_string());
@@ -285,7 +308,7 @@ fn parse_script_output_line(line: &str, out: &mut BuildScriptResult, error: &mut
             }
             "rustc-link-search" => {
                 let link_path = capture.get(3).expect("not optional").as_str().trim();
-                out.lib_path.insert(link_path.to_string().into());
+                out.lib_path.insert(link_path.to_string());
                 println!("added link path: {link_path}");
             }
             "rustc-flags" => {
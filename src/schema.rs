@@ -0,0 +1,413 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+};
+
+use cargo_metadata::Edition;
+use clap::ValueEnum;
+use color_eyre::eyre::{Context, Result};
+use schemars::{JsonSchema, schema_for};
+use serde::{Deserialize, Deserializer, Serialize, de::Error};
+
+/// Version of the json documents exchanged with nix, which only accepts its own version.
+///
+/// Bumped whenever a field is added or changed, even one with `#[serde(default)]`, as
+/// `deny_unknown_fields` makes older readers reject it. `schemaVersion` in `nix/lib.nix` has
+/// to match.
+pub const SCHEMA_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(transparent)]
+pub struct SchemaVersion(#[schemars(range(min = 2, max = 2))] u32);
+
+impl Default for SchemaVersion {
+    fn default() -> Self {
+        Self(SCHEMA_VERSION)
+    }
+}
+
+impl<'de> Deserialize<'de> for SchemaVersion {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let version = u32::deserialize(deserializer)?;
+        if version == SCHEMA_VERSION {
+            Ok(Self(version))
+        } else {
+            Err(D::Error::custom(format!(
+                "unsupported schema version {version}, expected {SCHEMA_VERSION}"
+            )))
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Metadata {
+    pub schema_version: SchemaVersion,
+    pub packages: BTreeMap<String, PackageMetadata>,
+    pub targets: BTreeMap<String, TargetMetadata>,
+    pub workspace: BTreeMap<String, String>,
+    pub main_package: Option<String>,
+    /// The `[profile]` tables of the workspace manifest with `inherits` applied, always
+    /// including `dev` and `release`.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Profile {
+    /// Panic strategy, rustc's default `unwind` if unset.
+    pub panic: Option<String>,
+    /// Debuginfo level like `line-tables-only`, `limited` or `full`, `none` leaves it out.
+    #[serde(default)]
+    pub debug: Option<String>,
+    /// How rustc splits the debuginfo off the artifacts, `packed` or `unpacked`.
+    #[serde(default)]
+    pub split_debuginfo: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PackageMetadata {
+    pub manifest_path: PathBuf,
+    pub version: String,
+    pub authors: Option<Vec<String>>,
+    pub pname: String,
+    pub description: Option<String>,
+    pub homepage: Option<String>,
+    pub repository: Option<String>,
+    pub license: Option<String>,
+    pub license_file: Option<PathBuf>,
+    pub rust_version: Option<String>,
+    pub readme: Option<PathBuf>,
+    pub all_features: Vec<String>,
+    #[schemars(with = "String")]
+    pub edition: Edition,
+    pub main_workspace: bool,
+    pub links: Option<String>,
+    /// A standard library crate from `rust-src`, its paths are relative to the `library` dir.
+    #[serde(default)]
+    pub sysroot_crate: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct TargetMetadata {
+    pub packages: BTreeMap<String, ResolvedPackage>,
+    /// Packages whose libraries make up the sysroot of the target, in dependency order. Empty
+    /// if the target uses the standard library that comes with rustc.
+    #[serde(default)]
+    pub sysroot: Vec<String>,
+    /// Linker and code generation settings from `.cargo/config.toml`.
+    #[serde(default)]
+    pub codegen: TargetCodegen,
+}
+
+/// Linker and code generation settings of a target, passed to rustc as `-C` options.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct TargetCodegen {
+    /// Linker like `clang`, with the linker flavor rustc infers from its name by default.
+    #[serde(default)]
+    pub linker: Option<String>,
+    /// Linker flavor like `gnu-lld-cc`.
+    #[serde(default)]
+    pub linker_flavor: Option<String>,
+    /// CPU like `x86-64-v3` or `native`.
+    #[serde(default)]
+    pub target_cpu: Option<String>,
+    /// Features like `+avx2` or `-sse4.1`, enabled on top of the ones of `target_cpu`.
+    #[serde(default)]
+    pub target_features: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ResolvedPackage {
+    pub features: Vec<String>,
+    pub build_script: Option<BuildScriptTarget>,
+    pub rust_lib: Option<CompileTarget>,
+    pub c_lib: Option<CompileTarget>,
+    pub bins: Option<Vec<CompileTarget>>,
+    /// Doctests of a workspace library, depending on its dev-dependencies and the library itself.
+    #[serde(default)]
+    pub doctest: Option<CompileTarget>,
+    /// Unit tests of the library and bins and the integration tests of a workspace member,
+    /// compiled with `--test` against its dev-dependencies.
+    #[serde(default)]
+    pub tests: Vec<CompileTarget>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Dep {
+    pub name: String,
+    pub pkg: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct CompileTarget {
+    pub target_name: String,
+    pub crate_name: String,
+    pub deps: Vec<Dep>,
+    pub crate_type: String,
+    pub entrypoint: PathBuf,
+    #[schemars(with = "String")]
+    pub edition: Edition,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct BuildScriptTarget {
+    pub main_deps: Vec<Dep>,
+    pub main_crate_name: String,
+    pub target_name: String,
+    pub crate_name: String,
+    pub deps: Vec<Dep>,
+    pub crate_type: String,
+    pub entrypoint: PathBuf,
+    #[schemars(with = "String")]
+    pub edition: Edition,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ResolvedDep {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct CrateJobCommon {
+    pub rustc_flags: Vec<String>,
+    pub cfgs: Vec<String>,
+    pub link_args: Vec<String>,
+    pub manifest_path: PathBuf,
+    pub version: String,
+    pub authors: Option<Vec<String>>,
+    pub pname: String,
+    pub description: Option<String>,
+    pub homepage: Option<String>,
+    pub repository: Option<String>,
+    pub license: Option<String>,
+    pub license_file: Option<PathBuf>,
+    pub rust_version: Option<String>,
+    pub readme: Option<PathBuf>,
+    pub target: String,
+    pub features: Vec<String>,
+    pub all_features: Vec<String>,
+    pub crate_name: String,
+    #[schemars(with = "String")]
+    pub edition: Edition,
+    pub deps: Vec<ResolvedDep>,
+    pub links: Option<String>,
+    pub optimize: bool,
+    pub debuginfo: bool,
+    /// `-C debuginfo` level like `line-tables-only` or `limited` used with `debuginfo`, `full`
+    /// if unset.
+    #[serde(default)]
+    pub debuginfo_level: Option<String>,
+    /// `-C split-debuginfo` mode. With `packed` the dwarf objects of libraries are kept in the
+    /// rlib and packaged into a `.dwp` next to the linked bins, cdylibs and tests, with
+    /// `unpacked` they are left as `.dwo` files next to each output. Build scripts and proc
+    /// macros keep their debuginfo.
+    #[serde(default)]
+    pub split_debuginfo: Option<String>,
+    /// Remap the source, `OUT_DIR` and build directory paths embedded in the outputs and pin the
+    /// codegen units, so rebuilds are bit-for-bit identical.
+    #[serde(default)]
+    pub reproducible: bool,
+    /// Panic strategy like `abort`, not applied to tests, proc macros and build scripts.
+    #[serde(default)]
+    pub panic: Option<String>,
+    /// Sysroot with the standard library crates built for the target, written by
+    /// [`crate::sysroot::run`].
+    #[serde(default)]
+    pub sysroot: Option<PathBuf>,
+    /// Built with the unstable features the standard library uses, see
+    /// [`PackageMetadata::sysroot_crate`].
+    #[serde(default)]
+    pub sysroot_crate: bool,
+    /// Instrument the crate with `-C instrument-coverage`, only set for workspace crates. Build
+    /// scripts and proc macros run during the build and are left alone.
+    #[serde(default)]
+    pub coverage: bool,
+    /// Sanitizer like `address` or `thread` passed to `-Z sanitizer`. Every crate linked into
+    /// the tests has to be built with it, except build scripts and proc macros, which run
+    /// uninstrumented on the host.
+    #[serde(default)]
+    pub sanitizer: Option<String>,
+    /// Instrument the crate with `-C profile-generate` for the training run of a profile-guided
+    /// optimization, see [`crate::pgo`]. Build scripts and proc macros are left alone.
+    #[serde(default)]
+    pub profile_generate: bool,
+    /// Profile written by [`crate::pgo::run`] the crate is optimized with through
+    /// `-C profile-use`.
+    #[serde(default)]
+    pub profile_use: Option<PathBuf>,
+    /// Linker and code generation settings of the target. Build scripts and proc macros only
+    /// use its linker.
+    #[serde(default)]
+    pub codegen: TargetCodegen,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct CrateJob {
+    pub schema_version: SchemaVersion,
+    pub common: CrateJobCommon,
+    pub crate_type: String,
+    pub entrypoint: PathBuf,
+    pub target_name: String,
+    pub build_script_run: Option<PathBuf>,
+    /// Only emit the `.rmeta` of a library, dependents can type-check against it before codegen finished.
+    #[serde(default)]
+    pub metadata_only: bool,
+    /// Outputs of all transitive rust libraries, searched when linking against their rlibs.
+    #[serde(default)]
+    pub link_deps: Vec<PathBuf>,
+    /// Only type-check the crate, libraries emit metadata without the MIR needed for codegen.
+    #[serde(default)]
+    pub check: bool,
+    /// Run the doctests of the library with `rustdoc --test`, linking against the rlibs of its deps.
+    #[serde(default)]
+    pub doctest: bool,
+    /// Linker version script for a cdylib, relative to the source. It is passed after the export
+    /// list rustc generates itself, and linkers bind every symbol to the first script listing it.
+    #[serde(default)]
+    pub version_script: Option<PathBuf>,
+}
+
+/// A crate documented by `nix-rust-build doc`, together with the source its job refers to.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct DocJob {
+    pub src: PathBuf,
+    pub job: CrateJob,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct BuildScriptJob {
+    pub schema_version: SchemaVersion,
+    pub common: CrateJobCommon,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct BuildScriptResult {
+    pub schema_version: SchemaVersion,
+    pub metadata: BTreeMap<String, String>,
+    pub link_args: Vec<String>,
+    pub link_args_cdylib: Vec<String>,
+    pub link_args_bins: Vec<String>,
+    pub link_args_bin: BTreeMap<String, Vec<String>>,
+    pub link_lib: Vec<String>,
+    pub lib_path: BTreeSet<String>,
+    pub flags: Vec<String>,
+    pub cfgs: Vec<String>,
+    pub check_cfgs: Vec<String>,
+    pub envs: BTreeMap<String, String>,
+    /// Paths relative to the manifest directory, the whole package without any.
+    #[serde(default)]
+    pub rerun_if_changed: Vec<String>,
+    #[serde(default)]
+    pub rerun_if_env_changed: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RustLibMetadata {
+    pub schema_version: SchemaVersion,
+    pub lib: PathBuf,
+    #[serde(default)]
+    pub rmeta: Option<PathBuf>,
+    /// Hash passed to rustc as `-C metadata`, unique for every configuration of the crate.
+    pub hash: String,
+    pub deps: BTreeSet<PathBuf>,
+    pub metadata: BTreeMap<String, String>,
+    pub links: Option<String>,
+    pub lib_path: BTreeSet<String>,
+    /// Native libraries linked by the crate and its dependencies, in rustc's `-l` syntax.
+    #[serde(default)]
+    pub link_lib: BTreeSet<String>,
+}
+
+/// The inputs of a compile or build script run, recorded in `nix-support/dep-info.json`.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct DepInfo {
+    pub schema_version: SchemaVersion,
+    pub pname: String,
+    pub version: String,
+    pub manifest_path: PathBuf,
+    /// Files and directories relative to the source of the package.
+    pub sources: BTreeSet<PathBuf>,
+    /// Files relative to the `OUT_DIR` of the build script run.
+    pub out_dir: BTreeSet<PathBuf>,
+    /// Absolute paths outside of both.
+    pub external: BTreeSet<PathBuf>,
+    /// Environment variables read with `env!` or watched by the build script.
+    pub env: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Document {
+    Metadata,
+    CrateJob,
+    DocJob,
+    BuildScriptJob,
+    BuildScriptResult,
+    RustLibMetadata,
+    DepInfo,
+}
+
+pub fn run(document: Document) -> Result<()> {
+    let schema = match document {
+        Document::Metadata => schema_for!(Metadata),
+        Document::CrateJob => schema_for!(CrateJob),
+        Document::DocJob => schema_for!(Vec<DocJob>),
+        Document::BuildScriptJob => schema_for!(BuildScriptJob),
+        Document::BuildScriptResult => schema_for!(BuildScriptResult),
+        Document::RustLibMetadata => schema_for!(RustLibMetadata),
+        Document::DepInfo => schema_for!(DepInfo),
+    };
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&schema).context("serializing schema")?
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn other_schema_versions_are_rejected() {
+        let doc = serde_json::json!({
+            "schemaVersion": SCHEMA_VERSION - 1,
+            "packages": {},
+            "targets": {},
+            "workspace": {},
+            "mainPackage": null,
+        });
+        let error = serde_json::from_value::<Metadata>(doc.clone()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "unsupported schema version {}, expected {SCHEMA_VERSION}",
+                SCHEMA_VERSION - 1
+            )
+        );
+
+        let mut doc = doc;
+        doc["schemaVersion"] = SCHEMA_VERSION.into();
+        serde_json::from_value::<Metadata>(doc).unwrap();
+    }
+}
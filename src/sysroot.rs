@@ -0,0 +1,272 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsStr,
+    fs,
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use cargo_metadata::Edition;
+use color_eyre::eyre::{Context, OptionExt, Result, bail, eyre};
+use serde::Deserialize;
+
+use crate::{
+    metadata::make_crate_name,
+    schema::{
+        BuildScriptTarget, CompileTarget, Dep, Metadata, PackageMetadata, ResolvedPackage,
+        RustLibMetadata,
+    },
+};
+
+/// A crate of the standard library built by `--build-std`, with the features and dependencies
+/// `cargo -Z build-std=core,alloc` gives it.
+struct StdCrate {
+    /// Relative to the `library` dir.
+    dir: &'static str,
+    features: &'static [&'static str],
+    deps: &'static [&'static str],
+}
+
+/// In dependency order, later crates depend on earlier ones.
+const CRATES: [StdCrate; 3] = [
+    StdCrate {
+        dir: "core",
+        features: &[],
+        deps: &[],
+    },
+    StdCrate {
+        dir: "compiler-builtins/compiler-builtins",
+        features: &["arch", "compiler-builtins", "unmangled-names"],
+        deps: &["core"],
+    },
+    StdCrate {
+        dir: "alloc",
+        features: &[],
+        deps: &["core", "compiler_builtins"],
+    },
+];
+
+#[derive(Deserialize)]
+struct Manifest {
+    package: ManifestPackage,
+    #[serde(default)]
+    features: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct ManifestPackage {
+    name: String,
+    version: String,
+    edition: Edition,
+    links: Option<String>,
+    description: Option<String>,
+    repository: Option<String>,
+    license: Option<String>,
+}
+
+/// Whether `target` is the path of a target spec instead of the name of a builtin target.
+pub fn is_target_spec(target: &str) -> bool {
+    target.ends_with(".json")
+}
+
+/// The name rustc gives `target`, the file name without `.json` for target specs.
+pub fn target_name(target: &str) -> &str {
+    if is_target_spec(target) {
+        Path::new(target)
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .unwrap_or(target)
+    } else {
+        target
+    }
+}
+
+/// Makes the path of a target spec absolute, the jobs using it run in other directories.
+pub fn resolve_target(target: String) -> Result<String> {
+    if !is_target_spec(&target) {
+        return Ok(target);
+    }
+    let path = fs::canonicalize(&target).with_context(|| format!("resolving {target}"))?;
+    path.into_os_string()
+        .into_string()
+        .map_err(|path| eyre!("path \"{}\" contains non unicode value", path.display()))
+}
+
+/// Passes `target` to rustc, which only accepts target specs with `-Z unstable-options`.
+pub fn target_args<'c>(command: &'c mut Command, target: &str) -> &'c mut Command {
+    if is_target_spec(target) {
+        command.args(["-Z", "unstable-options"]);
+    }
+    command.arg("--target").arg(target)
+}
+
+/// The `library` dir of the standard library sources, `RUST_SRC_PATH` or the one the
+/// `rust-src` component installs into the sysroot of `rustc`.
+pub fn library_dir(rustc: &Path) -> Result<PathBuf> {
+    if let Some(path) = env::var_os("RUST_SRC_PATH") {
+        return Ok(PathBuf::from(path));
+    }
+    let output = Command::new(rustc)
+        .arg("--print=sysroot")
+        .output()
+        .context("getting sysroot from rustc")?;
+    if !output.status.success() {
+        bail!("rustc failed to print its sysroot");
+    }
+    let sysroot = String::from_utf8(output.stdout).context("outputs includes non utf-8")?;
+    let library = Path::new(sysroot.trim()).join("lib/rustlib/src/rust/library");
+    if !library.is_dir() {
+        bail!(
+            "{} does not exist, install the rust-src component or set RUST_SRC_PATH",
+            library.display()
+        );
+    }
+    Ok(library)
+}
+
+/// A loaded [`StdCrate`].
+struct Loaded {
+    id: String,
+    crate_name: String,
+    dir: PathBuf,
+    edition: Edition,
+    features: Vec<String>,
+    deps: Vec<Dep>,
+    build_script: bool,
+}
+
+impl Loaded {
+    fn resolved(&self) -> ResolvedPackage {
+        let build_script = self.build_script.then(|| BuildScriptTarget {
+            main_deps: self.deps.clone(),
+            main_crate_name: self.crate_name.clone(),
+            target_name: "build_script".to_string(),
+            crate_name: "build_script".to_string(),
+            deps: Vec::new(),
+            crate_type: "bin".to_string(),
+            entrypoint: self.dir.join("build.rs"),
+            edition: self.edition,
+        });
+        ResolvedPackage {
+            features: self.features.clone(),
+            build_script,
+            rust_lib: Some(CompileTarget {
+                target_name: self.crate_name.clone(),
+                crate_name: self.crate_name.clone(),
+                deps: self.deps.clone(),
+                crate_type: "lib".to_string(),
+                entrypoint: self.dir.join("src/lib.rs"),
+                edition: self.edition,
+            }),
+            c_lib: None,
+            bins: None,
+            doctest: None,
+            tests: Vec::new(),
+        }
+    }
+}
+
+/// Adds `core`, `compiler_builtins` and `alloc` from the sources in [`library_dir`] to
+/// `metadata` and makes them the sysroot of every target in `build_std`.
+pub fn add_packages(metadata: &mut Metadata, rustc: &Path, build_std: &[String]) -> Result<()> {
+    if build_std.is_empty() {
+        return Ok(());
+    }
+    let library = library_dir(rustc)?;
+    let mut loaded: Vec<Loaded> = Vec::new();
+    for krate in &CRATES {
+        let dir = PathBuf::from(krate.dir);
+        let manifest_path = dir.join("Cargo.toml");
+        let manifest: Manifest = toml::from_str(
+            &fs::read_to_string(library.join(&manifest_path))
+                .with_context(|| format!("reading {}", manifest_path.display()))?,
+        )
+        .with_context(|| format!("parsing {}", manifest_path.display()))?;
+        let package = manifest.package;
+        let deps = krate
+            .deps
+            .iter()
+            .map(|name| {
+                let dep = loaded
+                    .iter()
+                    .find(|l| l.crate_name == *name)
+                    .ok_or_else(|| eyre!("{} depends on unknown {name}", package.name))?;
+                Ok(Dep {
+                    name: name.to_string(),
+                    pkg: dep.id.clone(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        let id = format!("sysroot#{}@{}", package.name, package.version);
+        metadata.packages.insert(
+            id.clone(),
+            PackageMetadata {
+                manifest_path,
+                version: package.version,
+                authors: None,
+                pname: package.name.clone(),
+                description: package.description,
+                homepage: None,
+                repository: package.repository,
+                license: package.license,
+                license_file: None,
+                rust_version: None,
+                readme: None,
+                all_features: manifest.features.into_keys().collect(),
+                edition: package.edition,
+                main_workspace: false,
+                links: package.links,
+                sysroot_crate: true,
+            },
+        );
+        loaded.push(Loaded {
+            id,
+            crate_name: make_crate_name(&package.name),
+            build_script: library.join(&dir).join("build.rs").is_file(),
+            dir,
+            edition: package.edition,
+            features: krate.features.iter().map(|f| f.to_string()).collect(),
+            deps,
+        });
+    }
+    for target in build_std {
+        let resolved = metadata
+            .targets
+            .get_mut(target)
+            .ok_or_else(|| eyre!("--build-std target {target} is not one of the targets"))?;
+        for krate in &loaded {
+            resolved.packages.insert(krate.id.clone(), krate.resolved());
+        }
+        resolved.sysroot = loaded.iter().map(|krate| krate.id.clone()).collect();
+        println!(
+            "building the standard library for {target} from {}",
+            library.display()
+        );
+    }
+    Ok(())
+}
+
+/// Links the libraries of the `rust-lib.toml` outputs in `libs` into the sysroot layout rustc
+/// expects in `out`, `lib/rustlib/<target>/lib`.
+pub fn run(target: String, out: PathBuf, libs: Vec<PathBuf>) -> Result<()> {
+    let dir = out
+        .join("lib/rustlib")
+        .join(target_name(&target))
+        .join("lib");
+    fs::create_dir_all(&dir).context("creating sysroot lib dir")?;
+    for lib in libs {
+        let metadata: RustLibMetadata = toml::from_str(
+            &fs::read_to_string(lib.join("rust-lib.toml")).context("reading rust lib metadata")?,
+        )
+        .context("deserializing rust lib metadata")?;
+        let file_name = metadata
+            .lib
+            .file_name()
+            .ok_or_eyre("rust lib without file name")?;
+        symlink(&metadata.lib, dir.join(file_name)).context("linking sysroot crate")?;
+        println!("added {} to the sysroot", metadata.lib.display());
+    }
+    Ok(())
+}
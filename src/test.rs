@@ -0,0 +1,93 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use color_eyre::eyre::{Context, Result, bail};
+use serde_json::json;
+
+/// ThreadSanitizer suppressions for the standard library, which isn't instrumented. Its
+/// channels and libtest's handoff of results synchronize through atomics the sanitizer can't
+/// see, races reported through them aren't in the tested code.
+const TSAN_SUPPRESSIONS: &str = "race:std::sync::mpmc\nrace:test::run_test\n";
+
+/// The test binaries in the `bin` dirs of the compile outputs `tests`.
+pub fn test_binaries(tests: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut binaries = Vec::new();
+    for test in tests {
+        let dir = test.join("bin");
+        let mut entries = fs::read_dir(&dir)
+            .with_context(|| format!("reading {}", dir.display()))?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .context("reading test bin dir entry")?;
+        entries.sort();
+        binaries.extend(entries);
+    }
+    if binaries.is_empty() {
+        bail!("no test binaries to run");
+    }
+    Ok(binaries)
+}
+
+/// Runs every binary in `package_dir` with `CARGO_MANIFEST_DIR` set, like `cargo test` runs
+/// them, after `configure` added its own environment. Returns whether each one succeeded.
+pub fn run_binaries(
+    binaries: &[PathBuf],
+    package_dir: &Path,
+    mut configure: impl FnMut(usize, &mut Command),
+) -> Result<Vec<bool>> {
+    let mut results = Vec::new();
+    for (index, binary) in binaries.iter().enumerate() {
+        let mut command = Command::new(binary);
+        command
+            .current_dir(package_dir)
+            .env("CARGO_MANIFEST_DIR", package_dir)
+            .stdin(Stdio::null());
+        configure(index, &mut command);
+        println!("executing {command:?}");
+        let status = command
+            .status()
+            .with_context(|| format!("executing {}", binary.display()))?;
+        if !status.success() {
+            println!("{} failed with {status}", binary.display());
+        }
+        results.push(status.success());
+    }
+    Ok(results)
+}
+
+/// Runs the test binaries of a workspace member and writes which ones passed to
+/// `out/tests.json`.
+///
+/// `tests` are the outputs of its `--test` compile jobs, each binary runs in `package_dir`.
+/// Sanitizer builds report their findings on stderr and fail the binary, ThreadSanitizer ones
+/// with the suppressions in `out/tsan.supp`.
+pub fn run(package_dir: PathBuf, out: PathBuf, tests: Vec<PathBuf>) -> Result<()> {
+    let binaries = test_binaries(&tests)?;
+    fs::create_dir_all(&out).context("creating output dir")?;
+    let suppressions = out.join("tsan.supp");
+    fs::write(&suppressions, TSAN_SUPPRESSIONS).context("writing tsan suppressions")?;
+    let tsan_options = format!("suppressions={}", suppressions.display());
+    let results = run_binaries(&binaries, &package_dir, |_, command| {
+        command.env("TSAN_OPTIONS", &tsan_options);
+    })?;
+    let outcomes = Vec::from_iter(binaries.iter().zip(&results).map(|(binary, success)| {
+        json!({
+            "name": binary.file_name().unwrap_or_default().to_string_lossy(),
+            "outcome": if *success { "ok" } else { "failed" },
+        })
+    }));
+    println!("writing tests.json");
+    fs::write(
+        out.join("tests.json"),
+        serde_json::to_vec_pretty(&outcomes).context("serializing test outcomes")?,
+    )
+    .context("writing test outcomes")?;
+    let failed = results.iter().filter(|success| !**success).count();
+    if failed != 0 {
+        bail!("{failed} of {} test binaries failed", binaries.len());
+    }
+    Ok(())
+}
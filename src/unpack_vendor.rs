@@ -39,7 +39,7 @@ pub fn run(src: PathBuf, mut out: PathBuf) -> Result<()> {
             match part {
                 Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
                 Component::ParentDir => {
-                    bail!("parent dir in tarball is not allowed due to security concerns")
+                    bail!("parent dir in tarball is not allowed due to security concerns");
                 }
                 Component::Normal(part) => {
                     if first {
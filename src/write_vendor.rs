@@ -7,16 +7,22 @@ use std::{
 use color_eyre::eyre::{Context, OptionExt, Result};
 use serde::Deserialize;
 
+/// A crate source that is linked into the vendor directory.
 #[derive(Debug, Deserialize)]
-struct Dependency {
-    path: String,
-    dir_name: String,
-    registry: String,
+pub struct Dependency {
+    pub path: String,
+    pub dir_name: String,
+    pub registry: String,
 }
 
-pub fn run(job: PathBuf, mut out: PathBuf) -> Result<()> {
+pub fn run(job: PathBuf, out: PathBuf) -> Result<()> {
     let job_str = fs::read_to_string(job).context("reading job")?;
     let job: HashMap<String, Dependency> = serde_json::from_str(&job_str).context("parsing job")?;
+    write(&job, out)
+}
+
+/// Creates a cargo vendor directory at `out` with a `config.toml` replacing all used registries.
+pub fn write(job: &HashMap<String, Dependency>, mut out: PathBuf) -> Result<()> {
     fs::create_dir(&out).context("mkdir out")?;
     let mut config = r#"[source.vendored-sources]
 directory=""#
@@ -0,0 +1,23 @@
+lib:
+let
+  inherit (lib) checkSchemaVersion schemaVersion;
+in
+{
+  testCurrent = {
+    expr = checkSchemaVersion "doc" {
+      inherit schemaVersion;
+      a = 1;
+    };
+    expected = {
+      inherit schemaVersion;
+      a = 1;
+    };
+  };
+  testMissing = {
+    expr = checkSchemaVersion "doc" { a = 1; };
+    expectedError = {
+      type = "ThrownError";
+      msg = "doc has schema version none, expected 2";
+    };
+  };
+}
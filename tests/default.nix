@@ -4,4 +4,9 @@ in{
   foldOverrides = import ./foldOverrides.nix buildLib;
   mergeListAttrSets = import ./mergeListAttrSets.nix buildLib;
   patchSrc = import ./patchSrc.nix buildLib;
+  sourceFilter = import ./sourceFilter.nix buildLib;
+  mergeTargetPackages = import ./mergeTargetPackages.nix buildLib;
+  checkSchemaVersion = import ./checkSchemaVersion.nix buildLib;
+  linkClosure = import ./linkClosure.nix buildLib;
+  docJobs = import ./docJobs.nix buildLib;
 }
@@ -0,0 +1,32 @@
+lib:
+let
+  inherit (lib) docJobs;
+in
+{
+  testJobs = {
+    expr = builtins.fromJSON (docJobs [
+      {
+        src = "/src/a";
+        rustBuildCrateJob = builtins.toJSON { crateType = "lib"; };
+      }
+      {
+        src = "/src/b";
+        rustBuildCrateJob = builtins.toJSON { crateType = "proc-macro"; };
+      }
+    ]);
+    expected = [
+      {
+        src = "/src/a";
+        job.crateType = "lib";
+      }
+      {
+        src = "/src/b";
+        job.crateType = "proc-macro";
+      }
+    ];
+  };
+  testEmpty = {
+    expr = builtins.fromJSON (docJobs [ ]);
+    expected = [ ];
+  };
+}
@@ -0,0 +1,43 @@
+lib:
+let
+  inherit (lib) linkClosure;
+  buildPlan = {
+    a = {
+      rustLib = "a-lib";
+      rustLibClosure = { };
+    };
+    b = {
+      rustLib = "b-lib";
+      rustLibClosure = {
+        a = "a-lib";
+      };
+    };
+    macro = {
+      rustLib = "macro-lib";
+      rustLibClosure = { };
+    };
+  };
+in
+{
+  testTransitive = {
+    expr = linkClosure buildPlan [
+      {
+        name = "b";
+        pkg = "b";
+      }
+      {
+        name = "macro";
+        pkg = "macro";
+      }
+    ];
+    expected = {
+      a = "a-lib";
+      b = "b-lib";
+      macro = "macro-lib";
+    };
+  };
+  testEmpty = {
+    expr = linkClosure buildPlan [ ];
+    expected = { };
+  };
+}
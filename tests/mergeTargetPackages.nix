@@ -0,0 +1,39 @@
+lib:
+let
+  inherit (lib) mergeTargetPackages;
+in
+{
+  testMerge = {
+    expr = mergeTargetPackages {
+      target = "wasm32-unknown-unknown";
+      packages = {
+        a = {
+          pname = "a";
+        };
+        b = {
+          pname = "b";
+        };
+      };
+      resolved = {
+        a = {
+          features = [ "std" ];
+          rustLib = {
+            deps = [ ];
+          };
+        };
+      };
+    };
+    expected = {
+      a = {
+        common = {
+          pname = "a";
+          target = "wasm32-unknown-unknown";
+          features = [ "std" ];
+        };
+        rustLib = {
+          deps = [ ];
+        };
+      };
+    };
+  };
+}
@@ -23,6 +23,27 @@ in
       add = 1;
     };
   };
+  testMainUnlisted = {
+    expr =
+      patchSrc
+        {
+          workspaceSrc = "test";
+          sources = abort "should not be evaled";
+          sourceFiles = {
+            other-version = [ "other/Cargo.toml" ];
+          };
+        }
+        {
+          mainWorkspace = true;
+          pname = "name";
+          version = "version";
+        };
+    expected = {
+      src = "test";
+      pname = "name";
+      version = "version";
+    };
+  };
   testSources = {
     expr =
       patchSrc
@@ -47,4 +68,25 @@ in
       test = "ex";
     };
   };
+  testSysrootCrate = {
+    expr =
+      patchSrc
+        {
+          workspaceSrc = abort "main src evaled";
+          sources = abort "sources evaled";
+          rustSrc = "library";
+        }
+        {
+          mainWorkspace = false;
+          sysrootCrate = true;
+          pname = "core";
+          version = "0.0.0";
+        };
+    expected = {
+      src = "library";
+      sysrootCrate = true;
+      pname = "core";
+      version = "0.0.0";
+    };
+  };
 }
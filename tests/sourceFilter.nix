@@ -0,0 +1,39 @@
+lib:
+let
+  inherit (lib) sourceFilter;
+  files = [
+    "w/Cargo.toml"
+    "w/src/lib.rs"
+    "w/README.md"
+  ];
+in
+{
+  testListedFile = {
+    expr = sourceFilter files "w/src/lib.rs" "regular";
+    expected = true;
+  };
+  testParentDir = {
+    expr = sourceFilter files "w/src" "directory";
+    expected = true;
+  };
+  testOtherFile = {
+    expr = sourceFilter files "w/src/main.rs" "regular";
+    expected = false;
+  };
+  testOtherMember = {
+    expr = sourceFilter files "nodt" "directory";
+    expected = false;
+  };
+  testFileAsPrefix = {
+    expr = sourceFilter files "w/README.md.orig" "regular";
+    expected = false;
+  };
+  testListedDir = {
+    expr = sourceFilter [ "w" ] "w/build/gen.c" "regular";
+    expected = true;
+  };
+  testWholeSource = {
+    expr = sourceFilter [ "" ] "anything" "regular";
+    expected = true;
+  };
+}